@@ -1,5 +1,41 @@
 extern crate napi_build;
 
+use std::env;
+use std::fs;
+use std::path::Path;
+
 fn main() {
   napi_build::setup();
+
+  // Target triple and the version of notify locked for the build, which are reported by
+  // the info function
+  println!(
+    "cargo:rustc-env=NFS_WATCHER_TARGET={}",
+    env::var("TARGET").unwrap_or_default()
+  );
+  println!(
+    "cargo:rustc-env=NFS_WATCHER_PROFILE={}",
+    env::var("PROFILE").unwrap_or_default()
+  );
+
+  let lock_file = Path::new(&env::var("CARGO_MANIFEST_DIR").unwrap()).join("Cargo.lock");
+  println!("cargo:rerun-if-changed={}", lock_file.display());
+  println!(
+    "cargo:rustc-env=NFS_WATCHER_NOTIFY_VERSION={}",
+    locked_version(&lock_file, "notify").unwrap_or_default()
+  );
+}
+
+// Returns the version of the package locked in the lock file
+fn locked_version(lock_file: &Path, name: &str) -> Option<String> {
+  let lock = fs::read_to_string(lock_file).ok()?;
+  let mut lines = lock.lines();
+  let name_line = format!("name = \"{}\"", name);
+
+  lines.find(|line| *line == name_line)?;
+  lines
+    .next()?
+    .strip_prefix("version = \"")?
+    .strip_suffix('"')
+    .map(String::from)
 }
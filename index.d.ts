@@ -3,19 +3,422 @@
 
 /* auto-generated by NAPI-RS */
 
+/** File system event delivered to the javascript callback */
+export interface WatchEvent {
+  kind: string;
+  path: string;
+  /** Timestamp in milliseconds since epoch with sub-millisecond precision */
+  ts: number;
+  /**
+   * Sequence number in the order the events are produced, which is strictly increasing
+   * regardless of the delivery order
+   */
+  seq: number;
+  /** Source path of the rename event */
+  oldPath?: string;
+  /**
+   * Whether the modify event is caused by renaming another file over the path, as in
+   * the atomic saves of editors
+   */
+  atomic?: boolean;
+  /**
+   * Full event kind reported by the backend such as Modify(Data(Content)), if the
+   * detail option is enabled
+   */
+  detail?: string;
+  /** Current target of the symlink change event */
+  target?: string;
+  /** Previous target of the symlink change event */
+  oldTarget?: string;
+  /**
+   * Size of the file in bytes if the includeSize option is enabled, or null if the
+   * file doesn't exist anymore
+   */
+  size?: number;
+  /**
+   * Device and inode numbers identifying the file across renames if the includeFileId
+   * option is enabled, or null if the file doesn't exist anymore. Only supported on unix.
+   */
+  dev?: number;
+  ino?: number;
+  /**
+   * Raw bytes of the path if it is not valid UTF-8, in which case the path is converted
+   * lossily. Only supported on unix.
+   */
+  pathBytes?: Buffer;
+  /**
+   * Number of the events dropped by the maxEventsPerSecond option since the previous
+   * coalesced event
+   */
+  count?: number;
+  /**
+   * Flags attributed to the event by the backend if the includeFlags option is enabled,
+   * such as rescan and the info of FSEvents like "rescan: kernel dropped" or "is: symlink"
+   */
+  flags?: Array<string>;
+  /**
+   * Id of the process which modified the file if the tracePid option is enabled, or null
+   * if it can't be traced. Only supported on linux with the CAP_SYS_ADMIN capability.
+   */
+  pid?: number;
+}
 /**
  * Initiates recommended watcher instance with threadsafe callback function from
  * node js main thread and call the callback on fs events. This function returns
  * watcher instance which can be used to add paths to be watched for fs events.
+ * If the onError callback is given, the errors are delivered to it instead of the
+ * callback. The options are given in JSON format, and fail with the reason if they are
+ * malformed or unknown. Empty options fall back to the defaults.
  */
 export function watch(
   options: string,
-  callback: (err: null | Error, event: string) => void
+  callback: (
+    err: null | Error,
+    event: WatchEvent | WatchEvent[] | Buffer
+  ) => void,
+  onError?: (err: Error) => void
 ): JsExternal;
-/** This function takes in watcher instance and a path to be watched for events. */
-export function add(ext: JsExternal, dir: string): void;
+/**
+ * This function creates the watcher instance the same way as watchWithId, and registers
+ * it with a token which can be passed to abort for closing the watcher. The token is the
+ * id of the watcher, which keeps the watcher alive until it is aborted or closed. Returns
+ * the watcher instance along with the token.
+ */
+export function watchWithToken(
+  options: string,
+  callback: (
+    err: null | Error,
+    event: WatchEvent | WatchEvent[] | Buffer
+  ) => void,
+  onError?: (err: Error) => void
+): {watcher: JsExternal; token: number};
+/**
+ * This function closes the watcher instance registered with the given token the same
+ * way as closeById. Fails if no watcher is registered with the token.
+ */
+export function abort(token: number): void;
+/**
+ * This function creates the watcher instance the same way as watch, and registers it
+ * with a numeric id which can be passed to the functions suffixed with ById instead of
+ * the watcher instance. The registry keeps the watcher alive until it is closed, which
+ * invalidates the id. Returns the watcher instance along with the id.
+ */
+export function watchWithId(
+  options: string,
+  callback: (
+    err: null | Error,
+    event: WatchEvent | WatchEvent[] | Buffer
+  ) => void,
+  onError?: (err: Error) => void
+): {watcher: JsExternal; id: number};
+/**
+ * This function adds the path to the watcher registered with the given id the same way
+ * as add. Fails if no watcher is registered with the id.
+ */
+export function addById(id: number, dir: string | Buffer): void;
+/**
+ * This function unwatches the path of the watcher registered with the given id the same
+ * way as unwatch. Fails if no watcher is registered with the id.
+ */
+export function unwatchById(id: number, dir: string | Buffer): void;
+/**
+ * This function unwatches all the paths of the watcher registered with the given id the
+ * same way as unwatchAll. Fails if no watcher is registered with the id.
+ */
+export function unwatchAllById(id: number): void;
+/**
+ * This function closes the watcher registered with the given id the same way as close,
+ * which invalidates the id. Fails if no watcher is registered with the id.
+ */
+export function closeById(id: number): void;
+/**
+ * This function returns the list of paths watched by the watcher registered with the
+ * given id the same way as list. Fails if no watcher is registered with the id.
+ */
+export function listById(id: number): Array<string | Buffer>;
+/**
+ * This function checks whether the given path is being watched by the watcher registered
+ * with the given id the same way as isWatched. Fails if no watcher is registered with
+ * the id.
+ */
+export function isWatchedById(id: number, dir: string | Buffer): boolean;
+/**
+ * This function pauses the watcher registered with the given id the same way as pause.
+ * Fails if no watcher is registered with the id.
+ */
+export function pauseById(id: number): void;
+/**
+ * This function resumes the watcher registered with the given id the same way as resume.
+ * Fails if no watcher is registered with the id.
+ */
+export function resumeById(id: number): void;
+/**
+ * This function takes in watcher instance and a path to be watched for events.
+ * The path is watched recursively if the watcher was created with recursive option.
+ * The ready event is emitted once the path is watched. The path can be given as a
+ * buffer of raw bytes if it is not valid UTF-8.
+ */
+export function add(ext: JsExternal, dir: string | Buffer): void;
+/**
+ * This function takes in watcher instance and a path to be watched for events the
+ * same way as add, except that the path is watched recursively or not as given
+ * regardless of the recursive option of the watcher.
+ */
+export function addRecursive(
+  ext: JsExternal,
+  dir: string | Buffer,
+  recursive: boolean
+): void;
+/**
+ * This function takes in watcher instance and a list of paths to be watched for events.
+ * All the paths are attempted and the paths that failed to be watched are reported
+ * together in a single error, while the others remain watched. The ready event is
+ * emitted once if any of the paths is watched.
+ */
+export function addAll(ext: JsExternal, dirs: Array<string | Buffer>): void;
+/** Path to be watched by addWithOptions along with its own settings */
+export interface PathEntry {
+  path: string;
+  /** Whether the path is watched recursively regardless of the recursive option */
+  recursive: boolean;
+  /**
+   * Glob patterns of the paths to be ignored under this path in addition to the ignore
+   * option
+   */
+  ignore?: Array<string>;
+}
+/**
+ * This function takes in watcher instance and a list of paths to be watched for events,
+ * each with its own recursive flag and ignore patterns. All the paths are attempted the
+ * same way as addAll, and the paths that failed to be watched are reported together in
+ * a single error.
+ */
+export function addWithOptions(
+  ext: JsExternal,
+  entries: Array<PathEntry>
+): void;
+/**
+ * This function takes in watcher instance and a glob pattern such as src/*.rs, and
+ * watches the base directory of the pattern delivering only the events of the matching
+ * paths, including the ones created later. The options are given in JSON format, where
+ * the case_insensitive option matches the pattern case insensitively.
+ */
+export function addGlob(
+  ext: JsExternal,
+  pattern: string,
+  options: string
+): void;
+/**
+ * This function takes in watcher instance and a path of the file to be watched for events.
+ * The parent directory of the file is watched, delivering only the events of the file
+ * itself, so that the file remains watched when it is replaced by atomic saves. The file
+ * may not exist yet, in which case the add event is delivered once it is created. The
+ * other entries of the directory are still delivered if it is watched as well. The file
+ * is reported by list and isWatched, and is unwatched by unwatch. Fails if the path is
+ * a directory.
+ */
+export function addFile(ext: JsExternal, file: string): void;
 /**
  * This function invokes unwatch method on the specific path and removes that path
- * from watching for fs events.
+ * from watching for fs events along with the additional paths watched under it, such
+ * as symlink targets and sub-directories.
+ */
+export function unwatch(ext: JsExternal, dir: string | Buffer): void;
+
+/**
+ * This function unwatches all the paths being watched by the watcher instance along
+ * with the additional paths watched under them. All the paths are attempted and the
+ * paths that failed to be unwatched are reported together in a single error. The
+ * watcher remains usable for adding paths.
+ */
+export function unwatchAll(ext: JsExternal): void;
+/**
+ * This function delivers the events held by the watcher instance right away, i.e. the
+ * batched events and the events waiting for the coalesceModify, settle and rename
+ * timers. The events are queued to the callback before this function returns, ahead of
+ * any later events.
+ */
+export function flush(ext: JsExternal): void;
+/**
+ * This function closes the watcher instance by dropping it, which stops watching all
+ * the paths and releases the resources. Any held events are delivered before closing
+ * the same way as flush, unless dropPending is true in which case they are discarded.
+ * Subsequent calls on this instance will fail.
+ */
+export function close(ext: JsExternal, dropPending?: boolean): void;
+/**
+ * This function returns the list of paths currently being watched by the watcher
+ * instance in their absolute form. The paths which are not valid UTF-8 are returned as
+ * buffers of raw bytes.
+ */
+export function list(ext: JsExternal): Array<string>;
+/**
+ * This function checks whether the given path is being watched by the watcher instance.
+ * The path is normalized into its absolute form the same way as it is added, and can be
+ * given as a buffer of raw bytes the same way as well.
+ */
+export function isWatched(ext: JsExternal, dir: string | Buffer): boolean;
+/**
+ * This function pauses the watcher instance, dropping all the fs events until it is
+ * resumed without removing the watched paths.
+ */
+export function pause(ext: JsExternal): void;
+/** This function resumes the paused watcher instance to deliver fs events again. */
+export function resume(ext: JsExternal): void;
+/**
+ * This function delivers only the events of the paths under any of the given prefixes
+ * without rewatching the paths, which can be used to focus on a subtree of the watched
+ * paths temporarily. The prefixes are normalized into their absolute form the same way
+ * as the paths are added. Passing an empty list clears the filter.
+ */
+export function setPrefixFilter(ext: JsExternal, prefixes: Array<string>): void;
+/**
+ * This function replaces the ignore, include, kinds and extensions filters with the
+ * ones in the given options JSON without rewatching the paths. The new filters apply to
+ * the events delivered after it returns, and the filters not given are cleared. Fails
+ * without changing the filters if any of the patterns is invalid. The lifecycle events
+ * such as ready and overflow are delivered regardless of the kinds filter.
+ */
+export function setFilters(ext: JsExternal, opts: string): void;
+/**
+ * This function updates the interval in milliseconds at which the poll watcher scans
+ * for fs events, keeping the watched paths. Setting zero will fall back to the default.
+ * Fails if the interval is below 100 ms or the watcher is not using the poll backend.
+ */
+export function setPollInterval(ext: JsExternal, ms: number): void;
+/** Statistics of the events handled by the watcher instance */
+export interface WatcherStats {
+  /** Number of the events received from the backend and the initial scan */
+  eventsSeen: number;
+  /** Number of the events delivered to the callback */
+  eventsDelivered: number;
+  /** Number of the events suppressed as duplicates or by the filters */
+  eventsSuppressed: number;
+  /** Number of the events dropped as the event queue is full */
+  eventsDropped: number;
+  /** Number of the paths being watched */
+  watchedPaths: number;
+}
+/**
+ * This function returns the statistics of the events handled by the watcher instance
+ * since it was created, along with the number of paths being watched.
+ */
+export function stats(ext: JsExternal): WatcherStats;
+/**
+ * This function returns the statistics of the watcher registered with the given id the
+ * same way as stats. Fails if no watcher is registered with the id.
+ */
+export function statsById(id: number): WatcherStats;
+/** Decision made on the event by the watcher instance in the dry run mode */
+export interface DebugEntry {
+  /**
+   * Raw event kind reported by the backend such as Modify(Data(Content)), or null for
+   * the events produced by the watcher itself such as ready and the initial events
+   */
+  raw?: string;
+  event: WatchEvent;
+  /** Whether the event would be delivered to the callback */
+  delivered: boolean;
+  /**
+   * Reason the event is suppressed, which is one of other, ignored, notIncluded,
+   * duplicate, throttled, debounced, coalesced, settled, pending, filtered, rateLimited
+   * or once
+   */
+  reason?: string;
+}
+/**
+ * This function returns the decisions made on the events by the watcher instance created
+ * with the dryRun option since the previous call, in the order they are made. The log
+ * keeps the latest decisions up to a limit. Returns an empty list if the watcher is not
+ * in the dry run mode.
+ */
+export function drainDebug(ext: JsExternal): Array<DebugEntry>;
+/**
+ * This function returns the name of the backend used by the watcher instance, which is
+ * one of inotify, fsevents, kqueue, readDirectoryChanges, poll or unknown. The backend
+ * is poll if the first watched path is detected to be on a network file system.
+ */
+export function backend(ext: JsExternal): string;
+/**
+ * This function watches the given path once without creating the watcher instance, and
+ * returns a promise resolving with the first event allowed by the filters in the options.
+ * The promise is rejected if no event arrives within the timeout_ms option if given, if
+ * the watcher stops before any event, or if the options are malformed. The promise
+ * occupies a thread of the libuv thread pool until it settles.
+ */
+export function waitForChange(
+  path: string,
+  options: string
+): Promise<WatchEvent>;
+/**
+ * This function subscribes to the events of the watcher instance, returning the event
+ * iterator read by nextEvent. The events are the ones delivered to the callback, with
+ * the absolute paths and the native kinds regardless of the relativeTo, kindMap, compat,
+ * normalizeSeparators and format options. Up to the given capacity of events, 1000 by
+ * default, are kept until read, beyond which the events are dropped and an overflow
+ * event is yielded in their place. Fails if the watcher is closed.
+ */
+export function events(ext: JsExternal, capacity?: number): JsExternal;
+/**
+ * This function returns a promise resolving with the next event of the event iterator
+ * returned by events, or with null once the watcher or the iterator is closed and the
+ * events kept so far are read. The promise occupies a thread of the libuv thread pool
+ * until it settles.
+ */
+export function nextEvent(stream: JsExternal): Promise<WatchEvent | null>;
+/**
+ * This function closes the event iterator returned by events, which resolves its
+ * pending and later nextEvent promises with null. The watcher is not affected.
+ */
+export function closeEvents(stream: JsExternal): void;
+/** Features supported by the backend of the watcher instance */
+export interface Capabilities {
+  /**
+   * Whether the paths can be watched recursively by the backend, which watches the
+   * sub-directories created later as well
+   */
+  recursive: boolean;
+  /**
+   * Whether the rename events are reported in pairs of source and destination, which
+   * are correlated into single rename event
+   */
+  renameCorrelation: boolean;
+  /** Whether the access events are reported, including the close write events */
+  accessEvents: boolean;
+  /** Whether the events are detected by polling, which supports comparing the contents */
+  pollOnly: boolean;
+}
+/**
+ * This function returns the features supported by the backend of the watcher instance,
+ * which can be used to degrade gracefully on the backends lacking them.
+ */
+export function capabilities(ext: JsExternal): Capabilities;
+/** Versions and features the native addon is built with */
+export interface BuildInfo {
+  /** Version of the native addon */
+  version: string;
+  /** Version of the notify crate providing the backends */
+  notifyVersion: string;
+  /** Target triple such as x86_64-unknown-linux-gnu */
+  target: string;
+  /** Build profile, either debug or release */
+  profile: string;
+  /**
+   * Platform dependent features supported by the build, among fanotify for the tracePid
+   * option, networkDetection for the autoDetectNetwork option, closeWrite for the
+   * closeWrite option and fileId for the includeFileId option
+   */
+  features: Array<string>;
+}
+/**
+ * This function returns the versions and features the native addon is built with, which
+ * helps telling apart the behaviors of the builds for different platforms.
+ */
+export function info(): BuildInfo;
+/**
+ * This function scans the paths watched by the poll watcher right away instead of
+ * waiting for the poll interval, and emits the changes not reported yet. The poll
+ * watcher is restarted so that the changes are not reported again. Returns false
+ * without scanning if the watcher is not using the poll backend.
  */
-export function unwatch(ext: JsExternal, dir: string): void;
+export function rescan(ext: JsExternal): boolean;
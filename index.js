@@ -261,4 +261,45 @@ if (!nativeBinding) {
   throw new Error(`Failed to load native binding`);
 }
 
-export const {watch, add, unwatch} = nativeBinding;
+export const {
+  watch,
+  watchWithToken,
+  abort,
+  watchWithId,
+  addById,
+  unwatchById,
+  unwatchAllById,
+  closeById,
+  listById,
+  isWatchedById,
+  pauseById,
+  resumeById,
+  statsById,
+  add,
+  addRecursive,
+  addAll,
+  addWithOptions,
+  addGlob,
+  addFile,
+  unwatch,
+  unwatchAll,
+  flush,
+  close,
+  list,
+  isWatched,
+  pause,
+  resume,
+  setPrefixFilter,
+  setFilters,
+  setPollInterval,
+  stats,
+  drainDebug,
+  backend,
+  waitForChange,
+  events,
+  nextEvent,
+  closeEvents,
+  capabilities,
+  info,
+  rescan,
+} = nativeBinding;
@@ -4,7 +4,7 @@ import path from 'path';
 import picomatch from 'picomatch';
 import lsdirp from 'lsdirp';
 import {statSync} from 'fs';
-import {unwatch, watch as notify, add} from './../index.js';
+import {unwatch, watch as notify, add, close} from './../index.js';
 
 // Options to configure watch
 interface WatchOptions {
@@ -17,15 +17,44 @@ interface WatchOptions {
    * native watcher is available. eg: docker.
    *  @default false */
   usePolling?: boolean;
-  /** Set the interval in seconds at which the file system should be polled
-   * for fs events. keep the value above 1 sec to avoid frequent polling.
-   * setting negative value will set usePolling option false.
-   * @default 4 */
+  /** Set the interval in milliseconds at which the file system should be
+   * polled for fs events. keep the value above 1000 ms to avoid frequent
+   * polling. setting zero or negative value will fall back to the default.
+   * values below 100 ms are rejected, as they are likely given in seconds.
+   * @default 4000 */
   pollInterval?: number;
 }
 
+// Shortest poll interval in milliseconds accepted, below which the interval is likely
+// given in seconds as it was before
+const MIN_POLL_INTERVAL = 100;
+
 // List of events that will be emitted by the watcher
-type EventName = 'add' | 'addDir' | 'modify' | 'remove' | 'removeDir';
+type EventName =
+  | 'add'
+  | 'addDir'
+  | 'modify'
+  | 'remove'
+  | 'removeDir'
+  | 'rename';
+
+// Event yielded by the async iterator of the watcher. The overflow event marks where
+// events were dropped, and has an empty path.
+interface WatchEvent {
+  kind: EventName | 'overflow';
+  path: string;
+  oldPath?: string;
+}
+
+// Maximum number of events queued for an async iterator, beyond which the events are
+// dropped until the iterator catches up
+const MAX_QUEUED_EVENTS = 10000;
+
+// Pending events of an async iterator along with the resolver of the awaiting next call
+interface EventQueue {
+  events: WatchEvent[];
+  resolve?: (result: IteratorResult<WatchEvent>) => void;
+}
 
 // eslint-disable-next-line @typescript-eslint/no-explicit-any
 const mergeObj = <T extends Record<string, any>>(target: T, source: T) => {
@@ -47,6 +76,7 @@ class FsEvent extends EventEmitter {
   #ignorePatterns = new Map<string, [Set<string>, picomatch.Matcher]>();
   #recursivePatterns = new Map<string, [Set<string>, picomatch.Matcher]>();
   #driveLetter = '';
+  #queues = new Set<EventQueue>();
 
   constructor(options: WatchOptions) {
     super();
@@ -58,11 +88,17 @@ class FsEvent extends EventEmitter {
     const opts: Required<WatchOptions> = {
       ignored: [],
       usePolling: false,
-      pollInterval: 4,
+      pollInterval: 4000,
     };
 
     mergeObj(opts, options);
 
+    if (opts.pollInterval > 0 && opts.pollInterval < MIN_POLL_INTERVAL) {
+      throw new RangeError(
+        `pollInterval of ${opts.pollInterval} ms is below ${MIN_POLL_INTERVAL} ms, it is given in milliseconds rather than seconds`
+      );
+    }
+
     // Push only non-empty string into ignored field for creating matcher.
     opts.ignored.forEach((ignored) => {
       if (typeof ignored === 'string' && ignored !== '') {
@@ -226,15 +262,29 @@ class FsEvent extends EventEmitter {
     // to the notify addon
     this.#watcher = notify(
       JSON.stringify({use_polling: usePolling, poll_interval: pollInterval}),
-      (err, data) => {
-        if (err) {
-          // Emits 'error' event upon watch error from native module
-          this.emit('error', err);
+      (_err, data) => {
+        // Watcher was restarted after a backend error and events may be missed
+        if ((data as {kind: string}).kind === 'restart') {
+          this.emit('restart');
+          return;
+        }
+        // Ready event is emitted by this watcher once all the paths are added
+        if ((data as {kind: string}).kind === 'ready') {
+          return;
+        }
+        // Events were dropped as the native event queue or the backend queue is full
+        if ((data as {kind: string}).kind === 'overflow') {
+          this.emit('overflow');
+          this.#pushEvent({kind: 'overflow', path: ''});
+          return;
         }
 
-        const event = JSON.parse(data) as {kind: EventName; path: string};
+        const event = data as WatchEvent;
 
         event.path = path.relative('.', event.path).replace(/\\/g, '/');
+        if (event.oldPath !== undefined) {
+          event.oldPath = path.relative('.', event.oldPath).replace(/\\/g, '/');
+        }
 
         if (this.#isNotIgnored(event.path)) {
           if (event.kind === 'addDir' && this.#isRecursiveMatch(event.path)) {
@@ -242,14 +292,29 @@ class FsEvent extends EventEmitter {
           } else if (event.kind === 'remove' && this.#dirs.has(event.path)) {
             event.kind = 'removeDir';
             this.unwatch(event.path);
+          } else if (
+            event.kind === 'rename' &&
+            event.oldPath !== undefined &&
+            this.#dirs.has(event.oldPath)
+          ) {
+            // Moves the watch from renamed directory to its new path
+            this.unwatch(event.oldPath);
+            if (this.#isRecursiveMatch(event.path)) {
+              this.add(event.path);
+            }
           }
 
           if (this.#isMatch(event.path)) {
             // Emits file system events
-            this.emit(event.kind, event.path);
-            this.emit('all', event.kind, event.path);
+            this.emit(event.kind, event.path, event.oldPath);
+            this.emit('all', event.kind, event.path, event.oldPath);
+            this.#pushEvent(event);
           }
         }
+      },
+      (err) => {
+        // Emits 'error' event upon watch error from native module
+        this.emit('error', err);
       }
     );
   }
@@ -279,8 +344,71 @@ class FsEvent extends EventEmitter {
     }
   }
 
+  /**
+   * Delivers the event to the awaiting next call of each async iterator, or queues it
+   * until the next call. The event is dropped if the queue is full, in which case an
+   * overflow event is queued in its place unless one is queued last already.
+   * @param {WatchEvent} event - The event to be delivered.
+   */
+  #pushEvent(event: WatchEvent) {
+    this.#queues.forEach((queue) => {
+      if (queue.resolve) {
+        queue.resolve({value: event, done: false});
+        queue.resolve = undefined;
+      } else if (queue.events.length < MAX_QUEUED_EVENTS) {
+        queue.events.push(event);
+      } else if (queue.events[queue.events.length - 1].kind !== 'overflow') {
+        queue.events.push({kind: 'overflow', path: ''});
+      }
+    });
+  }
+
+  /**
+   * Ends the async iterator of the given queue. The queued events are still yielded
+   * before the iterator completes.
+   * @param {EventQueue} queue - The queue of the iterator to be ended.
+   */
+  #endQueue(queue: EventQueue) {
+    this.#queues.delete(queue);
+    queue.resolve?.({value: undefined, done: true});
+    queue.resolve = undefined;
+  }
+
   // Public methods
 
+  /**
+   * Returns an async iterator yielding the file system events from the time it is
+   * created. The iterator completes once the watcher is closed or the loop is exited.
+   * Events are dropped while the iterator is too far behind, which is marked by an
+   * overflow event.
+   * @returns {AsyncIterableIterator<WatchEvent>} - The async iterator of the events.
+   */
+  events(): AsyncIterableIterator<WatchEvent> {
+    const queue: EventQueue = {events: []};
+    this.#queues.add(queue);
+
+    return {
+      next: (): Promise<IteratorResult<WatchEvent>> => {
+        const event = queue.events.shift();
+        if (event !== undefined) {
+          return Promise.resolve({value: event, done: false});
+        }
+        if (!this.#queues.has(queue)) {
+          return Promise.resolve({value: undefined, done: true});
+        }
+        return new Promise((resolve) => (queue.resolve = resolve));
+      },
+      return: (): Promise<IteratorResult<WatchEvent>> => {
+        queue.events = [];
+        this.#endQueue(queue);
+        return Promise.resolve({value: undefined, done: true});
+      },
+      [Symbol.asyncIterator]() {
+        return this;
+      },
+    };
+  }
+
   /**
    * Adds the provided paths to be watched.
    * @param {string | string[]} paths - The paths to be watched.
@@ -330,6 +458,12 @@ class FsEvent extends EventEmitter {
       this.#unwatchPath(path);
     });
 
+    // Release the native watcher resources
+    close(this.#watcher);
+
+    // End the async iterators
+    this.#queues.forEach((queue) => this.#endQueue(queue));
+
     // Clear all fields
     this.#dirs.clear();
     this.#files.clear();
@@ -338,16 +472,22 @@ class FsEvent extends EventEmitter {
     this.#recursivePatterns.clear();
   }
 
+  override on(
+    eventName: 'rename',
+    listener: (path: string, oldPath: string) => void
+  ): this;
   override on(eventName: EventName, listener: (path: string) => void): this;
   override on(
     eventName: 'all',
-    listener: (event: EventName, path: string) => void
+    listener: (event: EventName, path: string, oldPath?: string) => void
   ): this;
   override on(eventName: 'error', listener: (error: Error) => void): this;
   override on(eventName: 'ready', listener: () => void): this;
+  override on(eventName: 'restart', listener: () => void): this;
+  override on(eventName: 'overflow', listener: () => void): this;
   /**
-   * @param eventName {'add' | 'addDir' | 'modify' | 'remove' | 'removeDir' | 'all' | 'error' | 'ready'}
-   * @param listener {((path: string) => void) | ((event: 'add' | 'addDir' | 'modify' | 'remove' | 'removeDir', path: string) => void) | ((error: Error) => void) | (() => void)}
+   * @param eventName {'add' | 'addDir' | 'modify' | 'remove' | 'removeDir' | 'rename' | 'all' | 'error' | 'ready' | 'restart' | 'overflow'}
+   * @param listener {((path: string) => void) | ((path: string, oldPath: string) => void) | ((event: 'add' | 'addDir' | 'modify' | 'remove' | 'removeDir' | 'rename', path: string, oldPath?: string) => void) | ((error: Error) => void) | (() => void)}
    */
   override on(
     eventName: string | symbol,
@@ -365,7 +505,7 @@ let watcher: FsEvent;
  * @typedef {object} WatchOptions
  * @property {string[]} ignored Ignores added files or directories from watching
  * @property {boolean} usePolling If true, poll watcher will be used
- * @property {number} pollInterval Set the interval in seconds at which the file system should be polled for fs events
+ * @property {number} pollInterval Set the interval in milliseconds at which the file system should be polled for fs events
  */
 
 /**
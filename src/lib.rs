@@ -4,200 +4,8163 @@
 extern crate napi_derive;
 // extern crate globwalk;
 
-use std::path::{Path, PathBuf};
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{
+  atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+  mpsc::{self, RecvTimeoutError, TrySendError},
+  Arc, Condvar, LazyLock, Mutex, MutexGuard, RwLock, Weak,
+};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 // use globwalk::FileType;
+use globset::{Glob, GlobBuilder, GlobSet, GlobSetBuilder};
+use ignore::{
+  gitignore::{Gitignore, GitignoreBuilder},
+  Match,
+};
 use napi::{
   bindgen_prelude::*,
   threadsafe_function::{
     ErrorStrategy, ThreadSafeCallContext, ThreadsafeFunction, ThreadsafeFunctionCallMode,
   },
-  JsExternal, JsString, JsUndefined,
+  JsExternal, JsNumber, JsObject, JsString, JsUndefined,
 };
 use notify::{
-  event::{ModifyKind, RenameMode},
-  Config, Event, EventKind, PollWatcher, RecommendedWatcher, RecursiveMode, Watcher,
+  event::{
+    AccessKind, AccessMode, CreateKind, Flag, MetadataKind, ModifyKind, RemoveKind, RenameMode,
+  },
+  Config, Event, EventHandler, EventKind, NullWatcher, PollWatcher, RecommendedWatcher,
+  RecursiveMode, Watcher, WatcherKind,
 };
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use walkdir::WalkDir;
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 // FsEvent type
 pub struct FsEvent {
   kind: String,
   path: PathBuf,
+  // Timestamp in nanoseconds since epoch
   ts: u128,
+  // Sequence number in the order the events are produced
+  seq: u64,
+  // Source path of the rename event
+  #[serde(skip_serializing_if = "Option::is_none")]
+  old_path: Option<PathBuf>,
+  // Whether the path is replaced by renaming another file over it
+  #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+  atomic: bool,
+  // Full event kind reported by the backend
+  #[serde(skip_serializing_if = "Option::is_none")]
+  detail: Option<String>,
+  // Current and previous targets of the symlink change event
+  #[serde(skip_serializing_if = "Option::is_none")]
+  target: Option<PathBuf>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  old_target: Option<PathBuf>,
+  // Size of the file in bytes
+  #[serde(skip_serializing_if = "Option::is_none")]
+  size: Option<u64>,
+  // Device and inode numbers identifying the file
+  #[serde(skip_serializing_if = "Option::is_none")]
+  dev: Option<u64>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  ino: Option<u64>,
+  // Number of the events coalesced into the summary event
+  #[serde(skip_serializing_if = "Option::is_none")]
+  count: Option<u64>,
+  // Flags attributed to the event by the backend
+  #[serde(skip_serializing_if = "Option::is_none")]
+  flags: Option<Vec<String>>,
+  // Id of the process which modified the file
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pid: Option<u32>,
 }
 
+// Sequence number of the next event, which is shared by all the watcher instances. The
+// events of a watcher are numbered in strictly increasing order regardless of the order
+// in which they are delivered.
+static NEXT_SEQ: AtomicU64 = AtomicU64::new(1);
+
 impl FsEvent {
   fn new(kind: String, path: PathBuf, ts: u128) -> Self {
-    FsEvent { kind, path, ts }
+    FsEvent {
+      kind,
+      path,
+      ts,
+      seq: NEXT_SEQ.fetch_add(1, Ordering::Relaxed),
+      old_path: None,
+      atomic: false,
+      detail: None,
+      target: None,
+      old_target: None,
+      size: None,
+      dev: None,
+      ino: None,
+      count: None,
+      flags: None,
+      pid: None,
+    }
+  }
+
+  // Checks whether this event is a duplicate of the previous event, i.e. same kind
+  // and path occurred within the debounce window in nanoseconds.
+  fn is_duplicate(&self, prev: &FsEvent, debounce_ns: u128) -> bool {
+    self.kind == prev.kind && self.path == prev.path && self.ts < prev.ts + debounce_ns
+  }
+}
+
+/// File system event delivered to the javascript callback
+#[napi(object)]
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WatchEvent {
+  pub kind: String,
+  pub path: String,
+  /// Timestamp in milliseconds since epoch with sub-millisecond precision
+  pub ts: f64,
+  /// Sequence number in the order the events are produced, which is strictly increasing
+  /// regardless of the delivery order
+  pub seq: f64,
+  /// Source path of the rename event
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub old_path: Option<String>,
+  /// Whether the modify event is caused by renaming another file over the path, as in
+  /// the atomic saves of editors
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub atomic: Option<bool>,
+  /// Full event kind reported by the backend such as Modify(Data(Content)), if the
+  /// detail option is enabled
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub detail: Option<String>,
+  /// Current target of the symlink change event
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub target: Option<String>,
+  /// Previous target of the symlink change event
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub old_target: Option<String>,
+  /// Size of the file in bytes if the includeSize option is enabled, or null if the
+  /// file doesn't exist anymore
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub size: Option<f64>,
+  /// Device and inode numbers identifying the file across renames if the includeFileId
+  /// option is enabled, or null if the file doesn't exist anymore. Only supported on unix.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub dev: Option<f64>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub ino: Option<f64>,
+  /// Number of the events dropped by the maxEventsPerSecond option since the previous
+  /// coalesced event
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub count: Option<f64>,
+  /// Flags attributed to the event by the backend if the includeFlags option is enabled,
+  /// such as rescan and the info of FSEvents like "rescan: kernel dropped" or "is: symlink"
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub flags: Option<Vec<String>>,
+  /// Id of the process which modified the file if the tracePid option is enabled, or null
+  /// if it can't be traced. Only supported on linux with the CAP_SYS_ADMIN capability.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub pid: Option<u32>,
+  /// Raw bytes of the path if it is not valid UTF-8, in which case the path is converted
+  /// lossily. Only supported on unix.
+  #[serde(skip)]
+  pub path_bytes: Option<Buffer>,
+}
+
+// Format of the events delivered to the javascript callback. The events are passed as
+// objects in json format, or encoded into a buffer in msgpack format.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum EventFormat {
+  #[default]
+  Json,
+  Msgpack,
+}
+
+// Edge of the burst of duplicate events on each path at which the event is delivered
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum DebounceEdge {
+  // First event of the burst, suppressing the rest within debounce_ms of it
+  #[default]
+  Leading,
+  // Last event of the burst, once the path is quiet for debounce_ms
+  Trailing,
+  // First and last events of the burst
+  Both,
+}
+
+// Types of the paths whose events are delivered
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum EventTargets {
+  #[default]
+  All,
+  Files,
+  Dirs,
+}
+
+// Vocabulary of other watchers which the event kinds are mapped into
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum EventCompat {
+  Chokidar,
+}
+
+impl EventCompat {
+  // Maps the native event kind into the vocabulary, keeping the kinds which don't have
+  // the equivalent as is.
+  fn map_kind(self, kind: String) -> String {
+    match self {
+      EventCompat::Chokidar => match kind.as_str() {
+        "modify" => "change".to_string(),
+        "remove" => "unlink".to_string(),
+        "removeDir" => "unlinkDir".to_string(),
+        _ => kind,
+      },
+    }
   }
 }
 
-// PartialEq implementation for FsEvent where the curr_ev and prev_ev is checked
-// for not-equality.
-impl PartialEq for FsEvent {
-  fn eq(&self, other: &Self) -> bool {
-    self.kind == other.kind && self.path == other.path && self.ts == other.ts
+// The timestamp is converted into fractional milliseconds, which retains sub-microsecond
+// precision as a javascript number.
+impl From<FsEvent> for WatchEvent {
+  fn from(ev: FsEvent) -> Self {
+    WatchEvent {
+      path_bytes: path_bytes(&ev.path),
+      kind: ev.kind,
+      path: ev.path.to_string_lossy().into_owned(),
+      ts: ev.ts as f64 / 1e6,
+      seq: ev.seq as f64,
+      old_path: ev
+        .old_path
+        .map(|old_path| old_path.to_string_lossy().into_owned()),
+      atomic: ev.atomic.then_some(true),
+      detail: ev.detail,
+      target: ev
+        .target
+        .map(|target| target.to_string_lossy().into_owned()),
+      old_target: ev
+        .old_target
+        .map(|old_target| old_target.to_string_lossy().into_owned()),
+      size: ev.size.map(|size| size as f64),
+      dev: ev.dev.map(|dev| dev as f64),
+      ino: ev.ino.map(|ino| ino as f64),
+      count: ev.count.map(|count| count as f64),
+      flags: ev.flags,
+      pid: ev.pid,
+    }
   }
+}
 
-  fn ne(&self, other: &Self) -> bool {
-    // Don't invoke callback function if the event kind is other
-    if self.kind == "other" {
-      return false;
+// Returns the raw bytes of the path which is not valid UTF-8
+#[cfg(unix)]
+fn path_bytes(path: &Path) -> Option<Buffer> {
+  use std::os::unix::ffi::OsStrExt;
+  match path.to_str() {
+    Some(_) => None,
+    None => Some(Buffer::from(path.as_os_str().as_bytes().to_vec())),
+  }
+}
+
+#[cfg(not(unix))]
+fn path_bytes(_path: &Path) -> Option<Buffer> {
+  None
+}
+
+// Converts the path passed from javascript either as a string or a buffer of raw bytes.
+// The buffer is required for the paths which are not valid UTF-8 on unix.
+fn js_path(dir: Either<JsString, Buffer>) -> Result<PathBuf> {
+  match dir {
+    Either::A(dir) => Ok(PathBuf::from(dir.into_utf8()?.as_str()?)),
+    #[cfg(unix)]
+    Either::B(bytes) => {
+      use std::os::unix::ffi::OsStrExt;
+      Ok(PathBuf::from(std::ffi::OsStr::from_bytes(&bytes)))
     }
-    self.kind != other.kind || self.path != other.path || self.ts >= other.ts + 50
+    #[cfg(not(unix))]
+    Either::B(bytes) => String::from_utf8(bytes.to_vec())
+      .map(PathBuf::from)
+      .map_err(|e| Error::new(Status::InvalidArg, format!("{}", e))),
   }
 }
 
-// Options to configure watcher instance
+// Default interval in milliseconds at which the poll watcher scans for fs events
+const DEFAULT_POLL_INTERVAL: i64 = 4000;
+
+// Shortest poll interval in milliseconds accepted, below which the interval is likely
+// given in seconds as it was before
+const MIN_POLL_INTERVAL: u64 = 100;
+
+// Default window in milliseconds within which duplicate events are suppressed
+const DEFAULT_DEBOUNCE_MS: u64 = 50;
+
+// Maximum number of events buffered in a batch before it is flushed
+const MAX_BATCH_SIZE: usize = 1000;
+
+// Number of tracked recent events after which the stale ones are pruned
+const MAX_RECENT_EVENTS: usize = 1000;
+
+// Delay in milliseconds before the paths having stale file handles are watched again
+const STALE_REWATCH_DELAY_MS: u64 = 500;
+
+// Default cap in milliseconds of the delay between the retries of the poll watcher after
+// an error
+const DEFAULT_MAX_BACKOFF_MS: u64 = 60000;
+
+// Number of failed probes of the poll watcher after an error before polling is resumed
+// regardless
+const MAX_BACKOFF_PROBES: u32 = 10;
+
+// Maximum number of recently modified paths whose modifying processes are traced
+#[cfg(target_os = "linux")]
+const MAX_TRACED_PIDS: usize = 10_000;
+
+// Time in milliseconds waited for the fanotify event of the modified path
+#[cfg(target_os = "linux")]
+const PID_WAIT_MS: libc::c_int = 10;
+
+// Maximum number of symlinks followed by the watcher
+const MAX_LINK_TARGETS: usize = 10_000;
+
+// Maximum number of paths whose file types are cached
+const MAX_FILE_TYPES: usize = 100_000;
+
+// Options to configure watcher instance. Unknown options are rejected, so that the
+// misspelled options don't fall back to the defaults silently.
 #[derive(Serialize, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
 struct WatchOptions {
   use_polling: bool,
-  poll_interval: u64,
+  // Poll interval in milliseconds, which is rejected below MIN_POLL_INTERVAL. Zero or
+  // negative values fall back to default.
+  poll_interval: i64,
+  recursive: bool,
+  // Window in milliseconds within which duplicate events are suppressed
+  debounce_ms: u64,
+  // Glob patterns matched against the full event path to drop the event. The patterns
+  // prefixed with ! include back the paths ignored by the preceding patterns.
+  ignore: Vec<String>,
+  // Glob patterns matched against the full event path to allow only those events
+  include: Vec<String>,
+  // Interval in milliseconds at which the buffered events are delivered as an array.
+  // Zero disables batching.
+  batch_ms: u64,
+  // Kinds of events to be delivered. Empty list delivers all kinds. The lifecycle events
+  // such as ready are delivered regardless.
+  kinds: Vec<String>,
+  // If true, access events such as open, read and close are delivered
+  access: bool,
+  // If true, metadata change events such as permissions, ownership and times changes
+  // are delivered
+  metadata: bool,
+  // If true, poll watcher compares the file contents instead of modification time to
+  // detect changes. Has no effect if use_polling is false.
+  compare_contents: bool,
+  // If true, add events are emitted for the existing entries of the paths being added
+  emit_initial: bool,
+  // If true, the targets of the symlinked directories are watched as well
+  follow_symlinks: bool,
+  // Maximum depth of the entries below the watched paths to be reported when watching
+  // recursively. The directories are watched one by one up to this depth.
+  max_depth: Option<u32>,
+  // If true, events for dotfiles and entries under dot-directories are dropped
+  ignore_dotfiles: bool,
+  // File extensions of the events to be delivered, matched case-insensitively. If not
+  // empty, directory events and files without extension are dropped.
+  extensions: Vec<String>,
+  // Whether the poll watcher is used instead of the recommended watcher, if the path
+  // being watched first is on a network file system
+  auto_detect_network: bool,
+  // Whether the watcher is recreated and the paths are watched again after a fatal
+  // backend error, which is notified through the restart event. Events may be missed in
+  // the meantime as with the overflow event.
+  auto_restart: bool,
+  // Maximum number of event deliveries waiting for the javascript callback. Further
+  // events are dropped and reported by a single overflow event. 0 means unbounded.
+  max_queue: u32,
+  // Maximum queue size of the javascript callback, 0 means unbounded. Deliveries failing
+  // on the full queue are reported to the error callback if provided.
+  tsfn_queue_size: u32,
+  // Whether the background threads wait for the space in the callback queue instead of
+  // dropping the events, which keeps the events in order. It takes effect only with a
+  // tsfn_queue_size and stalls the watcher if the javascript callback is slow.
+  blocking: bool,
+  // Whether the backslash separators of the event paths are converted into forward
+  // slashes on windows
+  normalize_separators: bool,
+  // Base directory to which the event paths are made relative. The paths not under it
+  // are delivered in their absolute form.
+  relative_to: Option<String>,
+  // Whether the event paths are canonicalized before delivering, which resolves the
+  // symlinks at the cost of accessing the file system for every event
+  canonicalize: bool,
+  // Format of the events delivered to the callback, either json or msgpack
+  format: EventFormat,
+  // Minimum interval in milliseconds between the events delivered for the same path.
+  // The first event is delivered right away and the following ones within the interval
+  // are dropped. 0 disables throttling.
+  throttle_ms: u64,
+  // Whether the events carry the full event kind reported by the backend
+  detail: bool,
+  // Whether the file closed after writing is reported as change event, signalling that
+  // the writer has finished. Only supported on linux.
+  close_write: bool,
+  // Whether the burst of modify events on the same path is coalesced into the last one,
+  // which is delivered once the path is quiet for debounce_ms
+  coalesce_modify: bool,
+  // Whether the add, modify and remove events of each path are held until the path is
+  // quiet for debounce_ms, and delivered as single event of their net effect, i.e. add if
+  // the path is new, modify if it existed before and remove if it ended removed
+  settle: bool,
+  // Whether the duplicate events within debounce_ms and the events of other kind are
+  // suppressed. If disabled, every event from the backend is delivered.
+  dedup: bool,
+  // Edge of the burst of duplicate events at which the event is delivered, either leading,
+  // trailing or both. Has no effect if dedup is false.
+  debounce_edge: DebounceEdge,
+  // Kinds of events whose duplicates are suppressed, while the events of other kinds are
+  // always delivered. All kinds are deduplicated if not given.
+  dedup_kinds: Option<Vec<String>>,
+  // Types of the paths whose events are delivered, either all, files or dirs
+  targets: EventTargets,
+  // Whether the events carry the size of the files, which accesses the file system for
+  // every event
+  include_size: bool,
+  // Whether the events carry the device and inode numbers of the files
+  include_file_id: bool,
+  // Vocabulary of other watchers into which the event kinds are mapped, such as
+  // chokidar. The kinds and filters in the options still use the native names.
+  compat: Option<EventCompat>,
+  // Names into which the native event kinds are mapped, which take precedence over the
+  // compat option. The unmapped kinds pass through unchanged.
+  kind_map: HashMap<String, String>,
+  // Time in milliseconds after which wait_for_change fails if no event arrives, 0 means
+  // waiting until the first event
+  timeout_ms: u64,
+  // Whether each watched path is unwatched after its first delivered event. The watcher
+  // is closed once all the watched paths are unwatched.
+  once: bool,
+  // Time in milliseconds after which the watcher is closed if no event is delivered, 0
+  // means no timeout. The idleTimeout event is emitted once it is closed.
+  idle_timeout_ms: u64,
+  // Maximum number of the events delivered per second across all the paths, 0 means
+  // unlimited. The events dropped over the limit are summarized by the coalesced event
+  // every second.
+  max_events_per_second: u32,
+  // Whether the path which doesn't exist yet is watched once it appears, through its
+  // nearest existing ancestor until then. The add event is emitted when it appears.
+  watch_pending: bool,
+  // Whether the events carry the flags attributed by the backend. The raw flags of the
+  // backends are not exposed by notify, so only its flag and info attributes are
+  // available, which are empty on most of the events.
+  include_flags: bool,
+  // Whether the paths are watched by the process-global watcher shared by the watcher
+  // instances created with this option, which runs all the watching on a single thread.
+  // The poll backend is never shared.
+  shared: bool,
+  // Whether the ignore patterns in the .watcherignore file of each watched path are
+  // applied to the events under it along with the ignore option, matched against the
+  // paths relative to the watched path. The patterns are reloaded whenever the file
+  // changes.
+  watcherignore: bool,
+  // Whether the events of the paths ignored by the .gitignore files in the watched paths
+  // and their sub-directories are dropped, along with the ones ignored by the rules of
+  // their repository and the global excludes file as git does. The rules of the
+  // .gitignore files under the watched paths are reloaded whenever they change.
+  respect_gitignore: bool,
+  // Whether the modify events carry the id of the process which modified the file, which
+  // is traced with fanotify on linux. Requires the CAP_SYS_ADMIN capability, without which
+  // the pid is left null.
+  trace_pid: bool,
+  // Config of the backend applied to both the recommended watcher and the poll watcher,
+  // whose settings take precedence over the poll_interval and compare_contents options
+  config: BackendConfig,
+  // Whether the events are recorded into the debug log along with the decision made on
+  // them, instead of being delivered to the callback. The log is read with drain_debug.
+  dry_run: bool,
+  // Cap in milliseconds of the delay between the retries of the poll watcher after an
+  // error, which doubles from the poll interval on each failed retry. Zero disables the
+  // backoff, delivering every error.
+  max_backoff_ms: u64,
+  // Maximum number of the watches used by the watcher, counting one for each directory
+  // watched by the backend. Adding the path which would exceed it fails. Zero means no
+  // limit other than the one of the OS.
+  max_watches: u32,
+  // Whether the paths added in the relative form are resolved against the current
+  // directory at the time they are added, so that the event paths are always absolute
+  // regardless of the later changes of the current directory. The paths are made
+  // relative to relative_to after that if given.
+  absolute_paths: bool,
 }
 
 // Implement default value for watchoptions. This will be
-// used if there is an error parsing json.
+// used for the options not given in json.
 impl Default for WatchOptions {
   fn default() -> Self {
     Self {
       use_polling: false,
-      poll_interval: 4,
+      poll_interval: DEFAULT_POLL_INTERVAL,
+      recursive: false,
+      debounce_ms: DEFAULT_DEBOUNCE_MS,
+      ignore: Vec::new(),
+      include: Vec::new(),
+      batch_ms: 0,
+      kinds: Vec::new(),
+      access: false,
+      metadata: false,
+      compare_contents: false,
+      emit_initial: false,
+      follow_symlinks: false,
+      max_depth: None,
+      ignore_dotfiles: false,
+      extensions: Vec::new(),
+      auto_detect_network: true,
+      auto_restart: true,
+      max_queue: 0,
+      tsfn_queue_size: 0,
+      blocking: false,
+      normalize_separators: false,
+      relative_to: None,
+      canonicalize: false,
+      format: EventFormat::Json,
+      throttle_ms: 0,
+      detail: false,
+      close_write: false,
+      coalesce_modify: false,
+      settle: false,
+      dedup: true,
+      debounce_edge: DebounceEdge::Leading,
+      dedup_kinds: None,
+      targets: EventTargets::All,
+      include_size: false,
+      include_file_id: false,
+      compat: None,
+      kind_map: HashMap::new(),
+      timeout_ms: 0,
+      once: false,
+      idle_timeout_ms: 0,
+      max_events_per_second: 0,
+      include_flags: false,
+      watch_pending: false,
+      shared: false,
+      watcherignore: false,
+      respect_gitignore: false,
+      trace_pid: false,
+      config: BackendConfig::default(),
+      dry_run: false,
+      max_backoff_ms: DEFAULT_MAX_BACKOFF_MS,
+      max_watches: 0,
+      absolute_paths: false,
     }
   }
 }
 
-// Filtering dirs using glob patterns for watching can also be done by using globwalk crate.
-// But it will result in bigger output size.
-//
-// fn walkdir() -> Result<()> {
-//   let walker = globwalk::GlobWalkerBuilder::from_patterns(
-//     std::env::current_dir()?,
-//     &["node_modules", "!**/.git", "!**/node_modules", "!**/target"],
-//   )
-//   .file_type(FileType::DIR)
-//   .build()
-//   .map_err(|e| Error::new(Status::GenericFailure, format!("{}", e)))?
-//   .into_iter();
+// Settings of the notify config given in the config option. The settings not given fall
+// back to the options of the same name.
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct BackendConfig {
+  // Poll interval in milliseconds, which is rejected below MIN_POLL_INTERVAL. Zero or
+  // negative values fall back to default.
+  poll_interval: Option<i64>,
+  // If true, poll watcher compares the file contents instead of modification time
+  compare_contents: Option<bool>,
+}
 
-//   for dir in walker {
-//     if let Ok(direntry) = dir {
-//       // watch the matched paths for fs events
-//     }
-//   }
+// Parses the options given in JSON format. Empty or null options fall back to the
+// defaults, while the malformed options fail with the reason.
+fn parse_options<T: DeserializeOwned + Default>(opts: &str) -> Result<T> {
+  if opts.trim().is_empty() {
+    return Ok(T::default());
+  }
+  serde_json::from_str::<Option<T>>(opts)
+    .map(Option::unwrap_or_default)
+    .map_err(|e| Error::new(Status::InvalidArg, format!("Invalid options: {}", e)))
+}
 
-//   Ok(())
-// }
+impl WatchOptions {
+  // Config of the backend from the config option and the options of the same name. It is
+  // passed to the recommended watcher as well, which uses the settings applicable to it,
+  // such as for its poll fallback on some platforms. Fails if the poll interval is below
+  // the shortest one accepted.
+  fn backend_config(&self) -> Result<Config> {
+    let poll_interval = self.config.poll_interval.unwrap_or(self.poll_interval);
+    let poll_interval = if poll_interval > 0 {
+      poll_interval as u64
+    } else {
+      DEFAULT_POLL_INTERVAL as u64
+    };
+    if poll_interval < MIN_POLL_INTERVAL {
+      return Err(Error::new(
+        Status::InvalidArg,
+        format!(
+          "Poll interval of {} ms is below {} ms, it is given in milliseconds rather than seconds",
+          poll_interval, MIN_POLL_INTERVAL
+        ),
+      ));
+    }
 
-/// Initiates recommended watcher instance with threadsafe callback function from
-/// node js main thread and call the callback on fs events. This function returns
-/// watcher instance which can be used to add paths to be watched for fs events.
-#[napi(ts_args_type = "options: string, callback: (err: null | Error, event: string) => void")]
-pub fn watch(env: Env, opts: JsString, callback: JsFunction) -> Result<JsExternal> {
-  let options: WatchOptions = serde_json::from_str(opts.into_utf8()?.as_str()?).unwrap_or_default();
-
-  // Javascript callback to be invoked for fs events
-  let tsfn: ThreadsafeFunction<FsEvent, ErrorStrategy::CalleeHandled> = callback
-    .create_threadsafe_function(0, |cx: ThreadSafeCallContext<FsEvent>| {
-      Ok(vec![cx
-        .env
-        .create_string_from_std(serde_json::to_string(&cx.value)?)?])
-    })?;
+    Ok(
+      Config::default()
+        .with_poll_interval(Duration::from_millis(poll_interval))
+        .with_compare_contents(
+          self
+            .config
+            .compare_contents
+            .unwrap_or(self.compare_contents),
+        ),
+    )
+  }
+}
 
-  // Assign the current event if this is not equal to it. This ensures that the callback
-  // function will not be called for duplicate events within 50 ms of time.
-  let mut evt: std::result::Result<FsEvent, notify::Error> =
-    Ok(FsEvent::new(String::new(), PathBuf::new(), 0));
+// Event handler shared by the watchers created for the watcher instance
+type SharedHandler = Arc<Mutex<dyn FnMut(notify::Result<Event>) + Send>>;
 
-  let mut event_handler = move |ev: notify::Result<Event>| {
-    // Mutable reference to previous event
-    let prev_ev = &mut evt;
+// Cached file types of the paths seen in the events, i.e. whether each path is a
+// directory, which is shared by the event handler and the initial scan.
+type FileTypes = Arc<Mutex<FileTypeCache>>;
 
-    // Get the current timestamp for comparing the duplicate event
-    let timestamp = SystemTime::now()
-      .duration_since(UNIX_EPOCH)
-      .unwrap()
-      .as_millis();
-
-    // Convert the notify event type into FsEvent type.
-    let curr_ev = ev.and_then(|evt| {
-      let path = evt.paths[0].clone();
-      let dir_suffix = if path.is_dir() { "Dir" } else { "" };
-
-      Ok(FsEvent::new(
-        match evt.kind {
-          EventKind::Create(_) => String::from("add") + dir_suffix,
-          EventKind::Modify(kind) => match kind {
-            // Handle rename event as remove and add event
-            ModifyKind::Name(RenameMode::From) => String::from("remove") + dir_suffix,
-            ModifyKind::Name(RenameMode::To) => String::from("add") + dir_suffix,
-            ModifyKind::Any if path.is_file() => String::from("modify"),
-            _ => String::from("other"),
-          },
-          EventKind::Remove(_) => String::from("remove") + dir_suffix,
-          _ => String::from("other"),
-        },
-        path,
-        timestamp,
-      ))
-    });
+// File types grouped by the interned prefix of each path and keyed by the path relative
+// to it, so that the long common prefix of the paths under a watched path is stored only
+// once. The prefix is the closest watched path, or the parent directory of the paths
+// outside of them. Prefixes are interned only for the paths not under any of the interned
+// ones, so the key of a path doesn't change once it is cached. The prefix of a path is
+// looked up through its ancestors, and is dropped once no entry is grouped by it.
+struct FileTypeCache {
+  roots: WatchedPaths,
+  prefixes: HashMap<PathBuf, HashMap<PathBuf, bool>>,
+  len: usize,
+}
+
+impl FileTypeCache {
+  fn new(roots: WatchedPaths) -> Self {
+    FileTypeCache {
+      roots,
+      prefixes: HashMap::new(),
+      len: 0,
+    }
+  }
+
+  // Returns the longest interned prefix of the path along with the path relative to it
+  fn key<'a>(&self, path: &'a Path) -> Option<(&'a Path, &'a Path)> {
+    path
+      .ancestors()
+      .find(|ancestor| self.prefixes.contains_key(*ancestor))
+      .map(|prefix| (prefix, path.strip_prefix(prefix).unwrap_or(path)))
+  }
+
+  fn len(&self) -> usize {
+    self.len
+  }
+
+  fn get(&self, path: &Path) -> Option<bool> {
+    let (prefix, relative) = self.key(path)?;
+    self.prefixes[prefix].get(relative).copied()
+  }
+
+  fn contains_key(&self, path: &Path) -> bool {
+    self.get(path).is_some()
+  }
 
-    // Invoke the callback function if the curr_ev is error type or not equal to prev_ev.
-    if curr_ev.is_err() || curr_ev.as_ref().unwrap() != prev_ev.as_ref().unwrap() {
-      // Assign curr_ev to prev_ev if not an error type
-      if let Ok(ev) = curr_ev.as_ref() {
-        *prev_ev = Ok(ev.clone());
+  // Caches the file type of the path, interning its prefix if needed
+  fn insert(&mut self, path: &Path, is_dir: bool) {
+    let (prefix, relative) = match self.key(path) {
+      Some((prefix, relative)) => (prefix.to_path_buf(), relative),
+      None => {
+        let roots = self.roots.read().unwrap();
+        let prefix = closest_root(&roots, path)
+          .cloned()
+          .or_else(|| path.parent().map(Path::to_path_buf))
+          .unwrap_or_default();
+        drop(roots);
+        let relative = path.strip_prefix(&prefix).unwrap_or(path);
+        (prefix, relative)
       }
-      tsfn.call(
-        curr_ev.map_err(|e| Error::new(Status::GenericFailure, format!("{}", e))),
-        ThreadsafeFunctionCallMode::NonBlocking,
-      );
+    };
+    let entries = self.prefixes.entry(prefix).or_default();
+    if entries.insert(relative.to_path_buf(), is_dir).is_none() {
+      self.len += 1;
+    }
+  }
+
+  fn remove(&mut self, path: &Path) -> Option<bool> {
+    let (prefix, relative) = self.key(path)?;
+    let entries = self.prefixes.get_mut(prefix)?;
+    let removed = entries.remove(relative)?;
+    self.len -= 1;
+    if entries.is_empty() {
+      self.prefixes.remove(prefix);
+    }
+    Some(removed)
+  }
+
+  // Removes the entries under the given directory, including the prefixes interned under
+  // it along with their entries
+  fn remove_under(&mut self, dir: &Path) {
+    if let Some((prefix, dir_relative)) = self.key(dir) {
+      let prefix = prefix.to_path_buf();
+      if let Some(entries) = self.prefixes.get_mut(&prefix) {
+        let before = entries.len();
+        entries.retain(|relative, _| !relative.starts_with(dir_relative));
+        self.len -= before - entries.len();
+      }
+    }
+    let len = &mut self.len;
+    self.prefixes.retain(|prefix, entries| {
+      let keep = !prefix.starts_with(dir) && !entries.is_empty();
+      if !keep {
+        *len -= entries.len();
+      }
+      keep
+    });
+  }
+
+  fn clear(&mut self) {
+    self.prefixes.clear();
+    self.len = 0;
+  }
+}
+
+// Targets of the symlinks followed, as read from the links, which are shared by the
+// event handler for detecting the changes of the targets.
+type LinkTargets = Arc<Mutex<HashMap<PathBuf, PathBuf>>>;
+
+// Entries under the paths watched by the poll watcher, compared with the entries scanned by
+// rescan. These are kept in sync with the events of the poll watcher, so that only the
+// changes not reported yet are detected. None unless the poll watcher is used.
+type ScanSnapshot = Arc<Mutex<Option<HashMap<PathBuf, EntryState>>>>;
+
+// Modification time and size of the entry compared on rescan
+#[derive(Clone, Copy, PartialEq)]
+struct EntryState {
+  modified: Option<SystemTime>,
+  len: u64,
+  is_dir: bool,
+}
+
+impl EntryState {
+  fn new(metadata: &fs::Metadata) -> Self {
+    EntryState {
+      modified: metadata.modified().ok(),
+      len: metadata.len(),
+      is_dir: metadata.is_dir(),
     }
+  }
+}
+
+// Returns the entries under the given path up to the given depth, including the path
+// itself, keyed by their normalized paths
+fn scan_entries(path: &Path, max_depth: usize) -> HashMap<PathBuf, EntryState> {
+  WalkDir::new(normalize_path(path))
+    .max_depth(max_depth)
+    .into_iter()
+    .filter_map(|entry| entry.ok())
+    .filter_map(|entry| {
+      let state = EntryState::new(&entry.metadata().ok()?);
+      Some((entry.into_path(), state))
+    })
+    .collect()
+}
+
+// Paths being watched, which are shared with the event filter for matching the event
+// paths relative to them.
+type WatchedPaths = Arc<RwLock<HashSet<PathBuf>>>;
+
+// Single files watched through their parent directory, which are tracked as watched paths
+// as well. The parent is dedicated to the files if it isn't watched otherwise, in which
+// case only the events of the files are delivered from it.
+#[derive(Default)]
+struct FileWatch {
+  files: HashSet<PathBuf>,
+  dedicated: bool,
+}
+
+// Watched files keyed by their parent directory, shared with the event filter
+type WatchedFiles = Arc<RwLock<HashMap<PathBuf, FileWatch>>>;
+
+// Prefixes of the paths whose events are delivered, which are set at runtime without
+// rewatching the paths. Empty prefixes allow all the paths.
+type PathPrefixes = Arc<RwLock<Vec<PathBuf>>>;
+
+// Patterns, kinds and extensions of the event filter, which are replaced at runtime
+// without rewatching the paths
+type FilterPatterns = Arc<RwLock<PatternFilter>>;
+
+// Path which doesn't exist yet, watched through its nearest existing ancestor until it
+// appears if the watch_pending option is set. It is watched with the given mode then.
+struct PendingWatch {
+  ancestor: PathBuf,
+  recursive_mode: RecursiveMode,
+}
+
+// Pending paths keyed by their normalized form, shared with the event handler for
+// delivering only the events of the pending paths from their ancestors
+type PendingPaths = Arc<Mutex<HashMap<PathBuf, PendingWatch>>>;
+
+// Glob patterns given for each of the watched paths, which are applied to the events under
+// the path in addition to the ignore and include options. Shared by the event filter.
+type PathGlobs = Arc<RwLock<HashMap<PathBuf, GlobSet>>>;
+
+// Name of the file in the watched path listing the ignore patterns for the path
+const IGNORE_FILE_NAME: &str = ".watcherignore";
+
+// Reads the ignore patterns from the ignore file of the given watched path, one glob
+// pattern per line skipping the blank lines and the comments starting with #. The patterns
+// are matched against the paths relative to the watched path, where a leading slash is
+// dropped and a trailing slash matches the directory along with the entries under it.
+// Returns None if the file doesn't exist or has no pattern.
+fn read_ignore_file(root: &Path) -> Result<Option<GlobSet>> {
+  let contents = match fs::read_to_string(root.join(IGNORE_FILE_NAME)) {
+    Ok(contents) => contents,
+    Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+    Err(e) => return Err(Error::new(Status::GenericFailure, format!("{}", e))),
+  };
+  let patterns: Vec<String> = contents
+    .lines()
+    .map(str::trim)
+    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+    .flat_map(|line| {
+      let pattern = line.trim_start_matches('/');
+      match pattern.strip_suffix('/') {
+        Some(dir) => vec![String::from(dir), format!("{}/**", dir)],
+        None => vec![String::from(pattern)],
+      }
+    })
+    .collect();
+  if patterns.is_empty() {
+    return Ok(None);
+  }
+  build_glob_set(&patterns).map(Some)
+}
+
+// Matchers of the .gitignore files under the watched paths keyed by the directory of each
+// of the files, along with the rules applying to each watched path from outside of it.
+// Shared by the event filter.
+#[derive(Default)]
+struct GitignoreMatchers {
+  dirs: HashMap<PathBuf, Gitignore>,
+  // Rules of the repository of each watched path in the order of precedence, i.e. the
+  // .gitignore files of the parent directories within the repository, .git/info/exclude
+  // and the global excludes file. These are loaded once when the path is added.
+  excludes: HashMap<PathBuf, Vec<Gitignore>>,
+}
+
+impl GitignoreMatchers {
+  fn is_empty(&self) -> bool {
+    self.dirs.is_empty() && self.excludes.is_empty()
+  }
+
+  fn clear(&mut self) {
+    self.dirs.clear();
+    self.excludes.clear();
+  }
+
+  // Removes the rules of the given watched path and the directories under it
+  fn remove_under(&mut self, root: &Path) {
+    self.dirs.retain(|dir, _| !dir.starts_with(root));
+    self.excludes.remove(root);
+  }
+
+  // Reloads the .gitignore file in the given directory
+  fn reload(&mut self, dir: &Path) {
+    match read_gitignore(dir) {
+      Some(gitignore) => self.dirs.insert(dir.to_path_buf(), gitignore),
+      None => self.dirs.remove(dir),
+    };
+  }
+
+  // Matches the path against the rules of its parent directories up to the watched path,
+  // followed by the rules of the repository. The nested files take precedence over the
+  // outer ones, and the last rule matching the path decides within each file. Returns
+  // whether the path is ignored, or None if no rule matches it.
+  fn matched(&self, root: &Path, path: &Path, is_dir: bool) -> Option<bool> {
+    let dirs = path
+      .ancestors()
+      .skip(1)
+      .take_while(|dir| dir.starts_with(root))
+      .filter_map(|dir| self.dirs.get(dir));
+    let excludes = self.excludes.get(root).into_iter().flatten();
+
+    dirs
+      .chain(excludes)
+      .find_map(|gitignore| match gitignore.matched(path, is_dir) {
+        Match::None => None,
+        Match::Ignore(_) => Some(true),
+        Match::Whitelist(_) => Some(false),
+      })
+  }
+}
+
+type GitignoreRules = Arc<RwLock<GitignoreMatchers>>;
+
+const GITIGNORE_FILE_NAME: &str = ".gitignore";
+
+// Reads the .gitignore file in the given directory into its matcher, which is None if the
+// file doesn't exist or has no rule. Invalid patterns are skipped as git does.
+fn read_gitignore(dir: &Path) -> Option<Gitignore> {
+  let path = dir.join(GITIGNORE_FILE_NAME);
+  if !path.is_file() {
+    return None;
+  }
+
+  let mut builder = GitignoreBuilder::new(dir);
+  builder.add(path);
+  builder
+    .build()
+    .ok()
+    .filter(|gitignore| !gitignore.is_empty())
+}
+
+// Reads the rules of the repository containing the given watched path which apply to it
+// from outside, in the order of precedence. The global excludes file applies to the
+// watched path itself if it isn't in a repository.
+fn read_repo_excludes(root: &Path) -> Vec<Gitignore> {
+  let repo = root
+    .ancestors()
+    .find(|dir| dir.join(".git").exists())
+    .unwrap_or(root);
+
+  let mut excludes: Vec<Gitignore> = root
+    .ancestors()
+    .skip(1)
+    .take_while(|dir| dir.starts_with(repo))
+    .filter_map(read_gitignore)
+    .collect();
+
+  let mut builder = GitignoreBuilder::new(repo);
+  builder.add(repo.join(".git").join("info").join("exclude"));
+  excludes.extend(builder.build().ok());
+  excludes.push(GitignoreBuilder::new(repo).build_global().0);
+  excludes.retain(|gitignore| !gitignore.is_empty());
+  excludes
+}
+
+// Loads the rules of the given watched path, i.e. the rules of its repository and the
+// .gitignore files in it and its sub-directories
+fn load_gitignores(rules: &mut GitignoreMatchers, root: &Path) {
+  rules
+    .excludes
+    .insert(root.to_path_buf(), read_repo_excludes(root));
+  load_dir_gitignores(rules, root, root);
+}
+
+// Loads the .gitignore files in the given directory under the watched path and its
+// sub-directories, skipping the ignored directories and the .git directory
+fn load_dir_gitignores(rules: &mut GitignoreMatchers, root: &Path, dir: &Path) {
+  rules.reload(dir);
+
+  let entries = match fs::read_dir(dir) {
+    Ok(entries) => entries,
+    Err(_) => return,
   };
+  for entry in entries.flatten() {
+    let path = entry.path();
+    if entry.file_type().is_ok_and(|file_type| file_type.is_dir())
+      && entry.file_name() != ".git"
+      && rules.matched(root, &path, true) != Some(true)
+    {
+      load_dir_gitignores(rules, root, &path);
+    }
+  }
+}
 
-  // Creates dynamic watcher with javascript callback as an event handler. If the use_polling
-  // option is true, creates poll watcher instance else recommended watcher.
-  let watcher: Box<dyn Watcher> = if options.use_polling {
-    Box::new(
-      PollWatcher::new(
-        move |ev| event_handler(ev),
-        Config::default().with_poll_interval(Duration::from_secs(options.poll_interval)),
-      )
-      .map_err(|e| Error::new(Status::GenericFailure, format!("{}", e)))?,
-    )
-  } else {
-    Box::new(
-      RecommendedWatcher::new(move |ev| event_handler(ev), Config::default())
-        .map_err(|e| Error::new(Status::GenericFailure, format!("{}", e)))?,
-    )
+// Checks whether the path under the watched path is ignored by the .gitignore rules. The
+// path is ignored if any of its parent directories is, since git doesn't include the paths
+// back once their directory is excluded. Whether the path itself is a directory is given
+// by the kind of its event if known, since it may have been removed already.
+fn is_gitignored(
+  rules: &GitignoreMatchers,
+  root: &Path,
+  path: &Path,
+  is_dir: Option<bool>,
+) -> bool {
+  let relative = match path.strip_prefix(root) {
+    Ok(relative) => relative,
+    Err(_) => return false,
   };
 
-  env.create_external(watcher, None)
+  let mut current = root.to_path_buf();
+  let mut components = relative.components().peekable();
+  while let Some(component) = components.next() {
+    current.push(component);
+    let is_dir = match components.peek() {
+      Some(_) => true,
+      None => is_dir.unwrap_or_else(|| current.is_dir()),
+    };
+    if rules.matched(root, &current, is_dir) == Some(true) {
+      return true;
+    }
+  }
+  false
 }
 
-/// This function takes in watcher instance and a path to be watched for events.
-#[napi]
-pub fn add(env: Env, ext: JsExternal, dir: JsString) -> Result<JsUndefined> {
-  let dir = dir.into_utf8()?;
-  let watcher = env.get_value_external::<Box<dyn Watcher>>(&ext)?;
+// Returns the nearest existing ancestor directory of the given path
+fn existing_ancestor(path: &Path) -> Option<PathBuf> {
+  path
+    .ancestors()
+    .skip(1)
+    .find(|ancestor| ancestor.is_dir())
+    .map(Path::to_path_buf)
+}
 
-  watcher
-    .watch(Path::new(dir.as_str()?), RecursiveMode::NonRecursive)
-    .map_err(|e| Error::new(Status::GenericFailure, format!("{}", e)))?;
-  env.get_undefined()
+// Watcher state shared between the watcher instance and the threads spawned by the
+// event handler to watch the paths discovered from fs events. The event handler itself
+// must not lock it, since the backend may wait on the event handler thread while
+// watching a path. The watcher is taken out on close. The event handler and backend
+// config are kept for switching the backend and recreating the watcher.
+struct WatcherState {
+  watcher: Option<Box<dyn Watcher + Send>>,
+  handler: Option<SharedHandler>,
+  config: Config,
+  backend: WatcherKind,
+  // Whether the watcher is the poll watcher, which is read by the event handler
+  polling: Arc<AtomicBool>,
+  // Whether the recommended watcher is backed by the shared watcher
+  shared: bool,
+  recursive_mode: RecursiveMode,
+  max_depth: Option<usize>,
+  paths: WatchedPaths,
+  // Additional paths watched under each of the paths, i.e. the resolved targets of the
+  // symlinked directories and the sub-directories watched up to the max depth
+  extra_paths: HashMap<PathBuf, Vec<PathBuf>>,
+  // Recursive mode of each of the paths, which may differ from the mode of this watcher
+  // if chosen when adding the path
+  modes: HashMap<PathBuf, RecursiveMode>,
+  link_targets: LinkTargets,
+  pending: PendingPaths,
+  // Paths watched recursively which contain unreadable directories, under which the
+  // directories are watched one by one skipping the unreadable ones
+  manual_paths: WatchedPaths,
+  path_ignores: PathGlobs,
+  // Ignore patterns read from the ignore file of each of the paths
+  file_ignores: PathGlobs,
+  gitignores: GitignoreRules,
+  // Include patterns of the paths added with glob patterns, along with the patterns
+  path_includes: PathGlobs,
+  globs: HashMap<PathBuf, Vec<Glob>>,
+  files: WatchedFiles,
+  file_types: FileTypes,
+  // Recursive mode each of the paths is added with without glob patterns, whose entries
+  // are delivered along with the ones matching the glob patterns added on the same path
+  plain_modes: HashMap<PathBuf, RecursiveMode>,
+  // Number of the watches used by each of the paths, which is bounded by max_watches if
+  // not zero
+  watch_counts: HashMap<PathBuf, usize>,
+  max_watches: usize,
 }
 
-/// This function invokes unwatch method on the specific path and removes that path
-/// from watching for fs events.
-#[napi]
-pub fn unwatch(env: Env, ext: JsExternal, dir: JsString) -> Result<JsUndefined> {
-  let dir = dir.into_utf8()?;
-  let watcher = env.get_value_external::<Box<dyn Watcher>>(&ext)?;
+impl WatcherState {
+  // Returns the underlying watcher or an error if the watcher is closed.
+  fn watcher(&mut self) -> Result<&mut Box<dyn Watcher + Send>> {
+    self.watcher.as_mut().ok_or_else(|| {
+      Error::new(
+        Status::GenericFailure,
+        String::from("Watcher is already closed"),
+      )
+    })
+  }
 
-  watcher
-    .unwatch(Path::new(dir.as_str()?))
-    .map_err(|e| Error::new(Status::GenericFailure, format!("{}", e)))?;
-  env.get_undefined()
+  // Replaces the watcher with a new poll watcher or recommended watcher invoking the
+  // same event handler.
+  fn set_backend(&mut self, polling: bool) -> Result<()> {
+    let handler = self.handler.as_ref().ok_or_else(|| {
+      Error::new(
+        Status::GenericFailure,
+        String::from("Event handler is not set"),
+      )
+    })?;
+
+    let (watcher, backend) = create_watcher(handler, self.config, polling, self.shared)?;
+    self.watcher = Some(watcher);
+    self.backend = backend;
+    self.polling.store(polling, Ordering::Relaxed);
+    Ok(())
+  }
+
+  // Updates the interval of the poll watcher. The watcher is configured in place if the
+  // backend supports it, and recreated with the interval otherwise, as the poll watcher
+  // of notify 5 can't be configured once created.
+  fn set_poll_interval(&mut self, poll_interval: Duration) -> Result<()> {
+    self.watcher()?;
+    if self.backend != WatcherKind::PollWatcher {
+      return Err(Error::new(
+        Status::GenericFailure,
+        format!(
+          "Poll interval can't be set for the {} backend",
+          backend_name(self.backend)
+        ),
+      ));
+    }
+
+    self.config = self.config.with_poll_interval(poll_interval);
+    let config = self.config;
+    if self.watcher()?.configure(config).map_err(watch_error)? {
+      return Ok(());
+    }
+    self.restart()
+  }
+
+  // Recreates the watcher with the same backend and watches all the paths along with
+  // the additional paths under them again.
+  fn restart(&mut self) -> Result<()> {
+    self.watcher()?;
+    self.watcher.take();
+    self.set_backend(self.backend == WatcherKind::PollWatcher)?;
+
+    let roots: Vec<PathBuf> = self.paths.read().unwrap().iter().cloned().collect();
+    let targets: Vec<PathBuf> = self.extra_paths.values().flatten().cloned().collect();
+    for path in roots.iter().chain(targets.iter()) {
+      if !self.is_watched_file(path) {
+        let _ = self.watch(path);
+      }
+    }
+    let parents: Vec<PathBuf> = self
+      .files
+      .read()
+      .unwrap()
+      .iter()
+      .filter(|(_, watch)| watch.dedicated)
+      .map(|(parent, _)| parent.clone())
+      .collect();
+    for parent in parents {
+      let _ = self.watch_file_parent(&parent);
+    }
+    let ancestors: HashSet<PathBuf> = self.pending_ancestors();
+    for ancestor in ancestors {
+      self.watch_ancestor(&ancestor);
+    }
+    Ok(())
+  }
+
+  // Returns the ancestors watched for the pending paths
+  fn pending_ancestors(&self) -> HashSet<PathBuf> {
+    let pending = self.pending.lock().unwrap();
+    pending
+      .values()
+      .map(|watch| watch.ancestor.clone())
+      .collect()
+  }
+
+  // Watches the ancestor of the pending paths non-recursively, unless it is a watched
+  // path already
+  fn watch_ancestor(&mut self, ancestor: &Path) {
+    if !self.paths.read().unwrap().contains(ancestor) {
+      if let Ok(watcher) = self.watcher() {
+        let _ = watcher.watch(&extended_path(ancestor), RecursiveMode::NonRecursive);
+      }
+    }
+  }
+
+  // Unwatches the ancestor no longer needed for the pending paths, unless it is a watched
+  // path
+  fn unwatch_ancestor(&mut self, ancestor: &Path) {
+    let is_file_parent = self.files.read().unwrap().contains_key(ancestor);
+    if !is_file_parent && !self.paths.read().unwrap().contains(ancestor) {
+      if let Ok(watcher) = self.watcher() {
+        let _ = watcher.unwatch(&extended_path(ancestor));
+      }
+    }
+  }
+
+  // Stops watching the watched path which has been removed, and makes it pending if
+  // requested. Returns false if it is not watched or exists again. The watched file is
+  // kept watched through its parent until it is recreated.
+  fn remove_gone_root(&mut self, root: &Path, make_pending: bool) -> bool {
+    if !self.paths.read().unwrap().contains(root) || root.exists() || self.is_watched_file(root) {
+      return false;
+    }
+
+    let recursive_mode = self.mode_of(root);
+    let _ = self.unwatch_root(root);
+    if let Some(ancestor) = existing_ancestor(root).filter(|_| make_pending) {
+      if !self.pending_ancestors().contains(&ancestor) {
+        self.watch_ancestor(&ancestor);
+      }
+      self.pending.lock().unwrap().insert(
+        root.to_path_buf(),
+        PendingWatch {
+          ancestor,
+          recursive_mode,
+        },
+      );
+    }
+    true
+  }
+
+  // Watches the pending paths which have appeared as the watched paths, and moves the
+  // watches of the others to their nearest existing ancestors. Returns the add events of
+  // the paths which appeared along with their parents, other than the given path whose
+  // own event is delivered by the backend, and the directories under them skipped as they
+  // can't be watched along with their errors.
+  fn resolve_pending(&mut self, trigger: &Path) -> (Vec<FsEvent>, Vec<(PathBuf, Error)>) {
+    let before = self.pending_ancestors();
+    let timestamp = system_time_nanos(SystemTime::now());
+    let mut events = Vec::new();
+    let mut skipped = Vec::new();
+
+    let appeared: Vec<(PathBuf, PendingWatch)> = {
+      let mut pending = self.pending.lock().unwrap();
+      let targets: Vec<PathBuf> = pending
+        .keys()
+        .filter(|target| target.exists())
+        .cloned()
+        .collect();
+      for (target, watch) in pending.iter_mut() {
+        if let Some(ancestor) = existing_ancestor(target) {
+          watch.ancestor = ancestor;
+        }
+      }
+      targets
+        .into_iter()
+        .filter_map(|target| pending.remove(&target).map(|watch| (target, watch)))
+        .collect()
+    };
+
+    for (target, watch) in appeared {
+      self.modes.insert(target.clone(), watch.recursive_mode);
+      if self.watch_root(&target, &target).is_err() {
+        self.modes.remove(&target);
+        continue;
+      }
+      skipped.extend(self.watch_subdirs(&target, &target, false));
+      if target != trigger {
+        let kind = if target.is_dir() { "addDir" } else { "add" };
+        events.push(FsEvent::new(String::from(kind), target, timestamp));
+      }
+    }
+
+    let after = self.pending_ancestors();
+    for ancestor in before.difference(&after) {
+      self.unwatch_ancestor(ancestor);
+    }
+    for ancestor in after.difference(&before) {
+      self.watch_ancestor(ancestor);
+    }
+    (events, skipped)
+  }
+
+  // Watches the given path with the recursive mode of the watched path it is located
+  // under. The directories are watched non-recursively one by one if the depth is
+  // limited.
+  fn watch(&mut self, path: &Path) -> Result<()> {
+    let recursive_mode = match self.root_of(path) {
+      Some(root) => self.watch_mode(&root),
+      None if self.max_depth.is_some() => RecursiveMode::NonRecursive,
+      None => self.recursive_mode,
+    };
+
+    self
+      .watcher()?
+      .watch(&extended_path(path), recursive_mode)
+      .map_err(watch_error)
+  }
+
+  // Counts the watches needed for watching the given path in the given mode, i.e. one for
+  // each of the directories under it within the max depth if recursive along with the
+  // targets of the symlinks followed, and fails if they would exceed the max_watches limit
+  // along with the watches used by the other paths and the pending paths.
+  fn check_watch_limit(
+    &mut self,
+    path: &Path,
+    root: &Path,
+    recursive_mode: RecursiveMode,
+    follow_symlinks: bool,
+  ) -> Result<usize> {
+    if self.max_watches == 0 {
+      return Ok(0);
+    }
+
+    // Ancestors of the pending paths and the parents of the watched files are watched
+    // outside of the watched paths
+    let mut outside: HashSet<PathBuf> = self
+      .pending_ancestors()
+      .into_iter()
+      .chain(
+        self
+          .files
+          .read()
+          .unwrap()
+          .iter()
+          .filter(|(_, watch)| watch.dedicated)
+          .map(|(parent, _)| parent.clone()),
+      )
+      .collect();
+    // Forget the paths unwatched already. The watches of the path itself are replaced.
+    let paths = self.paths.read().unwrap();
+    outside.retain(|dir| !paths.contains(dir));
+    self
+      .watch_counts
+      .retain(|watched, _| paths.contains(watched));
+    drop(paths);
+    let in_use: usize = self
+      .watch_counts
+      .iter()
+      .filter(|(watched, _)| *watched != root)
+      .map(|(_, count)| count)
+      .sum::<usize>()
+      + outside.len();
+    let available = self.max_watches.saturating_sub(in_use);
+
+    let needed = match recursive_mode {
+      // Directories one level above the max depth are the deepest ones watched, the same
+      // way as watch_subdirs
+      RecursiveMode::Recursive => WalkDir::new(path)
+        .follow_links(follow_symlinks)
+        .max_depth(
+          self
+            .max_depth
+            .map_or(usize::MAX, |depth| depth.saturating_sub(1)),
+        )
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_dir())
+        .take(available + 1)
+        .count()
+        .max(1),
+      RecursiveMode::NonRecursive if follow_symlinks => {
+        let targets = find_symlinks(path, 1)
+          .iter()
+          .filter(|link| link.is_dir())
+          .count();
+        1 + targets
+      }
+      RecursiveMode::NonRecursive => 1,
+    };
+    if needed > available {
+      return Err(Error::new(
+        Status::GenericFailure,
+        format!(
+          "Watching {} would exceed the maxWatches limit of {} with {} watches in use. \
+           Increase the maxWatches option along with the OS limit such as \
+           fs.inotify.max_user_watches, or narrow the watch by watching fewer directories \
+           or lowering the maxDepth option.",
+          root.display(),
+          self.max_watches,
+          in_use
+        ),
+      ));
+    }
+    Ok(needed)
+  }
+
+  // Watches the given path as a watched path with its recursive mode and keeps track of
+  // it in the normalized form.
+  fn watch_root(&mut self, path: &Path, root: &Path) -> Result<()> {
+    let recursive_mode = self.watch_mode(root);
+    let watcher = self.watcher()?;
+
+    match watcher.watch(&extended_path(path), recursive_mode) {
+      // Unreadable directory fails the recursive watch of the readable path, in which case
+      // the directories under it are watched one by one by watch_subdirs
+      Err(e)
+        if recursive_mode == RecursiveMode::Recursive
+          && is_permission_denied(&e)
+          && fs::read_dir(path).is_ok() =>
+      {
+        let _ = watcher.unwatch(&extended_path(path));
+        watcher
+          .watch(&extended_path(path), RecursiveMode::NonRecursive)
+          .map_err(watch_error)?;
+        self
+          .manual_paths
+          .write()
+          .unwrap()
+          .insert(root.to_path_buf());
+      }
+      result => result.map_err(watch_error)?,
+    }
+    self.paths.write().unwrap().insert(root.to_path_buf());
+    Ok(())
+  }
+
+  // Returns the recursive mode of the given watched path
+  fn mode_of(&self, root: &Path) -> RecursiveMode {
+    self.modes.get(root).copied().unwrap_or(self.recursive_mode)
+  }
+
+  // Returns the recursive mode with which the paths under the given watched path are
+  // watched. The directories are watched one by one if the depth is limited or some of
+  // them are unreadable.
+  fn watch_mode(&self, root: &Path) -> RecursiveMode {
+    if self.max_depth.is_some() || self.manual_paths.read().unwrap().contains(root) {
+      RecursiveMode::NonRecursive
+    } else {
+      self.mode_of(root)
+    }
+  }
+
+  // Watched paths in their sorted order
+  fn watched_paths(&self) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = self.paths.read().unwrap().iter().cloned().collect();
+    paths.sort();
+    paths
+  }
+
+  // Returns the watched path under which the given path is located. The closest one is
+  // returned if the watched paths are nested.
+  fn root_of(&self, path: &Path) -> Option<PathBuf> {
+    let path = normalize_path(path);
+    let paths = self.paths.read().unwrap();
+
+    closest_root(&paths, &path)
+      .or_else(|| {
+        self
+          .extra_paths
+          .iter()
+          .find(|(_, targets)| targets.iter().any(|target| path.starts_with(target)))
+          .map(|(root, _)| root)
+      })
+      .cloned()
+  }
+
+  // Sets the glob patterns the watched path is added with, which are combined into the
+  // include patterns of the path
+  fn set_globs(&mut self, root: &Path, globs: Option<Vec<Glob>>) -> Result<()> {
+    match globs {
+      Some(globs) => self.globs.insert(root.to_path_buf(), globs),
+      None => self.globs.remove(root),
+    };
+    self.update_include(root)
+  }
+
+  // Combines the glob patterns of the watched path into its include patterns, along with
+  // the patterns of its entries if it is added without glob patterns as well. The path
+  // added recursively without glob patterns includes everything under it.
+  fn update_include(&mut self, root: &Path) -> Result<()> {
+    let mut path_includes = self.path_includes.write().unwrap();
+    let (globs, plain_mode) = match (self.globs.get(root), self.plain_modes.get(root)) {
+      (Some(globs), plain_mode) if plain_mode != Some(&RecursiveMode::Recursive) => {
+        (globs, plain_mode)
+      }
+      _ => {
+        path_includes.remove(root);
+        return Ok(());
+      }
+    };
+
+    let mut builder = GlobSetBuilder::new();
+    for glob in globs {
+      builder.add(glob.clone());
+    }
+    if plain_mode.is_some() {
+      let escaped = globset::escape(&root.to_string_lossy());
+      for pattern in [
+        escaped.clone(),
+        Path::new(&escaped).join("*").to_string_lossy().into(),
+      ] {
+        builder.add(
+          GlobBuilder::new(&pattern)
+            .literal_separator(true)
+            .build()
+            .map_err(|e| Error::new(Status::InvalidArg, format!("{}", e)))?,
+        );
+      }
+    }
+    let include = builder
+      .build()
+      .map_err(|e| Error::new(Status::InvalidArg, format!("{}", e)))?;
+    path_includes.insert(root.to_path_buf(), include);
+    Ok(())
+  }
+
+  // Unwatches the given watched path along with the additional paths watched under it.
+  // Pending path is no longer waited for, unwatching its ancestor unless it is needed for
+  // the other pending paths.
+  fn unwatch_root(&mut self, root: &Path) -> Result<()> {
+    let before = self.pending_ancestors();
+    if self.pending.lock().unwrap().remove(root).is_some() {
+      for ancestor in before.difference(&self.pending_ancestors()) {
+        self.unwatch_ancestor(ancestor);
+      }
+      return Ok(());
+    }
+    if self.unwatch_file(root) {
+      return Ok(());
+    }
+
+    let result = self
+      .watcher()?
+      .unwatch(&extended_path(root))
+      .map_err(|e| Error::new(Status::GenericFailure, format!("{}", e)));
+
+    self.paths.write().unwrap().remove(root);
+    self.modes.remove(root);
+    self.manual_paths.write().unwrap().remove(root);
+    self.path_ignores.write().unwrap().remove(root);
+    self.file_ignores.write().unwrap().remove(root);
+    self.plain_modes.remove(root);
+    let _ = self.set_globs(root, None);
+    self.gitignores.write().unwrap().remove_under(root);
+    for target in self.extra_paths.remove(root).unwrap_or_default() {
+      let _ = self.watcher()?.unwatch(&extended_path(&target));
+    }
+    self
+      .link_targets
+      .lock()
+      .unwrap()
+      .retain(|link, _| !link.starts_with(root));
+    self.forget_file_types(root);
+    self.sync_file_parents();
+    result
+  }
+
+  // Unwatches all the watched paths along with the additional paths watched under them,
+  // attempting all of them and reporting the paths failed to be unwatched together
+  fn unwatch_all(&mut self) -> Result<()> {
+    self.watcher()?;
+
+    let mut roots: Vec<PathBuf> = self.paths.write().unwrap().drain().collect();
+    roots.sort();
+    // Watched files are unwatched through their parents
+    let files = std::mem::take(&mut *self.files.write().unwrap());
+    self.file_types.lock().unwrap().clear();
+    roots.retain(|root| {
+      root
+        .parent()
+        .and_then(|parent| files.get(parent))
+        .is_none_or(|watch| !watch.files.contains(root))
+    });
+    let extra_paths = std::mem::take(&mut self.extra_paths);
+    self.modes.clear();
+    self.manual_paths.write().unwrap().clear();
+    self.path_ignores.write().unwrap().clear();
+    self.file_ignores.write().unwrap().clear();
+    self.path_includes.write().unwrap().clear();
+    self.globs.clear();
+    self.plain_modes.clear();
+    self.gitignores.write().unwrap().clear();
+    self.link_targets.lock().unwrap().clear();
+
+    let mut failures = Vec::new();
+    for root in roots {
+      if let Err(e) = self.watcher()?.unwatch(&extended_path(&root)) {
+        failures.push(format!("{}: {}", root.display(), e));
+      }
+      for target in extra_paths.get(&root).into_iter().flatten() {
+        let _ = self.watcher()?.unwatch(&extended_path(target));
+      }
+    }
+    for (parent, watch) in files {
+      if watch.dedicated {
+        let _ = self.watcher()?.unwatch(&extended_path(&parent));
+      }
+    }
+    // Pending paths are no longer waited for
+    for ancestor in self.pending_ancestors() {
+      self.unwatch_ancestor(&ancestor);
+    }
+    self.pending.lock().unwrap().clear();
+
+    if !failures.is_empty() {
+      return Err(Error::new(
+        Status::GenericFailure,
+        format!("Failed to unwatch paths: {}", failures.join(", ")),
+      ));
+    }
+    Ok(())
+  }
+
+  // Drops the cached file types under the unwatched path, unless it is still located
+  // under another watched path
+  fn forget_file_types(&self, root: &Path) {
+    let still_watched = closest_root(&self.paths.read().unwrap(), root).is_some();
+    if !still_watched {
+      self.file_types.lock().unwrap().remove_under(root);
+    }
+  }
+
+  // Checks whether the given path is watched as a single file
+  fn is_watched_file(&self, path: &Path) -> bool {
+    let files = self.files.read().unwrap();
+    path
+      .parent()
+      .and_then(|parent| files.get(parent))
+      .is_some_and(|watch| watch.files.contains(path))
+  }
+
+  // Checks whether the entries of the given directory are watched regardless of the
+  // watched files in it, i.e. the directory is a watched path, an additional path watched
+  // under one or located under a path watched recursively
+  fn is_covered(&self, dir: &Path) -> bool {
+    if self.paths.read().unwrap().contains(dir)
+      || self.extra_paths.values().flatten().any(|path| path == dir)
+    {
+      return true;
+    }
+    self
+      .root_of(dir)
+      .is_some_and(|root| self.watch_mode(&root) == RecursiveMode::Recursive)
+  }
+
+  // Watches the parent directory of the watched files non-recursively
+  fn watch_file_parent(&mut self, parent: &Path) -> Result<()> {
+    self
+      .watcher()?
+      .watch(&extended_path(parent), RecursiveMode::NonRecursive)
+      .map_err(watch_error)
+  }
+
+  // Stops watching the given watched file, unwatching its parent directory once it is
+  // no longer needed for the other files. Returns false if the file is not watched.
+  fn unwatch_file(&mut self, file: &Path) -> bool {
+    let parent = match file.parent() {
+      Some(parent) => parent,
+      None => return false,
+    };
+    let mut files = self.files.write().unwrap();
+    if !files
+      .get_mut(parent)
+      .is_some_and(|watch| watch.files.remove(file))
+    {
+      return false;
+    }
+    let dedicated = match files.get(parent) {
+      Some(watch) if watch.files.is_empty() => files.remove(parent).is_some_and(|w| w.dedicated),
+      _ => false,
+    };
+    drop(files);
+
+    self.paths.write().unwrap().remove(file);
+    if dedicated && !self.pending_ancestors().contains(parent) {
+      if let Ok(watcher) = self.watcher() {
+        let _ = watcher.unwatch(&extended_path(parent));
+      }
+    }
+    true
+  }
+
+  // Keeps the parent directories of the watched files watched as the other paths are
+  // added and unwatched. The parent no longer covered by the watched paths is watched
+  // for the files only, and the one covered again delivers all of its entries.
+  fn sync_file_parents(&mut self) {
+    let parents: Vec<(PathBuf, bool)> = self
+      .files
+      .read()
+      .unwrap()
+      .iter()
+      .map(|(parent, watch)| (parent.clone(), watch.dedicated))
+      .collect();
+
+    for (parent, dedicated) in parents {
+      let covered = self.is_covered(&parent);
+      if !covered && !dedicated && self.watch_file_parent(&parent).is_err() {
+        continue;
+      }
+      if let Some(watch) = self.files.write().unwrap().get_mut(&parent) {
+        watch.dedicated = !covered;
+      }
+    }
+  }
+
+  // Watches the targets of the given symlinks which are directories, along with the
+  // targets of the symlinks found under them if watching recursively. The targets are
+  // tracked under the given watched path, so that they are unwatched along with it.
+  // Each directory is watched only once, which guards against symlink loops. Symlinks
+  // beyond MAX_LINK_TARGETS are not followed, which are returned along with the errors.
+  fn watch_symlinks(&mut self, root: &Path, mut links: Vec<PathBuf>) -> Vec<(PathBuf, Error)> {
+    let mut skipped = Vec::new();
+    let mut visited: HashSet<PathBuf> = self
+      .paths
+      .read()
+      .unwrap()
+      .iter()
+      .chain(self.extra_paths.values().flatten())
+      .filter_map(|path| fs::canonicalize(path).ok())
+      .collect();
+
+    while let Some(link) = links.pop() {
+      let target = match fs::canonicalize(&link) {
+        Ok(target) if target.is_dir() => target,
+        _ => continue,
+      };
+      if let Ok(link_target) = fs::read_link(&link) {
+        let mut link_targets = self.link_targets.lock().unwrap();
+        if link_targets.len() >= MAX_LINK_TARGETS && !link_targets.contains_key(&link) {
+          skipped.push((
+            link,
+            Error::new(
+              Status::GenericFailure,
+              format!(
+                "Symlink is not followed as {} symlinks are followed already",
+                MAX_LINK_TARGETS
+              ),
+            ),
+          ));
+          continue;
+        }
+        link_targets.insert(link.clone(), link_target);
+      }
+
+      if !visited.insert(target.clone()) || self.watch(&target).is_err() {
+        continue;
+      }
+
+      if self.mode_of(root) == RecursiveMode::Recursive {
+        links.extend(find_symlinks(&target, usize::MAX));
+      }
+      self
+        .extra_paths
+        .entry(root.to_path_buf())
+        .or_default()
+        .push(target);
+    }
+    skipped
+  }
+
+  // Watches the sub-directories of the given directory, and optionally the directory
+  // itself, which are located within the max depth below the given watched path. The
+  // directories are tracked under the watched path, so that they are unwatched along
+  // with it. The directories under the paths containing unreadable directories are
+  // watched at any depth. Returns the directories skipped as they can't be watched, along
+  // with their errors.
+  fn watch_subdirs(
+    &mut self,
+    root: &Path,
+    dir: &Path,
+    include_self: bool,
+  ) -> Vec<(PathBuf, Error)> {
+    let max_depth = match self.max_depth {
+      Some(max_depth) if self.mode_of(root) == RecursiveMode::Recursive => max_depth,
+      None if self.manual_paths.read().unwrap().contains(root) => usize::MAX,
+      _ => return Vec::new(),
+    };
+
+    // Directories one level above the max depth are the deepest ones to be watched
+    // for reporting the entries at max depth.
+    let limit = max_depth.saturating_sub(1);
+    let depth = normalize_path(dir)
+      .strip_prefix(root)
+      .map(|path| path.components().count())
+      .unwrap_or_default();
+    if depth > limit {
+      return Vec::new();
+    }
+
+    let dirs: Vec<PathBuf> = WalkDir::new(dir)
+      .min_depth(if include_self { 0 } else { 1 })
+      .max_depth(limit - depth)
+      .into_iter()
+      .filter_map(|entry| entry.ok())
+      .filter(|entry| entry.file_type().is_dir())
+      .map(|entry| entry.into_path())
+      .collect();
+
+    let mut skipped = Vec::new();
+    for dir in dirs {
+      match self.watch(&dir) {
+        Ok(_) => self
+          .extra_paths
+          .entry(root.to_path_buf())
+          .or_default()
+          .push(dir),
+        Err(e) => skipped.push((dir, e)),
+      }
+    }
+    skipped
+  }
+
+  // Watches the given watched path and the additional paths under it again, which
+  // recovers them from the stale file handles on network file systems.
+  fn rewatch(&mut self, root: &Path) {
+    // Watched file is watched through its parent, unless the parent is watched otherwise
+    if self.is_watched_file(root) {
+      let parent = root.parent().filter(|parent| {
+        let files = self.files.read().unwrap();
+        files.get(*parent).is_some_and(|watch| watch.dedicated)
+      });
+      if let Some(parent) = parent {
+        if let Ok(watcher) = self.watcher() {
+          let _ = watcher.unwatch(&extended_path(parent));
+        }
+        let _ = self.watch_file_parent(parent);
+      }
+      return;
+    }
+
+    let targets = self.extra_paths.get(root).cloned().unwrap_or_default();
+
+    for path in std::iter::once(root.to_path_buf()).chain(targets) {
+      if let Ok(watcher) = self.watcher() {
+        let _ = watcher.unwatch(&extended_path(&path));
+      }
+      let _ = self.watch(&path);
+    }
+  }
+}
+
+// Returns the watched path under which the given normalized path is located, which is the
+// closest one if the watched paths are nested
+fn closest_root<'a>(roots: &'a HashSet<PathBuf>, path: &Path) -> Option<&'a PathBuf> {
+  roots
+    .iter()
+    .filter(|root| path.starts_with(root))
+    .max_by_key(|root| root.components().count())
+}
+
+// Returns the symlinks found under the given directory up to the given depth
+fn find_symlinks(dir: &Path, max_depth: usize) -> Vec<PathBuf> {
+  WalkDir::new(dir)
+    .min_depth(1)
+    .max_depth(max_depth)
+    .into_iter()
+    .filter_map(|entry| entry.ok())
+    .filter(|entry| entry.path_is_symlink())
+    .map(|entry| entry.into_path())
+    .collect()
+}
+
+// Watcher instance stored in the JsExternal. The paused flag is shared with the event
+// handler and the dispatcher is owned by the event handler. Cloning it creates another
+// handle of the same watcher instance.
+#[derive(Clone)]
+struct FsWatcher {
+  state: Arc<Mutex<WatcherState>>,
+  paused: Arc<AtomicBool>,
+  dispatcher: Weak<Dispatcher>,
+  file_types: FileTypes,
+  stats: Arc<EventStats>,
+  auto_detect_network: bool,
+  network_probe: NetworkProbe,
+  emit_initial: bool,
+  follow_symlinks: bool,
+  watch_pending: bool,
+  watcherignore: bool,
+  respect_gitignore: bool,
+  absolute_paths: bool,
+  snapshot: ScanSnapshot,
+  prefixes: PathPrefixes,
+  patterns: FilterPatterns,
+}
+
+impl FsWatcher {
+  fn state(&self) -> MutexGuard<'_, WatcherState> {
+    self.state.lock().unwrap()
+  }
+
+  // Subscribes to the delivered events through a channel of the given capacity, which is
+  // disconnected once the watcher is closed
+  fn subscribe(&self, capacity: usize) -> Result<mpsc::Receiver<FsEvent>> {
+    self.state().watcher()?;
+    let dispatcher = self
+      .dispatcher
+      .upgrade()
+      .ok_or_else(|| Error::new(Status::GenericFailure, String::from("Watcher is closed")))?;
+    let (sender, receiver) = mpsc::sync_channel(capacity.max(1));
+    dispatcher.streams.lock().unwrap().push(EventStream {
+      sender,
+      overflowed: false,
+    });
+    Ok(receiver)
+  }
+
+  // Watches the given path with the given recursive mode, or the recursive mode of this
+  // watcher if not given, delivering all the events under it regardless of the glob
+  // patterns added on the same path. The path is kept watched recursively for the glob
+  // patterns spanning its sub-directories.
+  fn add_path(&mut self, path: &Path, recursive_mode: Option<RecursiveMode>) -> Result<()> {
+    let root = normalize_path(path);
+    let (watch_mode, prev_mode) = {
+      let mut state = self.state();
+      let plain_mode = recursive_mode.unwrap_or(state.recursive_mode);
+      let watch_mode = if state.globs.contains_key(&root)
+        && state.modes.get(&root) == Some(&RecursiveMode::Recursive)
+      {
+        RecursiveMode::Recursive
+      } else {
+        plain_mode
+      };
+      let prev_mode = state.plain_modes.insert(root.clone(), plain_mode);
+      state.update_include(&root)?;
+      (watch_mode, prev_mode)
+    };
+
+    let result = self.watch_path(path, Some(watch_mode));
+    if result.is_err() {
+      let mut state = self.state();
+      match prev_mode {
+        Some(prev_mode) => state.plain_modes.insert(root.clone(), prev_mode),
+        None => state.plain_modes.remove(&root),
+      };
+      state.update_include(&root)?;
+    }
+    result
+  }
+
+  // Watches the given path with the given recursive mode, or the recursive mode of this
+  // watcher if not given, and keeps track of it.
+  fn watch_path(&mut self, path: &Path, recursive_mode: Option<RecursiveMode>) -> Result<()> {
+    // Path is watched in its absolute form so that the backend reports the event paths
+    // in that form
+    let absolute;
+    let path = if self.absolute_paths {
+      absolute = normalize_path(path);
+      absolute.as_path()
+    } else {
+      path
+    };
+
+    self.detect_backend(path)?;
+    let mut state = self.state();
+
+    let root = normalize_path(path);
+    let recursive_mode = recursive_mode.unwrap_or(state.recursive_mode);
+
+    // Path which doesn't exist yet is watched through its nearest existing ancestor
+    if self.watch_pending && !root.exists() {
+      let ancestor = existing_ancestor(&root).ok_or_else(|| {
+        Error::new(
+          Status::GenericFailure,
+          format!("No existing ancestor of {}", root.display()),
+        )
+      })?;
+      state.watcher()?;
+      if !state.pending_ancestors().contains(&ancestor) {
+        state.watch_ancestor(&ancestor);
+      }
+      state.pending.lock().unwrap().insert(
+        root,
+        PendingWatch {
+          ancestor,
+          recursive_mode,
+        },
+      );
+      return Ok(());
+    }
+
+    // Patterns of the ignore file are loaded before watching so that the initial events
+    // are filtered as well
+    if self.watcherignore {
+      let mut file_ignores = state.file_ignores.write().unwrap();
+      match read_ignore_file(&root)? {
+        Some(ignore) => file_ignores.insert(root.clone(), ignore),
+        None => file_ignores.remove(&root),
+      };
+    }
+    if self.respect_gitignore {
+      load_gitignores(&mut state.gitignores.write().unwrap(), &root);
+    }
+
+    let watches = state.check_watch_limit(path, &root, recursive_mode, self.follow_symlinks)?;
+    let prev_mode = state.modes.insert(root.clone(), recursive_mode);
+    if let Err(e) = state.watch_root(path, &root) {
+      // Restore the mode of the path if it was already watched
+      match prev_mode {
+        Some(prev_mode) => state.modes.insert(root, prev_mode),
+        None => state.modes.remove(&root),
+      };
+      return Err(e);
+    }
+    if watches > 0 {
+      state.watch_counts.insert(root.clone(), watches);
+    }
+
+    let mut skipped = state.watch_subdirs(&root, path, false);
+
+    // Path whose modifications can't be traced is reported the same way
+    if let Some(tracer) = self.dispatcher.upgrade().and_then(|d| d.pid_tracer.clone()) {
+      if let Err(e) = tracer.mark(&root) {
+        skipped.push((
+          root.clone(),
+          Error::new(Status::GenericFailure, format!("Failed to trace: {}", e)),
+        ));
+      }
+    }
+
+    // Depth of the entries being reported below this path
+    let max_depth = match recursive_mode {
+      RecursiveMode::Recursive => state.max_depth.unwrap_or(usize::MAX),
+      RecursiveMode::NonRecursive => 1,
+    };
+
+    if self.follow_symlinks {
+      let links = find_symlinks(path, max_depth);
+      skipped.extend(state.watch_symlinks(&root, links));
+    }
+    if state.backend == WatcherKind::PollWatcher {
+      let mut snapshot = self.snapshot.lock().unwrap();
+      snapshot
+        .get_or_insert_with(HashMap::new)
+        .extend(scan_entries(path, max_depth));
+    }
+    state.sync_file_parents();
+    drop(state);
+
+    // Directories skipped as they can't be watched are reported without failing the path
+    if let Some(dispatcher) = self.dispatcher.upgrade() {
+      for (dir, e) in skipped {
+        dispatcher.emit_watch_error(dir, e);
+      }
+    }
+
+    if self.emit_initial {
+      self.emit_initial_events(path, max_depth);
+    } else {
+      self.cache_file_types(path, max_depth);
+    }
+    Ok(())
+  }
+
+  // Watches the given path the same way as add_path, with the ignore patterns applied to
+  // the events under it. The patterns are set before watching so that the initial events
+  // are filtered as well, and restored if the path fails to be watched.
+  fn add_path_with_ignore(
+    &mut self,
+    path: &str,
+    recursive_mode: RecursiveMode,
+    ignore: GlobSet,
+  ) -> Result<()> {
+    let root = normalize_path(Path::new(path));
+    let path_ignores = Arc::clone(&self.state().path_ignores);
+    let prev_ignore = if ignore.is_empty() {
+      path_ignores.write().unwrap().remove(&root)
+    } else {
+      path_ignores.write().unwrap().insert(root.clone(), ignore)
+    };
+
+    let result = self.add_path(Path::new(path), Some(recursive_mode));
+    if result.is_err() {
+      let mut path_ignores = path_ignores.write().unwrap();
+      match prev_ignore {
+        Some(prev_ignore) => path_ignores.insert(root, prev_ignore),
+        None => path_ignores.remove(&root),
+      };
+    }
+    result
+  }
+
+  // Watches the base directory of the glob pattern, i.e. its leading components without
+  // glob characters, delivering only the events of the paths matching the pattern. The
+  // base directory is watched recursively if the pattern spans sub-directories. The pattern
+  // without glob characters is watched as a path.
+  fn add_glob(&mut self, pattern: &Path, case_insensitive: bool) -> Result<()> {
+    let (base, rest) = split_glob(pattern);
+    if rest.as_os_str().is_empty() {
+      return self.add_path(pattern, None);
+    }
+
+    let root = normalize_path(&base);
+    let glob = GlobBuilder::new(&root.join(&rest).to_string_lossy())
+      .literal_separator(true)
+      .case_insensitive(case_insensitive)
+      .build()
+      .map_err(|e| {
+        Error::new(
+          Status::InvalidArg,
+          format!("Invalid glob pattern '{}': {}", pattern.display(), e),
+        )
+      })?;
+    let spans_dirs = rest.components().count() > 1 || rest.to_string_lossy().contains("**");
+    self.add_path_with_glob(&base, glob, spans_dirs)
+  }
+
+  // Watches the single file through its parent directory, delivering only the events of
+  // the file itself from the parent unless the parent is watched otherwise. This keeps
+  // the file watched across the atomic saves which replace it, and works the same on the
+  // backends which can only watch directories. The file may not exist yet, in which case
+  // its add event is delivered once it is created. The file is tracked as a watched path.
+  fn add_file(&mut self, path: &Path) -> Result<()> {
+    let file = normalize_path(path);
+    if file.is_dir() {
+      return Err(Error::new(
+        Status::InvalidArg,
+        format!("{} is a directory", file.display()),
+      ));
+    }
+    let parent = file.parent().map(Path::to_path_buf).ok_or_else(|| {
+      Error::new(
+        Status::InvalidArg,
+        format!("No parent directory of {}", file.display()),
+      )
+    })?;
+
+    self.detect_backend(&parent)?;
+
+    let mut state = self.state();
+    let is_watched = state.files.read().unwrap().contains_key(&parent);
+    if !is_watched && !state.is_covered(&parent) {
+      state.watch_file_parent(&parent)?;
+      state.files.write().unwrap().insert(
+        parent.clone(),
+        FileWatch {
+          files: HashSet::new(),
+          dedicated: true,
+        },
+      );
+    }
+    state
+      .files
+      .write()
+      .unwrap()
+      .entry(parent)
+      .or_default()
+      .files
+      .insert(file.clone());
+    state.paths.write().unwrap().insert(file.clone());
+    drop(state);
+
+    if self.emit_initial {
+      self.emit_initial_events(&file, 0);
+    } else if let Some(parent) = file.parent() {
+      self.cache_file_types(parent, 1);
+    }
+    Ok(())
+  }
+
+  // Watches the base directory delivering the events of the paths matching the glob along
+  // with the ones requested by the other globs of the directory or by adding it without
+  // globs. The directory is watched recursively if the glob spans the sub-directories or
+  // it is already watched recursively.
+  fn add_path_with_glob(&mut self, base: &Path, glob: Glob, spans_dirs: bool) -> Result<()> {
+    let root = normalize_path(base);
+
+    // Patterns are set before watching so that the initial events are filtered as well,
+    // and restored if the path fails to be watched
+    let (recursive_mode, prev_globs) = {
+      let mut state = self.state();
+      let recursive_mode =
+        if spans_dirs || state.modes.get(&root) == Some(&RecursiveMode::Recursive) {
+          RecursiveMode::Recursive
+        } else {
+          RecursiveMode::NonRecursive
+        };
+      let prev_globs = state.globs.get(&root).cloned();
+      let mut globs = prev_globs.clone().unwrap_or_default();
+      globs.push(glob);
+      state.set_globs(&root, Some(globs))?;
+      (recursive_mode, prev_globs)
+    };
+
+    let result = self.watch_path(base, Some(recursive_mode));
+    if result.is_err() {
+      self.state().set_globs(&root, prev_globs)?;
+    }
+    result
+  }
+
+  // Switches to the poll watcher if the given path is on a network file system, where
+  // the native backends don't receive the events of remote changes, or back to the
+  // recommended watcher otherwise. This is done only if no path is being watched yet.
+  fn detect_backend(&self, path: &Path) -> Result<()> {
+    let mut state = self.state();
+    if !self.auto_detect_network || !state.paths.read().unwrap().is_empty() {
+      return Ok(());
+    }
+
+    let polling = (self.network_probe)(path);
+    if polling == (state.backend == WatcherKind::PollWatcher) {
+      return Ok(());
+    }
+
+    state.watcher()?;
+    state.set_backend(polling)
+  }
+
+  // Watches all the given paths, and reports the paths failed to be watched together in
+  // a single error while the others remain watched. The ready event is emitted once if
+  // any of the paths is watched.
+  fn add_paths(&mut self, dirs: &[PathBuf]) -> Result<()> {
+    let failures: Vec<String> = dirs
+      .iter()
+      .filter_map(|dir| {
+        self
+          .add_path(dir, None)
+          .err()
+          .map(|e| format!("{}: {}", dir.display(), e.reason))
+      })
+      .collect();
+
+    if failures.len() < dirs.len() {
+      self.emit_ready();
+    }
+    if !failures.is_empty() {
+      return Err(Error::new(
+        Status::GenericFailure,
+        format!("Failed to watch paths: {}", failures.join(", ")),
+      ));
+    }
+    Ok(())
+  }
+
+  // Emits ready event with empty path, signalling that the paths are being watched and
+  // the initial events if enabled are emitted.
+  fn emit_ready(&self) {
+    if let Some(dispatcher) = self.dispatcher.upgrade() {
+      dispatcher.emit(FsEvent::new(
+        String::from("ready"),
+        PathBuf::new(),
+        system_time_nanos(SystemTime::now()),
+      ));
+    }
+  }
+
+  // Caches the file types of the existing entries of the given path up to the given depth,
+  // so that replacing a file which existed before the path is watched is reported as its
+  // modify event. The initial events cache them the same way if enabled.
+  fn cache_file_types(&self, path: &Path, max_depth: usize) {
+    let dispatcher = match self.dispatcher.upgrade() {
+      Some(dispatcher) => dispatcher,
+      None => return,
+    };
+
+    let entries: Vec<(PathBuf, bool)> = WalkDir::new(path)
+      .min_depth(1)
+      .max_depth(max_depth)
+      .into_iter()
+      .filter_entry(|entry| {
+        !dispatcher
+          .filter
+          .is_subtree_ignored(entry.path(), entry.file_type().is_dir())
+      })
+      .filter_map(|entry| entry.ok())
+      .take(MAX_FILE_TYPES)
+      .map(|entry| {
+        let is_dir = entry.file_type().is_dir();
+        (entry.into_path(), is_dir)
+      })
+      .collect();
+
+    let mut file_types = self.file_types.lock().unwrap();
+    for (path, is_dir) in entries {
+      cache_file_type(&mut file_types, &path, is_dir);
+    }
+  }
+
+  // Emits add events for the existing entries of the given path up to the given depth.
+  // Ignored directories are skipped entirely. The events carry the modification time
+  // of the entries as timestamp.
+  fn emit_initial_events(&self, path: &Path, max_depth: usize) {
+    let dispatcher = match self.dispatcher.upgrade() {
+      Some(dispatcher) => dispatcher,
+      None => return,
+    };
+
+    let entries = WalkDir::new(path)
+      .min_depth(if path.is_dir() { 1 } else { 0 })
+      .max_depth(max_depth)
+      .into_iter()
+      .filter_entry(|entry| {
+        !dispatcher
+          .filter
+          .is_subtree_ignored(entry.path(), entry.file_type().is_dir())
+      })
+      .filter_map(|entry| entry.ok());
+
+    for entry in entries {
+      self.stats.add(&self.stats.seen, 1);
+      let is_dir = entry.file_type().is_dir();
+      if !dispatcher
+        .filter
+        .is_path_allowed(entry.path(), Some(is_dir))
+      {
+        self.stats.add(&self.stats.suppressed, 1);
+        continue;
+      }
+
+      cache_file_type(&mut self.file_types.lock().unwrap(), entry.path(), is_dir);
+
+      let kind = if is_dir { "addDir" } else { "add" };
+      let ts = entry
+        .metadata()
+        .ok()
+        .and_then(|metadata| metadata.modified().ok())
+        .map(system_time_nanos)
+        .unwrap_or_else(|| system_time_nanos(SystemTime::now()));
+
+      dispatcher.emit(FsEvent::new(String::from(kind), entry.into_path(), ts));
+    }
+  }
+}
+
+// Closes the watcher by dropping it after delivering the held and batched events, which
+// are discarded instead if drop_pending is set, and clears the watched paths.
+fn close_watcher(state: &Mutex<WatcherState>, dispatcher: &Weak<Dispatcher>, drop_pending: bool) {
+  if let Some(dispatcher) = dispatcher.upgrade() {
+    if drop_pending {
+      dispatcher.discard();
+    } else {
+      dispatcher.drain();
+    }
+    // Iterators complete once they have read the events sent so far
+    dispatcher.streams.lock().unwrap().clear();
+  }
+  let mut state = state.lock().unwrap();
+  state.watcher.take();
+  state.paths.write().unwrap().clear();
+  state.extra_paths.clear();
+  state.modes.clear();
+  state.manual_paths.write().unwrap().clear();
+  state.path_ignores.write().unwrap().clear();
+  state.file_ignores.write().unwrap().clear();
+  state.path_includes.write().unwrap().clear();
+  state.globs.clear();
+  state.files.write().unwrap().clear();
+  state.file_types.lock().unwrap().clear();
+  state.plain_modes.clear();
+  state.gitignores.write().unwrap().clear();
+  state.pending.lock().unwrap().clear();
+}
+
+// Watchers registered with an id by watch_with_id, or with a token by watch_with_token
+// which is the id of the watcher, which are kept alive until closed
+static WATCHERS: LazyLock<Mutex<HashMap<u32, FsWatcher>>> = LazyLock::new(Default::default);
+static NEXT_WATCHER_ID: AtomicU32 = AtomicU32::new(1);
+
+// Caches the file type of the given path unless the cache is full
+fn cache_file_type(file_types: &mut FileTypeCache, path: &Path, is_dir: bool) {
+  if file_types.len() < MAX_FILE_TYPES || file_types.contains_key(path) {
+    file_types.insert(path, is_dir);
+  }
+}
+
+// Determines whether the path of the event of given kind is a directory. The file types
+// are cached as the paths are added, so that the removed paths which can't be checked
+// anymore are still classified. The cached entries are evicted on removal.
+fn resolve_is_dir(file_types: &mut FileTypeCache, kind: &EventKind, path: &Path) -> bool {
+  match kind {
+    EventKind::Create(_) | EventKind::Modify(ModifyKind::Name(RenameMode::To)) => {
+      // Directory reported by the backend is reliable even if it is already removed
+      let is_dir = matches!(kind, EventKind::Create(CreateKind::Folder)) || path.is_dir();
+      cache_file_type(file_types, path, is_dir);
+      is_dir
+    }
+    EventKind::Remove(_) | EventKind::Modify(ModifyKind::Name(RenameMode::From)) => {
+      let cached = file_types.remove(path);
+      let is_dir = match kind {
+        EventKind::Remove(RemoveKind::Folder) => true,
+        EventKind::Remove(RemoveKind::File) => false,
+        _ => cached.unwrap_or_else(|| path.is_dir()),
+      };
+      // Entries under the removed directory are gone as well
+      if is_dir {
+        file_types.remove_under(path);
+      }
+      is_dir
+    }
+    _ => file_types.get(path).unwrap_or_else(|| path.is_dir()),
+  }
+}
+
+// Returns the device and inode numbers of the file. The file index on windows is not
+// available from the metadata in stable rust, so it is not supported there.
+#[cfg(unix)]
+fn file_id(metadata: &fs::Metadata) -> Option<(u64, u64)> {
+  use std::os::unix::fs::MetadataExt;
+  Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_id(_metadata: &fs::Metadata) -> Option<(u64, u64)> {
+  None
+}
+
+// Canonicalizes the given path. The path which doesn't exist anymore is canonicalized
+// through its parent directory, or returned as is if the parent doesn't exist either.
+// The verbatim prefix is avoided on windows where possible.
+fn canonical_path(path: &Path) -> PathBuf {
+  if path.as_os_str().is_empty() {
+    return PathBuf::new();
+  }
+
+  dunce::canonicalize(path).unwrap_or_else(|_| match (path.parent(), path.file_name()) {
+    (Some(parent), Some(name)) => dunce::canonicalize(parent)
+      .map(|parent| parent.join(name))
+      .unwrap_or_else(|_| path.to_path_buf()),
+    _ => path.to_path_buf(),
+  })
+}
+
+// Returns the given path relative to the first of the base directories it is located
+// under, or the absolute path if it is not under any of them. The base directory itself
+// is returned as "."
+fn relative_path(path: &Path, bases: &[PathBuf]) -> PathBuf {
+  let absolute = normalize_path(path);
+
+  match bases
+    .iter()
+    .find_map(|base| absolute.strip_prefix(base).ok())
+  {
+    Some(relative) if relative.as_os_str().is_empty() => PathBuf::from("."),
+    Some(relative) => relative.to_path_buf(),
+    None => absolute,
+  }
+}
+
+// Converts the backslash separators of the given path into forward slashes. UNC paths
+// remain valid as //server/share, while the verbatim paths with \\?\ prefix are kept as
+// is since forward slashes are not separators in them.
+#[cfg(windows)]
+fn to_forward_slashes(path: &mut String) {
+  if !path.starts_with(r"\\?\") {
+    *path = path.replace('\\', "/");
+  }
+}
+
+#[cfg(not(windows))]
+fn to_forward_slashes(_path: &mut String) {}
+
+// Maximum length of the windows paths without the extended-length prefix
+#[cfg(windows)]
+const MAX_PATH: usize = 260;
+
+// Prefixes the given path with \\?\ on windows if it exceeds MAX_PATH, which lifts the
+// length limit. The watched paths and their events remain in the normal form otherwise.
+#[cfg(windows)]
+fn extended_path(path: &Path) -> PathBuf {
+  let absolute = normalize_path(path);
+  match absolute.to_str() {
+    Some(p) if p.len() >= MAX_PATH && !p.starts_with(r"\\?\") => match p.strip_prefix(r"\\") {
+      Some(unc) => PathBuf::from(format!(r"\\?\UNC\{}", unc)),
+      None => PathBuf::from(format!(r"\\?\{}", p)),
+    },
+    _ => path.to_path_buf(),
+  }
+}
+
+#[cfg(not(windows))]
+fn extended_path(path: &Path) -> PathBuf {
+  path.to_path_buf()
+}
+
+// Strips the extended-length prefix from the event path of the long path watched on
+// windows. The verbatim paths other than the drive and UNC paths are kept as is.
+#[cfg(windows)]
+fn strip_extended_prefix(path: &mut PathBuf) {
+  let stripped = path
+    .to_str()
+    .and_then(|p| match p.strip_prefix(r"\\?\UNC\") {
+      Some(unc) => Some(format!(r"\\{}", unc)),
+      None => p
+        .strip_prefix(r"\\?\")
+        .filter(|p| p.as_bytes().get(1) == Some(&b':'))
+        .map(String::from),
+    });
+  if let Some(stripped) = stripped {
+    *path = PathBuf::from(stripped);
+  }
+}
+
+#[cfg(not(windows))]
+fn strip_extended_prefix(_path: &mut PathBuf) {}
+
+// Returns the given system time in nanoseconds since epoch
+fn system_time_nanos(time: SystemTime) -> u128 {
+  time
+    .duration_since(UNIX_EPOCH)
+    .map(|duration| duration.as_nanos())
+    .unwrap_or_default()
+}
+
+// Compiles the given glob patterns into a single glob set. Any invalid pattern
+// results in an error naming that pattern.
+fn build_glob_set(patterns: &[String]) -> Result<GlobSet> {
+  let mut builder = GlobSetBuilder::new();
+
+  for pattern in patterns {
+    builder.add(Glob::new(pattern).map_err(|e| {
+      Error::new(
+        Status::InvalidArg,
+        format!("Invalid glob pattern '{}': {}", pattern, e),
+      )
+    })?);
+  }
+
+  builder
+    .build()
+    .map_err(|e| Error::new(Status::InvalidArg, format!("{}", e)))
+}
+
+// Ordered ignore patterns, where the patterns prefixed with ! include back the paths
+// ignored by the preceding patterns. The last pattern matching a path decides whether it
+// is ignored, as in gitignore.
+struct IgnoreMatcher {
+  set: GlobSet,
+  negated: Vec<bool>,
+}
+
+impl IgnoreMatcher {
+  fn new(patterns: &[String]) -> Result<Self> {
+    let negated = patterns
+      .iter()
+      .map(|pattern| pattern.starts_with('!'))
+      .collect();
+    let patterns: Vec<String> = patterns
+      .iter()
+      .map(|pattern| pattern.strip_prefix('!').unwrap_or(pattern).to_string())
+      .collect();
+
+    Ok(IgnoreMatcher {
+      set: build_glob_set(&patterns)?,
+      negated,
+    })
+  }
+
+  // Checks whether any of the patterns includes back the ignored paths
+  fn has_negation(&self) -> bool {
+    self.negated.contains(&true)
+  }
+
+  fn is_match(&self, path: &Path) -> bool {
+    if !self.has_negation() {
+      return self.set.is_match(path);
+    }
+    self
+      .set
+      .matches(path)
+      .into_iter()
+      .max()
+      .is_some_and(|index| !self.negated[index])
+  }
+}
+
+// Checks whether the error is a fatal backend error after which the watcher doesn't
+// report any events, i.e. a generic or io error not related to any path.
+fn is_fatal_error(error: &notify::Error) -> bool {
+  matches!(
+    error.kind,
+    notify::ErrorKind::Generic(_) | notify::ErrorKind::Io(_)
+  ) && error.paths.is_empty()
+}
+
+// Stops the poll watcher after an error and probes the watched paths containing the
+// error paths, or all the watched paths if none of them does, with the delay doubling from
+// the poll interval up to the cap on each failed probe. The error paths themselves are not
+// probed, as they are usually the entries removed during the scan. The delays are jittered
+// so that the watchers of the same server don't retry at once. The poll watcher is
+// recreated once the paths are reachable again, which is notified through the recovered
+// event, or after MAX_BACKOFF_PROBES failed probes so that the other paths are polled
+// again, in which case the next error starts another streak.
+fn back_off_polling(
+  state: Weak<Mutex<WatcherState>>,
+  dispatcher: Weak<Dispatcher>,
+  paths: Vec<PathBuf>,
+  max_backoff: Duration,
+  backing_off: Arc<AtomicBool>,
+) {
+  let mut delay = match state.upgrade() {
+    Some(state) => {
+      let mut state = state.lock().unwrap();
+      if state.watcher.is_none() {
+        return;
+      }
+      // Paths remain in the state, and are watched again once recovered
+      state.watcher = Some(Box::new(NullWatcher));
+      state.config.poll_interval()
+    }
+    None => return,
+  };
+
+  for probe in 1..=MAX_BACKOFF_PROBES {
+    delay = next_backoff(delay, max_backoff);
+    thread::sleep(jitter(delay));
+
+    let (state, dispatcher) = match (state.upgrade(), dispatcher.upgrade()) {
+      (Some(state), Some(dispatcher)) => (state, dispatcher),
+      _ => return,
+    };
+    let mut state = state.lock().unwrap();
+    if state.watcher.is_none() {
+      return;
+    }
+
+    let mut probed: Vec<PathBuf> = paths
+      .iter()
+      .filter_map(|path| state.root_of(path))
+      .collect();
+    if probed.is_empty() {
+      probed = state.paths.read().unwrap().iter().cloned().collect();
+    }
+    // Watched files may not exist while their parents are reachable
+    let reachable = probed
+      .iter()
+      .all(|path| fs::metadata(path).is_ok() || state.is_watched_file(path));
+    if !reachable && probe < MAX_BACKOFF_PROBES {
+      continue;
+    }
+
+    backing_off.store(false, Ordering::SeqCst);
+    match state.restart() {
+      Ok(_) if reachable => dispatcher.emit(FsEvent::new(
+        String::from("recovered"),
+        PathBuf::new(),
+        system_time_nanos(SystemTime::now()),
+      )),
+      Ok(_) => {}
+      Err(e) => dispatcher.emit_error(e),
+    }
+    return;
+  }
+}
+
+// Returns the delay of the next probe after the given one, which doubles up to the cap
+fn next_backoff(delay: Duration, max_backoff: Duration) -> Duration {
+  (delay * 2).min(max_backoff)
+}
+
+// Returns the random delay between the half and the whole of the given delay
+fn jitter(delay: Duration) -> Duration {
+  use std::hash::{BuildHasher, Hasher};
+
+  // Hasher is keyed randomly for each instance, which is enough for spreading the delays
+  let random = std::collections::hash_map::RandomState::new()
+    .build_hasher()
+    .finish();
+  delay.mul_f64(0.5 + (random % 1000) as f64 / 2000.0)
+}
+
+// Converts the error of the backend into the error delivered to javascript. Reaching the
+// OS limit of the watches, which is reported as ENOSPC by inotify, is explained along with
+// the ways to resolve it.
+fn watch_error(error: notify::Error) -> Error {
+  let limit_reached = match &error.kind {
+    notify::ErrorKind::MaxFilesWatch => true,
+    notify::ErrorKind::Io(e) => e.raw_os_error() == Some(libc::ENOSPC),
+    _ => false,
+  };
+  if !limit_reached {
+    return Error::new(Status::GenericFailure, format!("{}", error));
+  }
+
+  let paths: Vec<String> = error
+    .paths
+    .iter()
+    .map(|path| path.display().to_string())
+    .collect();
+  Error::new(
+    Status::GenericFailure,
+    format!(
+      "Reached the OS limit of file watches{}. Increase the limit such as with \
+       `sysctl fs.inotify.max_user_watches=524288` on linux, or narrow the watch by \
+       watching fewer directories, ignoring large directories or lowering the maxDepth \
+       option.",
+      if paths.is_empty() {
+        String::new()
+      } else {
+        format!(" while watching {}", paths.join(", "))
+      }
+    ),
+  )
+}
+
+// Checks whether the error is caused by the lack of permission for a path
+fn is_permission_denied(error: &notify::Error) -> bool {
+  matches!(&error.kind, notify::ErrorKind::Io(e) if e.kind() == io::ErrorKind::PermissionDenied)
+}
+
+// Checks whether the error is caused by a stale file handle (ESTALE), which happens when
+// the server of a network file system recycles the inode of a watched path.
+fn is_stale_handle(error: &notify::Error) -> bool {
+  matches!(&error.kind, notify::ErrorKind::Io(e) if e.kind() == io::ErrorKind::StaleNetworkFileHandle)
+}
+
+// Creates the poll watcher if polling is true or the recommended watcher otherwise with
+// the given config, which invokes the shared event handler. The recommended watcher is
+// backed by the shared watcher if shared is true, which is created with the default
+// config as it is shared by the instances. Returns the watcher along with its backend
+// name.
+fn create_watcher(
+  handler: &SharedHandler,
+  config: Config,
+  polling: bool,
+  shared: bool,
+) -> Result<(Box<dyn Watcher + Send>, WatcherKind)> {
+  let handler = Arc::clone(handler);
+  let event_handler = move |ev: notify::Result<Event>| (*handler.lock().unwrap())(ev);
+
+  match polling {
+    true => Ok((
+      Box::new(
+        PollWatcher::new(event_handler, config)
+          .map_err(|e| Error::new(Status::GenericFailure, format!("{}", e)))?,
+      ),
+      PollWatcher::kind(),
+    )),
+    false if shared => Ok((
+      Box::new(
+        SharedWatcher::new(event_handler, config)
+          .map_err(|e| Error::new(Status::GenericFailure, format!("{}", e)))?,
+      ),
+      SharedWatcher::kind(),
+    )),
+    false => Ok((
+      Box::new(
+        RecommendedWatcher::new(event_handler, config)
+          .map_err(|e| Error::new(Status::GenericFailure, format!("{}", e)))?,
+      ),
+      RecommendedWatcher::kind(),
+    )),
+  }
+}
+
+// Event handler of the watcher instance routed the events of a path watched by the shared
+// watcher, along with the recursive mode in which the instance watches the path
+struct SharedRoute {
+  id: u64,
+  recursive_mode: RecursiveMode,
+  handler: SharedHandler,
+}
+
+// Process-global recommended watcher used by the watcher instances created with the shared
+// option. It is created once a path is watched and dropped once no path is watched.
+static SHARED_WATCHER: LazyLock<Mutex<Option<RecommendedWatcher>>> =
+  LazyLock::new(Default::default);
+// Routes of each of the paths watched by the shared watcher. The event handler of the
+// shared watcher locks only the routes, since the backend may wait on the event handler
+// thread while watching a path.
+static SHARED_ROUTES: LazyLock<RwLock<HashMap<PathBuf, Vec<SharedRoute>>>> =
+  LazyLock::new(Default::default);
+static NEXT_SHARED_ID: AtomicU64 = AtomicU64::new(1);
+
+// Recursive mode in which the path is watched by the shared watcher for the given routes,
+// which is recursive if any of the instances watches it recursively
+fn shared_mode(routes: Option<&Vec<SharedRoute>>) -> Option<RecursiveMode> {
+  let routes = routes.filter(|routes| !routes.is_empty())?;
+  if routes
+    .iter()
+    .any(|route| route.recursive_mode == RecursiveMode::Recursive)
+  {
+    Some(RecursiveMode::Recursive)
+  } else {
+    Some(RecursiveMode::NonRecursive)
+  }
+}
+
+// Checks whether the event path is under the path watched in the given recursive mode
+fn is_routed(root: &Path, recursive_mode: RecursiveMode, path: &Path) -> bool {
+  match recursive_mode {
+    RecursiveMode::Recursive => path.starts_with(root),
+    RecursiveMode::NonRecursive => path == root || path.parent() == Some(root),
+  }
+}
+
+// Copies the event or the error for each of the instances it is routed to. The io errors
+// are recreated from their kind and message, since they can't be cloned.
+fn clone_event(ev: &notify::Result<Event>) -> notify::Result<Event> {
+  let error = match ev {
+    Ok(event) => return Ok(event.clone()),
+    Err(error) => error,
+  };
+  let kind = match &error.kind {
+    notify::ErrorKind::Generic(message) => notify::ErrorKind::Generic(message.clone()),
+    notify::ErrorKind::Io(e) => notify::ErrorKind::Io(
+      e.raw_os_error()
+        .map(io::Error::from_raw_os_error)
+        .unwrap_or_else(|| io::Error::new(e.kind(), e.to_string())),
+    ),
+    notify::ErrorKind::PathNotFound => notify::ErrorKind::PathNotFound,
+    notify::ErrorKind::WatchNotFound => notify::ErrorKind::WatchNotFound,
+    notify::ErrorKind::InvalidConfig(config) => notify::ErrorKind::InvalidConfig(*config),
+    notify::ErrorKind::MaxFilesWatch => notify::ErrorKind::MaxFilesWatch,
+  };
+  Err(notify::Error {
+    kind,
+    paths: error.paths.clone(),
+  })
+}
+
+// Event handler of the shared watcher, which routes each event only to the instances
+// watching any of its paths. The events without paths such as the overflow events and the
+// backend errors are routed to all the instances.
+fn route_shared_event(ev: notify::Result<Event>) {
+  let paths = match &ev {
+    Ok(event) => &event.paths,
+    Err(error) => &error.paths,
+  };
+  let handlers: Vec<SharedHandler> = {
+    let routes = SHARED_ROUTES.read().unwrap();
+    let mut ids = HashSet::new();
+    routes
+      .iter()
+      .flat_map(|(root, routes)| routes.iter().map(move |route| (root, route)))
+      .filter(|(root, route)| {
+        paths.is_empty()
+          || paths
+            .iter()
+            .any(|path| is_routed(root, route.recursive_mode, path))
+      })
+      .filter(|(_, route)| ids.insert(route.id))
+      .map(|(_, route)| Arc::clone(&route.handler))
+      .collect()
+  };
+
+  for handler in handlers {
+    (*handler.lock().unwrap())(clone_event(&ev));
+  }
+}
+
+// Watcher of the instance created with the shared option, which watches the paths with
+// the shared watcher and registers the event handler of the instance for them. The paths
+// are unwatched once it is dropped.
+struct SharedWatcher {
+  id: u64,
+  handler: SharedHandler,
+}
+
+impl Watcher for SharedWatcher {
+  fn new<F: EventHandler>(mut event_handler: F, _config: Config) -> notify::Result<Self> {
+    Ok(SharedWatcher {
+      id: NEXT_SHARED_ID.fetch_add(1, Ordering::SeqCst),
+      handler: Arc::new(Mutex::new(move |ev: notify::Result<Event>| {
+        event_handler.handle_event(ev)
+      })),
+    })
+  }
+
+  fn watch(&mut self, path: &Path, recursive_mode: RecursiveMode) -> notify::Result<()> {
+    let mut watcher = SHARED_WATCHER.lock().unwrap();
+    if watcher.is_none() {
+      *watcher = Some(RecommendedWatcher::new(
+        route_shared_event,
+        Config::default(),
+      )?);
+    }
+
+    // Route is registered before watching so that no event of the path is missed
+    let (previous, current) = {
+      let mut routes = SHARED_ROUTES.write().unwrap();
+      let previous = shared_mode(routes.get(path));
+      let path_routes = routes.entry(path.to_path_buf()).or_default();
+      path_routes.retain(|route| route.id != self.id);
+      path_routes.push(SharedRoute {
+        id: self.id,
+        recursive_mode,
+        handler: Arc::clone(&self.handler),
+      });
+      (previous, shared_mode(Some(path_routes)))
+    };
+    if previous == current {
+      return Ok(());
+    }
+
+    let result = watcher
+      .as_mut()
+      .map_or(Ok(()), |watcher| watcher.watch(path, recursive_mode));
+    if result.is_err() {
+      let mut routes = SHARED_ROUTES.write().unwrap();
+      if let Some(path_routes) = routes.get_mut(path) {
+        path_routes.retain(|route| route.id != self.id);
+        if path_routes.is_empty() {
+          routes.remove(path);
+        }
+      }
+      if routes.is_empty() {
+        watcher.take();
+      }
+    }
+    result
+  }
+
+  fn unwatch(&mut self, path: &Path) -> notify::Result<()> {
+    let mut watcher = SHARED_WATCHER.lock().unwrap();
+    let (previous, current, is_empty) = {
+      let mut routes = SHARED_ROUTES.write().unwrap();
+      let path_routes = match routes.get_mut(path) {
+        Some(path_routes) if path_routes.iter().any(|route| route.id == self.id) => path_routes,
+        _ => return Err(notify::Error::watch_not_found().add_path(path.to_path_buf())),
+      };
+      let previous = shared_mode(Some(path_routes));
+      path_routes.retain(|route| route.id != self.id);
+      let current = shared_mode(Some(path_routes));
+      if current.is_none() {
+        routes.remove(path);
+      }
+      (previous, current, routes.is_empty())
+    };
+
+    // Path remains watched in the mode of the other instances still watching it
+    let result = match (watcher.as_mut(), current) {
+      (Some(watcher), None) => watcher.unwatch(path),
+      (Some(watcher), Some(recursive_mode)) if current != previous => {
+        watcher.watch(path, recursive_mode)
+      }
+      _ => Ok(()),
+    };
+    if is_empty {
+      watcher.take();
+    }
+    result
+  }
+
+  fn kind() -> WatcherKind {
+    RecommendedWatcher::kind()
+  }
+}
+
+impl Drop for SharedWatcher {
+  fn drop(&mut self) {
+    let paths: Vec<PathBuf> = SHARED_ROUTES
+      .read()
+      .unwrap()
+      .iter()
+      .filter(|(_, routes)| routes.iter().any(|route| route.id == self.id))
+      .map(|(path, _)| path.clone())
+      .collect();
+    for path in paths {
+      let _ = self.unwatch(&path);
+    }
+  }
+}
+
+// Probe telling whether a path is on a network file system, used by the backend
+// detection. It is a plain function so that the detection can be driven without such
+// a mount.
+type NetworkProbe = fn(&Path) -> bool;
+
+// Checks whether the given path is on a network file system such as NFS or SMB, based
+// on the file system type reported by statfs. Always false on the other platforms.
+#[cfg(target_os = "linux")]
+fn is_network_fs(path: &Path) -> bool {
+  use std::os::unix::ffi::OsStrExt;
+
+  // Magic numbers of the network file systems, see statfs(2)
+  const NETWORK_FS_MAGICS: [u64; 9] = [
+    0x6969,     // nfs
+    0x517b,     // smb
+    0xff534d42, // cifs
+    0xfe534d42, // smb2
+    0x5346414f, // afs
+    0x73757245, // coda
+    0x01021997, // 9p
+    0x00c36400, // ceph
+    0x564c,     // ncp
+  ];
+
+  let path = match std::ffi::CString::new(path.as_os_str().as_bytes()) {
+    Ok(path) => path,
+    Err(_) => return false,
+  };
+  let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+
+  if unsafe { libc::statfs(path.as_ptr(), &mut stat) } != 0 {
+    return false;
+  }
+  NETWORK_FS_MAGICS.contains(&(stat.f_type as u64))
+}
+
+#[cfg(target_os = "macos")]
+fn is_network_fs(path: &Path) -> bool {
+  use std::os::unix::ffi::OsStrExt;
+
+  const NETWORK_FS_TYPES: [&str; 5] = ["nfs", "smbfs", "afpfs", "webdav", "cifs"];
+
+  let path = match std::ffi::CString::new(path.as_os_str().as_bytes()) {
+    Ok(path) => path,
+    Err(_) => return false,
+  };
+  let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+
+  if unsafe { libc::statfs(path.as_ptr(), &mut stat) } != 0 {
+    return false;
+  }
+  let fs_type = unsafe { std::ffi::CStr::from_ptr(stat.f_fstypename.as_ptr()) };
+  NETWORK_FS_TYPES.contains(&fs_type.to_string_lossy().as_ref())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn is_network_fs(_path: &Path) -> bool {
+  false
+}
+
+// Tracer of the processes modifying the files, which listens to the fanotify events of the
+// mounts containing the watched paths alongside the backend. The events are read on the
+// reader thread so that the queue of the mount doesn't overflow, and right before looking
+// up the process of an event, since the fanotify event is queued along with the backend
+// event by the same write.
+#[cfg(target_os = "linux")]
+struct PidTracer {
+  fd: libc::c_int,
+  pids: Mutex<TracedPids>,
+}
+
+// Process that last modified each of the recently modified paths, up to MAX_TRACED_PIDS
+// paths beyond which the least recently modified ones are evicted
+#[cfg(target_os = "linux")]
+#[derive(Default)]
+struct TracedPids {
+  pids: HashMap<PathBuf, (u64, u32)>,
+  // Paths in the order of their modifications along with the sequence of each. Entries of
+  // the paths modified again since are stale, which are skipped on the eviction.
+  order: VecDeque<(u64, PathBuf)>,
+  seq: u64,
+}
+
+#[cfg(target_os = "linux")]
+impl TracedPids {
+  fn insert(&mut self, path: PathBuf, pid: u32) {
+    self.seq += 1;
+    self.pids.insert(path.clone(), (self.seq, pid));
+    self.order.push_back((self.seq, path));
+
+    while self.pids.len() > MAX_TRACED_PIDS {
+      match self.order.pop_front() {
+        Some((seq, path)) if self.pids.get(&path).is_some_and(|(last, _)| *last == seq) => {
+          self.pids.remove(&path);
+        }
+        Some(_) => {}
+        None => break,
+      }
+    }
+    // Drop the stale entries once they outnumber the paths, keeping the order bounded
+    if self.order.len() > 2 * MAX_TRACED_PIDS {
+      let pids = &self.pids;
+      self
+        .order
+        .retain(|(seq, path)| pids.get(path).is_some_and(|(last, _)| last == seq));
+    }
+  }
+
+  fn get(&self, path: &Path) -> Option<u32> {
+    self.pids.get(path).map(|(_, pid)| *pid)
+  }
+}
+
+#[cfg(target_os = "linux")]
+impl PidTracer {
+  // Initializes the fanotify group, which requires the CAP_SYS_ADMIN capability
+  fn new() -> io::Result<Arc<Self>> {
+    let fd = unsafe {
+      libc::fanotify_init(
+        libc::FAN_CLASS_NOTIF | libc::FAN_CLOEXEC | libc::FAN_NONBLOCK,
+        (libc::O_RDONLY | libc::O_LARGEFILE | libc::O_CLOEXEC) as libc::c_uint,
+      )
+    };
+    if fd < 0 {
+      return Err(io::Error::last_os_error());
+    }
+    let tracer = Arc::new(PidTracer {
+      fd,
+      pids: Mutex::new(TracedPids::default()),
+    });
+
+    // Read the events until the tracer is dropped along with the watcher
+    let weak_tracer = Arc::downgrade(&tracer);
+    thread::spawn(move || loop {
+      let mut poll_fd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+      };
+      // Timeout lets the thread notice the tracer being dropped
+      unsafe { libc::poll(&mut poll_fd, 1, 500) };
+
+      match weak_tracer.upgrade() {
+        Some(tracer) => tracer.read_events(),
+        None => break,
+      }
+    });
+    Ok(tracer)
+  }
+
+  // Listens to the modifications of the files on the mount containing the path
+  fn mark(&self, path: &Path) -> io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())
+      .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let marked = unsafe {
+      libc::fanotify_mark(
+        self.fd,
+        libc::FAN_MARK_ADD | libc::FAN_MARK_MOUNT,
+        libc::FAN_MODIFY | libc::FAN_CLOSE_WRITE,
+        libc::AT_FDCWD,
+        path.as_ptr(),
+      )
+    };
+    if marked != 0 {
+      return Err(io::Error::last_os_error());
+    }
+    Ok(())
+  }
+
+  // Records the process of each of the queued events by the path of its file descriptor,
+  // which is closed right after
+  fn read_events(&self) {
+    const METADATA_LEN: usize = std::mem::size_of::<libc::fanotify_event_metadata>();
+
+    let mut pids = self.pids.lock().unwrap();
+    let mut buf = [0u8; 4096];
+    loop {
+      let len = unsafe { libc::read(self.fd, buf.as_mut_ptr().cast(), buf.len()) };
+      if len <= 0 {
+        break;
+      }
+
+      let mut offset = 0;
+      while offset + METADATA_LEN <= len as usize {
+        let metadata: libc::fanotify_event_metadata =
+          unsafe { std::ptr::read_unaligned(buf.as_ptr().add(offset).cast()) };
+        if metadata.vers != libc::FANOTIFY_METADATA_VERSION
+          || (metadata.event_len as usize) < METADATA_LEN
+        {
+          break;
+        }
+        if metadata.fd >= 0 {
+          if let Ok(path) = fs::read_link(format!("/proc/self/fd/{}", metadata.fd)) {
+            pids.insert(path, metadata.pid as u32);
+          }
+          unsafe { libc::close(metadata.fd) };
+        }
+        offset += metadata.event_len as usize;
+      }
+    }
+  }
+
+  // Process that last modified the file at the path. The path is also looked up in its
+  // canonical form, since the file descriptors resolve to the canonical paths. The event
+  // of fanotify may be queued just after the event of the backend, which is waited for
+  // shortly if the path is not known yet.
+  fn pid_of(&self, path: &Path) -> Option<u32> {
+    let canonical = fs::canonicalize(path).ok();
+    for timeout in [0, PID_WAIT_MS] {
+      if timeout > 0 {
+        let mut poll_fd = libc::pollfd {
+          fd: self.fd,
+          events: libc::POLLIN,
+          revents: 0,
+        };
+        unsafe { libc::poll(&mut poll_fd, 1, timeout) };
+      }
+      self.read_events();
+
+      let pids = self.pids.lock().unwrap();
+      let pid = pids
+        .get(path)
+        .or_else(|| canonical.as_ref().and_then(|path| pids.get(path)));
+      if pid.is_some() {
+        return pid;
+      }
+    }
+    None
+  }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for PidTracer {
+  fn drop(&mut self) {
+    unsafe { libc::close(self.fd) };
+  }
+}
+
+// Processes are traced only with fanotify, which is not available on other platforms
+#[cfg(not(target_os = "linux"))]
+struct PidTracer;
+
+#[cfg(not(target_os = "linux"))]
+impl PidTracer {
+  fn new() -> io::Result<Arc<Self>> {
+    Err(io::Error::new(
+      io::ErrorKind::Unsupported,
+      "Process tracing is only supported on linux",
+    ))
+  }
+
+  fn mark(&self, _path: &Path) -> io::Result<()> {
+    Ok(())
+  }
+
+  fn pid_of(&self, _path: &Path) -> Option<u32> {
+    None
+  }
+}
+
+// Returns the name of the backend used by the watcher of given kind
+fn backend_name(kind: WatcherKind) -> &'static str {
+  match kind {
+    WatcherKind::Inotify => "inotify",
+    WatcherKind::Fsevent => "fsevents",
+    WatcherKind::Kqueue => "kqueue",
+    WatcherKind::PollWatcher => "poll",
+    WatcherKind::ReadDirectoryChangesWatcher => "readDirectoryChanges",
+    _ => "unknown",
+  }
+}
+
+// Returns the flag and info attributes of the given event
+fn event_flags(event: &Event) -> Vec<String> {
+  let flag = event.flag().map(|flag| match flag {
+    Flag::Rescan => String::from("rescan"),
+  });
+  flag
+    .into_iter()
+    .chain(event.info().map(String::from))
+    .collect()
+}
+
+// Returns the name of the given event kind for the path. The access, metadata and close
+// write events are named only if enabled, and the others are named as other. Whether the
+// path is a directory is checked only for the kinds named with the Dir suffix, since the
+// check may need a stat call which is expensive on network file systems.
+fn event_kind_name(
+  kind: &EventKind,
+  path: &Path,
+  is_dir: impl FnOnce() -> bool,
+  access: bool,
+  metadata: bool,
+  close_write: bool,
+) -> &'static str {
+  match kind {
+    // Rename events are correlated into single rename event by the event handler.
+    // Uncorrelated ones are handled as remove and add event.
+    EventKind::Create(_) | EventKind::Modify(ModifyKind::Name(RenameMode::To)) => {
+      if is_dir() {
+        "addDir"
+      } else {
+        "add"
+      }
+    }
+    EventKind::Remove(_) | EventKind::Modify(ModifyKind::Name(RenameMode::From)) => {
+      if is_dir() {
+        "removeDir"
+      } else {
+        "remove"
+      }
+    }
+    EventKind::Modify(kind) => match kind {
+      // Data changes are reported as any on some backends
+      ModifyKind::Any | ModifyKind::Data(_) if path.is_file() => "modify",
+      // Metadata events are noisy on some backends, so they are delivered only if
+      // enabled
+      ModifyKind::Metadata(kind) if metadata => metadata_kind_name(*kind),
+      _ => "other",
+    },
+    EventKind::Access(AccessKind::Close(AccessMode::Write)) if close_write => "change",
+    // Access events are noisy, so they are delivered only if enabled
+    EventKind::Access(_) if access => "access",
+    _ => "other",
+  }
+}
+
+// Returns the event kind name for the given kind of metadata change
+fn metadata_kind_name(kind: MetadataKind) -> &'static str {
+  match kind {
+    MetadataKind::Permissions => "permissions",
+    MetadataKind::Ownership => "ownership",
+    MetadataKind::AccessTime | MetadataKind::WriteTime => "times",
+    _ => "metadata",
+  }
+}
+
+// Callback delivering the events or the errors to the javascript callback through its
+// threadsafe function, which returns the status of queueing the call
+type EventCallback =
+  Box<dyn Fn(Result<Vec<FsEvent>>, ThreadsafeFunctionCallMode) -> Status + Send + Sync>;
+
+// Callback delivering the errors to the separate javascript callback for the errors
+type ErrorCallback = Box<dyn Fn(Error, ThreadsafeFunctionCallMode) -> Status + Send + Sync>;
+
+// Dispatches the events to javascript callback one at a time or in batches if
+// batching is enabled. The events not allowed by filter for their kind, extension or
+// path type are dropped.
+struct Dispatcher {
+  callback: EventCallback,
+  // Separate javascript callback for the errors if provided
+  error_callback: Option<ErrorCallback>,
+  batch: Option<Mutex<Vec<FsEvent>>>,
+  filter: EventFilter,
+  // Number of event deliveries queued but not yet handled by the javascript callback,
+  // which is bounded by max_queue if not zero. The overflowed flag is set when events
+  // are dropped until the queue is available again.
+  in_flight: Arc<AtomicUsize>,
+  max_queue: usize,
+  overflowed: AtomicBool,
+  // Whether the calls block on the full callback queue. Calls from the javascript thread
+  // never block, since the queue can't be drained while it waits.
+  blocking: bool,
+  js_thread: thread::ThreadId,
+  canonicalize: bool,
+  include_size: bool,
+  include_file_id: bool,
+  // Watched paths whose first event is delivered if the once option is set, which are
+  // unwatched through the watcher state
+  once: Option<Mutex<HashSet<PathBuf>>>,
+  state: Weak<Mutex<WatcherState>>,
+  // Handle of this dispatcher for closing the watcher along with it
+  this: Weak<Dispatcher>,
+  // Time of the last delivered event, from which the idle timeout is measured
+  last_event: Mutex<Instant>,
+  rate_limit: Option<RateLimit>,
+  // Events held by the event handler until their timers expire, which are delivered right
+  // away on drain
+  timers: Arc<HoldTimers>,
+  // Work on the watcher state requested by the event handler
+  tasks: Arc<StateTasks>,
+  pending_modify: CoalescingEvents,
+  pending_settle: SettlingEvents,
+  pending_debounce: DebouncingEvents,
+  pending_rename: Arc<Mutex<Option<PendingRename>>>,
+  // Tracer of the processes modifying the files if the trace_pid option is set and the
+  // tracing is permitted
+  pid_tracer: Option<Arc<PidTracer>>,
+  // Decisions made on the events in the dry run mode, which are read with drain_debug
+  debug_log: Option<Mutex<VecDeque<DebugRecord>>>,
+  stats: Arc<EventStats>,
+  // Channels of the event iterators subscribed by events, which are fed along with the
+  // callback and closed along with the watcher
+  streams: Mutex<Vec<EventStream>>,
+}
+
+// Capacity of the channel of the event iterator if not given, beyond which the events are
+// dropped until the iterator catches up
+const DEFAULT_STREAM_CAPACITY: u32 = 1000;
+
+// Bounded channel of the event iterator. The events delivered while the channel is full
+// are dropped, which is reported by an overflow event once the channel has room again.
+struct EventStream {
+  sender: mpsc::SyncSender<FsEvent>,
+  overflowed: bool,
+}
+
+impl EventStream {
+  // Sends the event into the channel, returning false once the iterator is dropped
+  fn send(&mut self, event: &FsEvent) -> bool {
+    if self.overflowed {
+      let marker = FsEvent::new(String::from("overflow"), PathBuf::new(), event.ts);
+      match self.sender.try_send(marker) {
+        Ok(()) => self.overflowed = false,
+        Err(TrySendError::Full(_)) => return true,
+        Err(TrySendError::Disconnected(_)) => return false,
+      }
+    }
+    match self.sender.try_send(event.clone()) {
+      Ok(()) => true,
+      Err(TrySendError::Full(_)) => {
+        self.overflowed = true;
+        true
+      }
+      Err(TrySendError::Disconnected(_)) => false,
+    }
+  }
+}
+
+// Maximum number of the decisions kept in the debug log, beyond which the oldest ones are
+// dropped
+const MAX_DEBUG_RECORDS: usize = 10000;
+
+// Decision made on the event in the dry run mode, along with the reason it is suppressed
+struct DebugRecord {
+  event: FsEvent,
+  delivered: bool,
+  reason: Option<&'static str>,
+}
+
+// Token bucket limiting the events delivered per second, which holds up to one second
+// worth of tokens. The events dropped while the bucket is empty are counted for the
+// coalesced event.
+struct RateLimit {
+  rate: f64,
+  bucket: Mutex<(f64, Instant)>,
+  coalesced: AtomicU64,
+}
+
+impl RateLimit {
+  fn new(rate: u32) -> Self {
+    RateLimit {
+      rate: f64::from(rate),
+      bucket: Mutex::new((f64::from(rate), Instant::now())),
+      coalesced: AtomicU64::new(0),
+    }
+  }
+
+  // Takes a token from the bucket refilled for the time elapsed, or counts the event as
+  // coalesced if the bucket is empty
+  fn acquire(&self) -> bool {
+    let mut bucket = self.bucket.lock().unwrap();
+    let (tokens, refilled) = &mut *bucket;
+    *tokens = (*tokens + refilled.elapsed().as_secs_f64() * self.rate).min(self.rate);
+    *refilled = Instant::now();
+
+    if *tokens >= 1.0 {
+      *tokens -= 1.0;
+      true
+    } else {
+      self.coalesced.fetch_add(1, Ordering::Relaxed);
+      false
+    }
+  }
+}
+
+// Counters of the events seen from the backend, delivered to the javascript callback,
+// suppressed as duplicates or by the filters, and dropped on the full queue. These are
+// shared by the event handler, the dispatcher and the watcher instance.
+#[derive(Default)]
+struct EventStats {
+  seen: AtomicU64,
+  delivered: AtomicU64,
+  suppressed: AtomicU64,
+  dropped: AtomicU64,
+}
+
+impl EventStats {
+  fn add(&self, counter: &AtomicU64, count: usize) {
+    counter.fetch_add(count as u64, Ordering::Relaxed);
+  }
+}
+
+impl Dispatcher {
+  fn emit(&self, mut event: FsEvent) {
+    if !self.filter.is_event_allowed(&event) {
+      self.suppress(&event, "filtered");
+      return;
+    }
+
+    // Events not related to any path such as ready are not limited
+    if let Some(rate_limit) = &self.rate_limit {
+      if !event.path.as_os_str().is_empty() && !rate_limit.acquire() {
+        self.suppress(&event, "rateLimited");
+        return;
+      }
+    }
+
+    // Only the first event of each watched path is delivered if the once option is set.
+    // Events not related to any path such as ready are always delivered.
+    let mut unwatched_root = None;
+    if let Some(fired) = self
+      .once
+      .as_ref()
+      .filter(|_| !event.path.as_os_str().is_empty())
+    {
+      let roots = self.filter.roots.read().unwrap();
+      let mut fired = fired.lock().unwrap();
+      // Forget the paths unwatched already, so that they can be watched again
+      fired.retain(|root| roots.contains(root));
+
+      match closest_root(&roots, &normalize_path(&event.path)) {
+        Some(root) if fired.insert(root.clone()) => unwatched_root = Some(root.clone()),
+        _ => {
+          self.suppress(&event, "once");
+          return;
+        }
+      }
+    }
+
+    *self.last_event.lock().unwrap() = Instant::now();
+
+    if self.canonicalize {
+      event.path = canonical_path(&event.path);
+      event.old_path = event.old_path.map(|old_path| canonical_path(&old_path));
+    }
+    // Removed paths are not checked as they don't exist anymore
+    if (self.include_size || self.include_file_id) && !event.kind.starts_with("remove") {
+      if let Ok(metadata) = fs::metadata(&event.path) {
+        if self.include_size {
+          event.size = Some(metadata.len());
+        }
+        if let Some((dev, ino)) = file_id(&metadata).filter(|_| self.include_file_id) {
+          event.dev = Some(dev);
+          event.ino = Some(ino);
+        }
+      }
+    }
+    if let Some(tracer) = self
+      .pid_tracer
+      .as_ref()
+      .filter(|_| matches!(event.kind.as_str(), "modify" | "change"))
+    {
+      event.pid = tracer.pid_of(&event.path);
+    }
+
+    self.deliver(event);
+
+    // Unwatch the path after its first event, and close the watcher with the last one
+    // the same way as close does
+    if let Some(root) = unwatched_root {
+      self
+        .tasks
+        .push(StateTask::UnwatchFired(root), Duration::ZERO);
+    }
+  }
+
+  // Emits the last of the settled events of a path as the event of their net effect, given
+  // whether the path existed before the first of them. Nothing is emitted if the path was
+  // created and removed in the meantime.
+  fn emit_settled(&self, existed: bool, mut event: FsEvent) {
+    let kind = match (existed, event.kind != "remove") {
+      (false, true) => "add",
+      (true, true) => "modify",
+      (true, false) => "remove",
+      (false, false) => {
+        self.suppress(&event, "settled");
+        return;
+      }
+    };
+    event.kind = String::from(kind);
+    self.emit(event);
+  }
+
+  // Reports the path which can't be watched through the watchError event, and its error
+  // prefixed with the path the same way as the errors of add
+  fn emit_watch_error(&self, path: PathBuf, error: Error) {
+    let message = format!("{}: {}", path.display(), error.reason);
+    self.emit(FsEvent::new(
+      String::from("watchError"),
+      path,
+      system_time_nanos(SystemTime::now()),
+    ));
+    self.emit_error(Error::new(error.status, message));
+  }
+
+  // Errors are not batched and delivered right away, to the error callback if provided
+  fn emit_error(&self, error: Error) {
+    match &self.error_callback {
+      Some(error_callback) => {
+        error_callback(error, self.call_mode());
+      }
+      None => self.call(Err(error)),
+    }
+  }
+
+  // Delivers the event, or buffers it if batching is enabled
+  fn deliver(&self, event: FsEvent) {
+    if self.debug_log.is_some() {
+      self.record(event, true, None);
+      return;
+    }
+
+    let mut streams = self.streams.lock().unwrap();
+    if !streams.is_empty() {
+      streams.retain_mut(|stream| stream.send(&event));
+    }
+    drop(streams);
+
+    match &self.batch {
+      Some(batch) => {
+        let mut batch = batch.lock().unwrap();
+        batch.push(event);
+        if batch.len() >= MAX_BATCH_SIZE {
+          self.call(Ok(std::mem::take(&mut *batch)));
+        }
+      }
+      None => self.call(Ok(vec![event])),
+    }
+  }
+
+  // Counts the event as suppressed for the given reason, which is recorded along with the
+  // event in the dry run mode
+  fn suppress(&self, event: &FsEvent, reason: &'static str) {
+    self.stats.add(&self.stats.suppressed, 1);
+    if self.debug_log.is_some() {
+      self.record(event.clone(), false, Some(reason));
+    }
+  }
+
+  // Records the decision made on the event into the debug log if in the dry run mode
+  fn record(&self, event: FsEvent, delivered: bool, reason: Option<&'static str>) {
+    if let Some(debug_log) = &self.debug_log {
+      let mut debug_log = debug_log.lock().unwrap();
+      if debug_log.len() >= MAX_DEBUG_RECORDS {
+        debug_log.pop_front();
+      }
+      debug_log.push_back(DebugRecord {
+        event,
+        delivered,
+        reason,
+      });
+    }
+  }
+
+  // Delivers the coalesced event carrying the number of the events dropped by the rate
+  // limit since the previous one, if any
+  fn flush_coalesced(&self) {
+    if let Some(rate_limit) = &self.rate_limit {
+      let count = rate_limit.coalesced.swap(0, Ordering::Relaxed);
+      if count > 0 {
+        let mut event = FsEvent::new(
+          String::from("coalesced"),
+          PathBuf::new(),
+          system_time_nanos(SystemTime::now()),
+        );
+        event.count = Some(count);
+        self.deliver(event);
+      }
+    }
+  }
+
+  // Delivers the events held by the event handler right away in the order they are
+  // produced, i.e. the pending rename, coalesced modify and settled events, followed by the
+  // summary of the rate limit and the batched events.
+  fn drain(&self) {
+    let mut held: Vec<(Option<bool>, FsEvent)> = Vec::new();
+    for timer in self.timers.take() {
+      self.release(&timer, |existed, event| held.push((existed, event)));
+    }
+    held.sort_by_key(|(_, event)| event.seq);
+
+    for (existed, event) in held {
+      match existed {
+        Some(existed) => self.emit_settled(existed, event),
+        None => self.emit(event),
+      }
+    }
+    self.flush_coalesced();
+    self.flush();
+  }
+
+  // Does the task requested by the event handler on the watcher state, and delivers its
+  // events and the errors of the paths failed to be watched once the state is unlocked.
+  // Tasks of the closed watcher are dropped.
+  fn run(&self, task: StateTask) {
+    let state = match self.state.upgrade() {
+      Some(state) => state,
+      None => return,
+    };
+    let mut events = Vec::new();
+    let mut skipped = Vec::new();
+    let mut error = None;
+    let mut unwatched_all = false;
+    {
+      let mut state = state.lock().unwrap();
+      if state.watcher.is_none() {
+        return;
+      }
+      match task {
+        StateTask::Rewatch(paths) => {
+          let mut roots: Vec<PathBuf> = if paths.is_empty() {
+            state.paths.read().unwrap().iter().cloned().collect()
+          } else {
+            paths
+              .iter()
+              .filter_map(|path| state.root_of(path))
+              .collect()
+          };
+          roots.sort();
+          roots.dedup();
+          for root in roots {
+            state.rewatch(&root);
+          }
+        }
+        StateTask::Restart(reason) => match state.restart() {
+          Ok(_) => events.push(FsEvent::new(
+            String::from("restart"),
+            PathBuf::new(),
+            system_time_nanos(SystemTime::now()),
+          )),
+          Err(restart_err) => {
+            error = Some(Error::new(
+              Status::GenericFailure,
+              format!("{} (restart failed: {})", reason, restart_err.reason),
+            ))
+          }
+        },
+        StateTask::RemoveGoneRoot { root, make_pending } => {
+          if state.remove_gone_root(&root, make_pending) {
+            events.push(FsEvent::new(
+              String::from("watchedPathGone"),
+              root,
+              system_time_nanos(SystemTime::now()),
+            ));
+          }
+        }
+        StateTask::ResolvePending(trigger) => {
+          (events, skipped) = state.resolve_pending(&trigger);
+        }
+        StateTask::FollowLink { link, old_dir } => {
+          if let Some(root) = state.root_of(&link) {
+            // Previous target can be resolved only if it still exists
+            if let Some(old_dir) = old_dir.and_then(|dir| fs::canonicalize(dir).ok()) {
+              if let Some(targets) = state.extra_paths.get_mut(&root) {
+                targets.retain(|target| *target != old_dir);
+              }
+              if let Ok(watcher) = state.watcher() {
+                let _ = watcher.unwatch(&extended_path(&old_dir));
+              }
+            }
+            skipped = state.watch_symlinks(&root, vec![link]);
+          }
+        }
+        StateTask::WatchSubdirs(dir) => {
+          if let Some(root) = state.root_of(&dir) {
+            skipped = state.watch_subdirs(&root, &dir, true);
+          }
+        }
+        StateTask::UnwatchFired(root) => {
+          let _ = state.unwatch_root(&root);
+          unwatched_all = state.paths.read().unwrap().is_empty();
+        }
+      }
+    }
+    if unwatched_all {
+      close_watcher(&state, &self.this, false);
+    }
+
+    for event in events {
+      self.emit(event);
+    }
+    for (path, e) in skipped {
+      self.emit_watch_error(path, e);
+    }
+    if let Some(error) = error {
+      self.emit_error(error);
+    }
+  }
+
+  // Emits the event held for the given expired timer if it is still the latest one held
+  // for its path
+  fn expire(&self, timer: &HoldTimer) {
+    self.release(timer, |existed, event| match existed {
+      Some(existed) => self.emit_settled(existed, event),
+      None => self.emit(event),
+    });
+  }
+
+  // Takes the event held for the given timer if it is still the latest one held for its
+  // path, and passes it along with whether the path existed before if settled. The held
+  // events are locked meanwhile, so that the event handler doesn't emit a later event of
+  // the path before it.
+  fn release(&self, timer: &HoldTimer, deliver: impl FnOnce(Option<bool>, FsEvent)) {
+    match timer.hold {
+      Hold::Modify => {
+        let mut pending = self.pending_modify.lock().unwrap();
+        if pending.get(&timer.path).map(|(id, _)| *id) == Some(timer.id) {
+          let (_, event) = pending.remove(&timer.path).unwrap();
+          deliver(None, event);
+        }
+      }
+      Hold::Settle => {
+        let mut pending = self.pending_settle.lock().unwrap();
+        if pending.get(&timer.path).map(|(id, _, _)| *id) == Some(timer.id) {
+          let (_, existed, event) = pending.remove(&timer.path).unwrap();
+          deliver(Some(existed), event);
+        }
+      }
+      // Events delivered already at the leading edge are only forgotten
+      Hold::Debounce => {
+        let mut pending = self.pending_debounce.lock().unwrap();
+        if pending.get(&timer.path).map(|(id, _, _)| *id) == Some(timer.id) {
+          if let Some((_, true, event)) = pending.remove(&timer.path) {
+            deliver(None, event);
+          }
+        }
+      }
+      // Rename from event not correlated in time is emitted as is
+      Hold::Rename => {
+        let mut pending = self.pending_rename.lock().unwrap();
+        if pending.as_ref().map(|rename| rename.id) == Some(timer.id) {
+          deliver(None, pending.take().unwrap().event);
+        }
+      }
+    }
+  }
+
+  // Drops the events held by the event handler and the batched events without delivering
+  fn discard(&self) {
+    self.pending_rename.lock().unwrap().take();
+    self.timers.take();
+    self.pending_modify.lock().unwrap().clear();
+    self.pending_settle.lock().unwrap().clear();
+    self.pending_debounce.lock().unwrap().clear();
+    if let Some(batch) = &self.batch {
+      batch.lock().unwrap().clear();
+    }
+  }
+
+  // Delivers the buffered events if any
+  fn flush(&self) {
+    if let Some(batch) = &self.batch {
+      let events = std::mem::take(&mut *batch.lock().unwrap());
+      if !events.is_empty() {
+        self.call(Ok(events));
+      }
+    }
+  }
+
+  // Events are dropped while the queue is full, emitting single overflow event instead.
+  // Errors are not counted as they don't reach the completion of the javascript callback.
+  fn call(&self, value: Result<Vec<FsEvent>>) {
+    // Number of the events being delivered by this call
+    let (value, count) = match value {
+      Ok(events)
+        if self.max_queue > 0 && self.in_flight.load(Ordering::SeqCst) >= self.max_queue =>
+      {
+        self.stats.add(&self.stats.dropped, events.len());
+        if self.overflowed.swap(true, Ordering::SeqCst) {
+          return;
+        }
+        (
+          Ok(vec![FsEvent::new(
+            String::from("overflow"),
+            PathBuf::new(),
+            system_time_nanos(SystemTime::now()),
+          )]),
+          0,
+        )
+      }
+      Ok(events) => {
+        self.overflowed.store(false, Ordering::SeqCst);
+        let count = events.len();
+        (Ok(events), count)
+      }
+      Err(e) => (Err(e), 0),
+    };
+
+    let counted = value.is_ok();
+    if counted {
+      self.in_flight.fetch_add(1, Ordering::SeqCst);
+    }
+
+    // Report the deliveries failed on the full queue of the javascript callback to the
+    // error callback, as the callback itself can't receive them.
+    let status = (self.callback)(value, self.call_mode());
+    if status == Status::Ok {
+      self.stats.add(&self.stats.delivered, count);
+    } else if status == Status::QueueFull {
+      self.stats.add(&self.stats.dropped, count);
+      if counted {
+        self.in_flight.fetch_sub(1, Ordering::SeqCst);
+      }
+      if let Some(error_callback) = &self.error_callback {
+        error_callback(
+          Error::new(
+            Status::QueueFull,
+            String::from("Callback queue is full, dropping events"),
+          ),
+          ThreadsafeFunctionCallMode::NonBlocking,
+        );
+      }
+    }
+  }
+
+  fn call_mode(&self) -> ThreadsafeFunctionCallMode {
+    if self.blocking && thread::current().id() != self.js_thread {
+      ThreadsafeFunctionCallMode::Blocking
+    } else {
+      ThreadsafeFunctionCallMode::NonBlocking
+    }
+  }
+}
+
+// Kind of the events held by the event handler until their timers expire
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Hold {
+  Modify,
+  Settle,
+  Debounce,
+  Rename,
+}
+
+// Timer of the event held for the path, which is ordered by its deadline. The id is that
+// of the held event, distinguishing the successive events held for the same path. The
+// path is empty for the rename from event, as only one is held at a time.
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct HoldTimer {
+  deadline: Instant,
+  hold: Hold,
+  id: u64,
+  path: PathBuf,
+}
+
+// Pending timers of the held events of a watcher, which are expired in the order of their
+// deadlines by single timer thread rather than thread per held event
+#[derive(Default)]
+struct HoldTimers {
+  deadlines: Mutex<BinaryHeap<Reverse<HoldTimer>>>,
+  wakeup: Condvar,
+}
+
+impl HoldTimers {
+  // Schedules the timer, waking up the timer thread if it expires before the others
+  fn schedule(&self, timer: HoldTimer) {
+    let mut deadlines = self.deadlines.lock().unwrap();
+    let earliest = deadlines
+      .peek()
+      .is_none_or(|Reverse(next)| timer.deadline < next.deadline);
+    deadlines.push(Reverse(timer));
+    if earliest {
+      self.wakeup.notify_one();
+    }
+  }
+
+  // Waits until the earliest timer expires or up to the given timeout, and returns the
+  // expired timers
+  fn expired(&self, timeout: Duration) -> Vec<HoldTimer> {
+    let mut deadlines = self.deadlines.lock().unwrap();
+    let wait = deadlines.peek().map_or(timeout, |Reverse(next)| {
+      next
+        .deadline
+        .saturating_duration_since(Instant::now())
+        .min(timeout)
+    });
+    if !wait.is_zero() {
+      deadlines = self.wakeup.wait_timeout(deadlines, wait).unwrap().0;
+    }
+
+    let now = Instant::now();
+    let mut expired = Vec::new();
+    while deadlines
+      .peek()
+      .is_some_and(|Reverse(next)| next.deadline <= now)
+    {
+      expired.push(deadlines.pop().unwrap().0);
+    }
+    expired
+  }
+
+  // Takes all the pending timers regardless of their deadlines
+  fn take(&self) -> Vec<HoldTimer> {
+    std::mem::take(&mut *self.deadlines.lock().unwrap())
+      .into_iter()
+      .map(|Reverse(timer)| timer)
+      .collect()
+  }
+}
+
+// Interval at which the timer thread checks whether the watcher is dropped while no timer
+// is pending
+const TIMER_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+// Last modify event of each path waiting for the path to be quiet if coalescing, along
+// with the id of its timer
+type CoalescingEvents = Arc<Mutex<HashMap<PathBuf, (u64, FsEvent)>>>;
+
+// Last settled event of each path waiting for the path to be quiet, along with the id of
+// its timer and whether the path existed before the first of the events
+type SettlingEvents = Arc<Mutex<HashMap<PathBuf, (u64, bool, FsEvent)>>>;
+
+// Last event of the burst of duplicate events on each path waiting for the path to be
+// quiet, along with the id of its timer and whether it is held for the trailing edge
+// rather than delivered already at the leading edge
+type DebouncingEvents = Arc<Mutex<HashMap<PathBuf, (u64, bool, FsEvent)>>>;
+
+// Rename from event waiting for its rename to counterpart. The id distinguishes
+// successive pending events which may carry the same tracker cookie.
+struct PendingRename {
+  id: u64,
+  tracker: Option<usize>,
+  event: FsEvent,
+}
+
+// Tells whether the path of the event of given kind is a directory if the kind does, i.e.
+// the kinds with the Dir suffix and the ones of files only
+fn kind_is_dir(kind: &str) -> Option<bool> {
+  match kind {
+    kind if kind.ends_with("Dir") => Some(true),
+    "add" | "remove" | "modify" => Some(false),
+    _ => None,
+  }
+}
+
+// Kinds of the events about the watcher itself rather than the changes of the paths,
+// which are not filtered by the kinds option
+const LIFECYCLE_KINDS: [&str; 8] = [
+  "ready",
+  "overflow",
+  "restart",
+  "watchError",
+  "watchedPathGone",
+  "recovered",
+  "idleTimeout",
+  "coalesced",
+];
+
+// Normalizes the given path into absolute form without accessing the file system,
+// so that the path can be normalized the same way even after it is removed.
+fn normalize_path(path: &Path) -> PathBuf {
+  std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+// Options of set_filters in JSON format, which replace the same options given to watch.
+// The options not given are cleared.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FilterOptions {
+  ignore: Vec<String>,
+  include: Vec<String>,
+  kinds: Vec<String>,
+  extensions: Vec<String>,
+}
+
+// Ignore and include glob patterns, event kinds and file extensions of the event filter
+struct PatternFilter {
+  ignore: IgnoreMatcher,
+  include: GlobSet,
+  kinds: HashSet<String>,
+  extensions: HashSet<String>,
+}
+
+impl PatternFilter {
+  fn new(options: &FilterOptions) -> Result<Self> {
+    Ok(PatternFilter {
+      ignore: IgnoreMatcher::new(&options.ignore)?,
+      include: build_glob_set(&options.include)?,
+      kinds: options.kinds.iter().cloned().collect(),
+      extensions: options
+        .extensions
+        .iter()
+        .map(|ext| ext.trim_start_matches('.').to_lowercase())
+        .collect(),
+    })
+  }
+}
+
+// Event filter built from the ignore and include glob patterns, the event kinds, file
+// extensions and the dotfiles option of watch options
+struct EventFilter {
+  patterns: FilterPatterns,
+  targets: EventTargets,
+  ignore_dotfiles: bool,
+  roots: WatchedPaths,
+  path_ignores: PathGlobs,
+  file_ignores: PathGlobs,
+  gitignores: GitignoreRules,
+  path_includes: PathGlobs,
+  files: WatchedFiles,
+  prefixes: PathPrefixes,
+}
+
+impl EventFilter {
+  fn new(options: &WatchOptions, roots: WatchedPaths) -> Result<Self> {
+    let patterns = PatternFilter::new(&FilterOptions {
+      ignore: options.ignore.clone(),
+      include: options.include.clone(),
+      kinds: options.kinds.clone(),
+      extensions: options.extensions.clone(),
+    })?;
+
+    Ok(EventFilter {
+      patterns: Arc::new(RwLock::new(patterns)),
+      targets: options.targets,
+      ignore_dotfiles: options.ignore_dotfiles,
+      roots,
+      path_ignores: PathGlobs::default(),
+      file_ignores: PathGlobs::default(),
+      gitignores: GitignoreRules::default(),
+      path_includes: PathGlobs::default(),
+      files: WatchedFiles::default(),
+      prefixes: PathPrefixes::default(),
+    })
+  }
+
+  // Checks whether the events for the given path should be delivered. Ignore patterns
+  // take precedence over include patterns and an empty include list allows all paths.
+  // Whether the path is a directory is given if known from its event, which is checked
+  // against the path otherwise only if needed.
+  fn is_path_allowed(&self, path: &Path, is_dir: Option<bool>) -> bool {
+    !self.is_path_ignored(path, is_dir)
+      && self.is_path_included(path)
+      && self.is_included_under_root(path)
+      && self.is_under_prefixes(path)
+  }
+
+  // Checks whether the given path matches the include patterns. An empty include list
+  // allows all paths.
+  fn is_path_included(&self, path: &Path) -> bool {
+    let patterns = self.patterns.read().unwrap();
+    patterns.include.is_empty() || patterns.include.is_match(path)
+  }
+
+  // Checks whether the given path is under any of the prefixes set at runtime
+  fn is_under_prefixes(&self, path: &Path) -> bool {
+    let prefixes = self.prefixes.read().unwrap();
+    if prefixes.is_empty() || path.as_os_str().is_empty() {
+      return true;
+    }
+
+    let normalized = normalize_path(path);
+    prefixes.iter().any(|prefix| normalized.starts_with(prefix))
+  }
+
+  // Reason the given path is not allowed, which is either ignored by the ignore patterns
+  // or not included by the include patterns and prefixes
+  fn suppression_reason(&self, path: &Path, is_dir: Option<bool>) -> &'static str {
+    if self.is_path_ignored(path, is_dir) {
+      "ignored"
+    } else {
+      "notIncluded"
+    }
+  }
+
+  // Checks whether the given path matches the include patterns of its closest watched
+  // path if it is added with glob patterns, and is a watched file if it is located in a
+  // directory watched only for the files in it
+  fn is_included_under_root(&self, path: &Path) -> bool {
+    let path_includes = self.path_includes.read().unwrap();
+    let files = self.files.read().unwrap();
+    if (path_includes.is_empty() && files.is_empty()) || path.as_os_str().is_empty() {
+      return true;
+    }
+
+    let normalized = normalize_path(path);
+    let roots = self.roots.read().unwrap();
+    // Directory watched only for the files in it delivers the events of the files only
+    let in_dedicated = [Some(normalized.as_path()), normalized.parent()]
+      .into_iter()
+      .flatten()
+      .any(|dir| files.get(dir).is_some_and(|watch| watch.dedicated));
+    if in_dedicated && !roots.contains(&normalized) {
+      return false;
+    }
+    closest_root(&roots, &normalized)
+      .and_then(|root| path_includes.get(root))
+      .is_none_or(|include| include.is_match(&normalized))
+  }
+
+  // Checks whether the given path matches any of the ignore patterns, including the ones
+  // given for the closest watched path, or is a dotfile to be ignored.
+  fn is_path_ignored(&self, path: &Path, is_dir: Option<bool>) -> bool {
+    let is_ignored = self.patterns.read().unwrap().ignore.is_match(path);
+    is_ignored
+      || (self.ignore_dotfiles && self.is_dotfile(path))
+      || self.is_ignored_under_root(path, is_dir)
+  }
+
+  // Checks whether the entries under the given path can be skipped as it is ignored, which
+  // is not the case if any of the ignore patterns includes back the ignored paths
+  fn is_subtree_ignored(&self, path: &Path, is_dir: bool) -> bool {
+    let has_negation = self.patterns.read().unwrap().ignore.has_negation();
+    !has_negation && self.is_path_ignored(path, Some(is_dir))
+  }
+
+  // Checks whether the given path matches the ignore patterns of its closest watched path,
+  // given either when adding the path or in its ignore file, or is ignored by the
+  // .gitignore files under it
+  fn is_ignored_under_root(&self, path: &Path, is_dir: Option<bool>) -> bool {
+    let path_ignores = self.path_ignores.read().unwrap();
+    let file_ignores = self.file_ignores.read().unwrap();
+    let gitignores = self.gitignores.read().unwrap();
+    if path_ignores.is_empty() && file_ignores.is_empty() && gitignores.is_empty() {
+      return false;
+    }
+
+    let normalized = normalize_path(path);
+    let roots = self.roots.read().unwrap();
+    closest_root(&roots, &normalized).is_some_and(|root| {
+      path_ignores
+        .get(root)
+        .is_some_and(|ignore| ignore.is_match(path))
+        || file_ignores.get(root).is_some_and(|ignore| {
+          ignore.is_match(normalized.strip_prefix(root).unwrap_or(&normalized))
+        })
+        || is_gitignored(&gitignores, root, &normalized, is_dir)
+    })
+  }
+
+  // Checks whether the given path is a dotfile or located under a dot-directory. Only
+  // the components below the watched path are checked, so that watching a path under
+  // a dot-directory doesn't ignore everything.
+  fn is_dotfile(&self, path: &Path) -> bool {
+    let path = normalize_path(path);
+    let roots = self.roots.read().unwrap();
+    let relative = roots
+      .iter()
+      .filter_map(|root| path.strip_prefix(root).ok())
+      .min_by_key(|relative| relative.components().count())
+      .unwrap_or(&path);
+
+    relative.components().any(|component| match component {
+      Component::Normal(name) => name.to_string_lossy().starts_with('.'),
+      _ => false,
+    })
+  }
+
+  // Checks whether the event should be delivered based on its kind, file extension and
+  // the type of its path. Events not related to any path such as ready are always allowed.
+  fn is_event_allowed(&self, event: &FsEvent) -> bool {
+    self.is_kind_allowed(&event.kind)
+      && self.is_extension_allowed(event)
+      && self.is_target_allowed(event)
+  }
+
+  // Checks whether the event is for the type of path to be delivered. The directory is
+  // determined from the kind suffix, and from the path itself for the kinds without it.
+  fn is_target_allowed(&self, event: &FsEvent) -> bool {
+    if self.targets == EventTargets::All || event.path.as_os_str().is_empty() {
+      return true;
+    }
+
+    let is_dir = kind_is_dir(&event.kind).unwrap_or_else(|| event.path.is_dir());
+    is_dir == (self.targets == EventTargets::Dirs)
+  }
+
+  // Checks whether the events of given kind should be delivered. An empty kinds list
+  // allows all kinds. The lifecycle events of the watcher itself are always allowed.
+  fn is_kind_allowed(&self, kind: &str) -> bool {
+    let patterns = self.patterns.read().unwrap();
+    patterns.kinds.is_empty() || patterns.kinds.contains(kind) || LIFECYCLE_KINDS.contains(&kind)
+  }
+
+  // Checks whether the event is for a file having one of the extensions. An empty
+  // extensions list allows all events.
+  fn is_extension_allowed(&self, event: &FsEvent) -> bool {
+    let patterns = self.patterns.read().unwrap();
+    if patterns.extensions.is_empty() || event.path.as_os_str().is_empty() {
+      return true;
+    }
+
+    !event.kind.ends_with("Dir")
+      && event
+        .path
+        .extension()
+        .map(|ext| {
+          patterns
+            .extensions
+            .contains(&ext.to_string_lossy().to_lowercase())
+        })
+        .unwrap_or(false)
+  }
+}
+
+// Filtering dirs using glob patterns for watching can also be done by using globwalk crate.
+// But it will result in bigger output size.
+//
+// fn walkdir() -> Result<()> {
+//   let walker = globwalk::GlobWalkerBuilder::from_patterns(
+//     std::env::current_dir()?,
+//     &["node_modules", "!**/.git", "!**/node_modules", "!**/target"],
+//   )
+//   .file_type(FileType::DIR)
+//   .build()
+//   .map_err(|e| Error::new(Status::GenericFailure, format!("{}", e)))?
+//   .into_iter();
+
+//   for dir in walker {
+//     if let Ok(direntry) = dir {
+//       // watch the matched paths for fs events
+//     }
+//   }
+
+//   Ok(())
+// }
+
+/// Initiates recommended watcher instance with threadsafe callback function from
+/// node js main thread and call the callback on fs events. This function returns
+/// watcher instance which can be used to add paths to be watched for fs events.
+/// If the onError callback is given, the errors are delivered to it instead of the
+/// callback. The options are given in JSON format, and fail with the reason if they are
+/// malformed or unknown. Empty options fall back to the defaults.
+#[napi(
+  ts_args_type = "options: string, callback: (err: null | Error, event: WatchEvent | WatchEvent[] | Buffer) => void, onError?: (err: Error) => void"
+)]
+pub fn watch(
+  env: Env,
+  opts: JsString,
+  callback: JsFunction,
+  on_error: Option<JsFunction>,
+) -> Result<JsExternal> {
+  let options: WatchOptions = parse_options(opts.into_utf8()?.as_str()?)?;
+
+  // Javascript callback to be invoked for fs events. The events are passed as an array
+  // if batching is enabled, and encoded into a buffer if the format is msgpack.
+  let batched = options.batch_ms > 0;
+  let normalize_separators = options.normalize_separators;
+  let format = options.format;
+  let compat = options.compat;
+  let kind_map = options.kind_map.clone();
+  // Base directory is matched in both its absolute and canonical forms, since the event
+  // paths are reported based on the watched paths which may not be canonical.
+  let relative_bases: Vec<PathBuf> = options
+    .relative_to
+    .as_ref()
+    .map(|base| {
+      let mut bases = vec![normalize_path(Path::new(base))];
+      bases.extend(fs::canonicalize(base).ok());
+      bases.dedup();
+      bases
+    })
+    .unwrap_or_default();
+  let in_flight = Arc::new(AtomicUsize::new(0));
+  let completed = Arc::clone(&in_flight);
+  let tsfn: ThreadsafeFunction<Vec<FsEvent>, ErrorStrategy::CalleeHandled> = callback
+    .create_threadsafe_function(
+      options.tsfn_queue_size as usize,
+      move |cx: ThreadSafeCallContext<Vec<FsEvent>>| {
+        completed.fetch_sub(1, Ordering::SeqCst);
+        let mut events: Vec<WatchEvent> = cx
+          .value
+          .into_iter()
+          .map(|mut event| {
+            if !relative_bases.is_empty() {
+              event.path = relative_path(&event.path, &relative_bases);
+              event.old_path = event
+                .old_path
+                .map(|old_path| relative_path(&old_path, &relative_bases));
+            }
+            if let Some(kind) = kind_map.get(&event.kind) {
+              event.kind = kind.clone();
+            } else if let Some(compat) = compat {
+              event.kind = compat.map_kind(event.kind);
+            }
+            WatchEvent::from(event)
+          })
+          .collect();
+        if normalize_separators {
+          for event in events.iter_mut() {
+            to_forward_slashes(&mut event.path);
+            if let Some(old_path) = event.old_path.as_mut() {
+              to_forward_slashes(old_path);
+            }
+          }
+        }
+
+        // Encode the event or the batch of events into a buffer in msgpack format
+        if format == EventFormat::Msgpack {
+          let encoded = if batched {
+            rmp_serde::to_vec_named(&events)
+          } else {
+            rmp_serde::to_vec_named(&events[0])
+          }
+          .map_err(|e| Error::new(Status::GenericFailure, format!("{}", e)))?;
+
+          return Ok(vec![Either3::C(Buffer::from(encoded))]);
+        }
+
+        Ok(vec![if batched {
+          Either3::B(events)
+        } else {
+          Either3::A(events.remove(0))
+        }])
+      },
+    )?;
+
+  // Javascript callback to be invoked only with the errors of the watcher
+  let error_tsfn: Option<ThreadsafeFunction<(), ErrorStrategy::CalleeHandled>> = on_error
+    .map(|on_error| {
+      on_error.create_threadsafe_function(0, |_: ThreadSafeCallContext<()>| Ok(Vec::<()>::new()))
+    })
+    .transpose()?;
+
+  let fs_watcher = create_fs_watcher(
+    options,
+    in_flight,
+    Box::new(move |value, mode| tsfn.call(value, mode)),
+    error_tsfn.map(|error_tsfn| -> ErrorCallback {
+      Box::new(move |error, mode| error_tsfn.call(Err(error), mode))
+    }),
+  )?;
+  env.create_external(fs_watcher, None)
+}
+
+// Work on the watcher state requested by the event handler, which is done by the state
+// worker thread of the watcher as the backend can't watch or unwatch a path while
+// handling an event
+#[derive(PartialEq)]
+enum StateTask {
+  // Watch the watched paths containing the given paths again after their file handles
+  // went stale, or all the watched paths if none is given
+  Rewatch(Vec<PathBuf>),
+  // Recreate the watcher after the fatal error of the given message
+  Restart(String),
+  // Stop watching the removed watched path, making it pending if requested
+  RemoveGoneRoot {
+    root: PathBuf,
+    make_pending: bool,
+  },
+  // Resolve the pending paths once the given path under their ancestors appears
+  ResolvePending(PathBuf),
+  // Watch the target of the followed symlink, in place of the previous target if given
+  FollowLink {
+    link: PathBuf,
+    old_dir: Option<PathBuf>,
+  },
+  // Watch the sub-directories of the created directory which are to be watched manually
+  WatchSubdirs(PathBuf),
+  // Unwatch the watched path whose first event is delivered if the once option is set,
+  // closing the watcher if it is the last one
+  UnwatchFired(PathBuf),
+}
+
+// Tasks queued for the state worker along with their due times. A task is queued only
+// once while it is waiting, so that a burst of events doesn't repeat the same work.
+#[derive(Default)]
+struct StateTasks {
+  tasks: Mutex<Vec<(Instant, StateTask)>>,
+  wakeup: Condvar,
+}
+
+impl StateTasks {
+  // Queues the task to be done after the given delay unless it is waiting already
+  fn push(&self, task: StateTask, delay: Duration) {
+    let mut tasks = self.tasks.lock().unwrap();
+    if tasks.iter().all(|(_, queued)| *queued != task) {
+      tasks.push((Instant::now() + delay, task));
+      self.wakeup.notify_one();
+    }
+  }
+
+  // Waits until any task is due or up to the given timeout, and returns the due tasks in
+  // the order they were queued
+  fn due(&self, timeout: Duration) -> Vec<StateTask> {
+    let mut tasks = self.tasks.lock().unwrap();
+    let now = Instant::now();
+    let wait = tasks
+      .iter()
+      .map(|(due, _)| due.saturating_duration_since(now))
+      .fold(timeout, Duration::min);
+    if !wait.is_zero() {
+      tasks = self.wakeup.wait_timeout(tasks, wait).unwrap().0;
+    }
+
+    let now = Instant::now();
+    let (due, waiting): (Vec<_>, Vec<_>) = std::mem::take(&mut *tasks)
+      .into_iter()
+      .partition(|(due, _)| *due <= now);
+    *tasks = waiting;
+    due.into_iter().map(|(_, task)| task).collect()
+  }
+}
+
+// Event handler of the watcher instance, which converts the events of the backend and
+// passes them through the filters and the holds before delivering them
+struct EventProcessor {
+  dispatcher: Arc<Dispatcher>,
+  state: Weak<Mutex<WatcherState>>,
+  paused: Arc<AtomicBool>,
+  // Errors of the poll watcher are retried with backoff until it recovers, during which
+  // the errors after the first one are not delivered
+  polling: Arc<AtomicBool>,
+  backing_off: Arc<AtomicBool>,
+  max_backoff: Duration,
+  snapshot: ScanSnapshot,
+  file_types: FileTypes,
+  // Targets of the followed symlinks, compared with the current targets on their events
+  link_targets: LinkTargets,
+  pending: PendingPaths,
+  manual_paths: WatchedPaths,
+  // Last emitted event of each path. This ensures that the callback function will not
+  // be called for duplicate events on the same path within debounce_ms of time.
+  recent_events: HashMap<PathBuf, FsEvent>,
+  debounce_ns: u128,
+  dedup: bool,
+  dedup_kinds: Option<HashSet<String>>,
+  coalesce_modify: bool,
+  settle: bool,
+  debounce_edge: DebounceEdge,
+  // Window within which the held events are replaced by the later events of their paths,
+  // and the rename to event is correlated with the held rename from event
+  hold_window: Duration,
+  // Id of the last held event
+  hold_id: u64,
+  // Time of the last delivered event of each path for throttling the events
+  last_emitted: HashMap<PathBuf, u128>,
+  throttle_ns: u128,
+  // Tracker of the last correlated rename event, used to drop the rename both event
+  // delivered for the same rename by some backends
+  correlated_tracker: Option<usize>,
+  access: bool,
+  close_write: bool,
+  metadata: bool,
+  follow_symlinks: bool,
+  limit_depth: bool,
+  auto_restart: bool,
+  detail: bool,
+  dry_run: bool,
+  include_flags: bool,
+  watch_pending: bool,
+  watcherignore: bool,
+  respect_gitignore: bool,
+}
+
+impl EventProcessor {
+  fn handle(&mut self, ev: notify::Result<Event>) {
+    if self.paused.load(Ordering::Relaxed) {
+      return;
+    }
+
+    // Get the current timestamp for comparing the duplicate event
+    let timestamp = system_time_nanos(SystemTime::now());
+    let mut evt = match ev {
+      Ok(evt) => evt,
+      Err(e) => return self.handle_error(e, timestamp),
+    };
+
+    for path in evt.paths.iter_mut() {
+      strip_extended_prefix(path);
+    }
+    self.update_snapshot(&evt.paths);
+
+    // Rename mode and tracker cookie used for correlating the rename events
+    let rename = match evt.kind {
+      EventKind::Modify(ModifyKind::Name(mode @ (RenameMode::From | RenameMode::To)))
+        if evt.paths.len() == 1 =>
+      {
+        Some((mode, evt.tracker()))
+      }
+      _ => None,
+    };
+
+    for mut ev in self.convert(&mut evt, timestamp) {
+      self.dispatcher.stats.add(&self.dispatcher.stats.seen, 1);
+      // Raw event kind is recorded along with the decision in the dry run mode
+      if self.detail || self.dry_run {
+        ev.detail = Some(format!("{:?}", evt.kind));
+      }
+      if self.include_flags {
+        ev.flags = Some(event_flags(&evt));
+      }
+
+      self.reload_ignores(&ev.path);
+      self.check_gone_root(&ev);
+      if self.watch_pending && !self.check_pending(&ev) {
+        continue;
+      }
+      if self.follow_symlinks && ev.path.is_symlink() {
+        self.check_link_target(&ev.path, timestamp);
+      }
+      self.watch_added_dir(&ev);
+
+      // Coalesced and settled events are not checked for duplicates, as the last one of
+      // them is delivered anyway. Duplicates of the kinds not in dedup_kinds are delivered.
+      let settled =
+        self.settle && rename.is_none() && matches!(ev.kind.as_str(), "add" | "modify" | "remove");
+      let coalesced = self.coalesce_modify && !settled && ev.kind == "modify";
+      let deduped = self.dedup
+        && self
+          .dedup_kinds
+          .as_ref()
+          .is_none_or(|kinds| kinds.contains(&ev.kind));
+      let debounced = deduped
+        && self.debounce_edge != DebounceEdge::Leading
+        && !settled
+        && !coalesced
+        && rename.is_none();
+
+      let checked = deduped && !coalesced && !settled && !debounced;
+      if let Some(reason) = self.suppression(&ev, checked, timestamp) {
+        self.dispatcher.suppress(&ev, reason);
+        continue;
+      }
+      if self.debounce(&ev, debounced) || self.throttle(&ev, timestamp) {
+        continue;
+      }
+      let ev = match self
+        .hold_settled(ev, settled)
+        .and_then(|ev| self.hold_modify(ev, coalesced))
+      {
+        Some(ev) => ev,
+        None => continue,
+      };
+      match rename {
+        Some((mode, tracker)) => {
+          if let Some(ev) = self.correlate_rename(ev, mode, tracker) {
+            self.dispatcher.emit(ev);
+          }
+        }
+        None => self.dispatcher.emit(ev),
+      }
+    }
+  }
+
+  // Delivers the backend error, except for the errors recovered by the state worker and
+  // the poll errors retried with backoff
+  fn handle_error(&mut self, e: notify::Error, timestamp: u128) {
+    let tasks = &self.dispatcher.tasks;
+    match e {
+      // Stale file handles are recovered by watching the affected paths again after a
+      // delay
+      e if is_stale_handle(&e) => tasks.push(
+        StateTask::Rewatch(e.paths),
+        Duration::from_millis(STALE_REWATCH_DELAY_MS),
+      ),
+      // Consumers are notified through the restart event to re-sync
+      e if self.auto_restart && is_fatal_error(&e) => {
+        tasks.push(StateTask::Restart(e.to_string()), Duration::ZERO)
+      }
+      // Only the first error of the streak is delivered along with the watchError event,
+      // and the others are dropped until the poll watcher recovers. Only one streak is
+      // backed off at a time.
+      e if !self.max_backoff.is_zero() && self.polling.load(Ordering::Relaxed) => {
+        if self.backing_off.swap(true, Ordering::SeqCst) {
+          return;
+        }
+        let paths = e.paths.clone();
+        let path = paths.first().cloned().unwrap_or_default();
+        self
+          .dispatcher
+          .emit(FsEvent::new(String::from("watchError"), path, timestamp));
+        self.dispatcher.emit_error(watch_error(e));
+
+        let state = self.state.clone();
+        let dispatcher = Arc::downgrade(&self.dispatcher);
+        let max_backoff = self.max_backoff;
+        let backing_off = Arc::clone(&self.backing_off);
+        thread::spawn(move || back_off_polling(state, dispatcher, paths, max_backoff, backing_off));
+      }
+      e => self.dispatcher.emit_error(watch_error(e)),
+    }
+  }
+
+  // Keeps the snapshot of the poll watcher in sync with the changes reported by it
+  fn update_snapshot(&self, paths: &[PathBuf]) {
+    if let Some(entries) = self.snapshot.lock().unwrap().as_mut() {
+      for path in paths {
+        let path = normalize_path(path);
+        match fs::symlink_metadata(&path) {
+          Ok(metadata) => {
+            entries.insert(path, EntryState::new(&metadata));
+          }
+          Err(_) => {
+            // Entries under the removed directory are gone as well
+            if entries.remove(&path).is_some_and(|entry| entry.is_dir) {
+              entries.retain(|entry, _| !entry.starts_with(&path));
+            }
+          }
+        }
+      }
+    }
+  }
+
+  // Converts the notify event into FsEvent type for each of the event paths. The paths
+  // are moved out of the event into the converted events.
+  fn convert(&mut self, evt: &mut Event, timestamp: u128) -> Vec<FsEvent> {
+    let mut paths = std::mem::take(&mut evt.paths);
+    match (&evt.kind, paths.as_mut_slice()) {
+      // Rescan flag is set once the backend has dropped events, such as on the overflow of
+      // the inotify queue, which is delivered as overflow event so that consumers can do a
+      // full rescan. The event carries the directory to be rescanned if the backend tells.
+      _ if evt.flag() == Some(Flag::Rescan) => {
+        if paths.is_empty() {
+          paths.push(PathBuf::new());
+        }
+        paths
+          .into_iter()
+          .map(|path| FsEvent::new(String::from("overflow"), path, timestamp))
+          .collect()
+      }
+      // Some events may not carry any path, which are treated as other.
+      (_, []) => vec![FsEvent::new(
+        String::from("other"),
+        PathBuf::new(),
+        timestamp,
+      )],
+      // Rename both event carries the source and destination paths, which is delivered as
+      // single rename event unless it is already correlated from the rename from and to
+      // events. Rename events of other modes carrying both paths are treated the same,
+      // so that the source path is not lost.
+      (EventKind::Modify(ModifyKind::Name(_)), [from, to]) => {
+        if evt.tracker().is_some() && evt.tracker() == self.correlated_tracker {
+          return Vec::new();
+        }
+
+        // Move the cached file type to the destination path. The destination already
+        // known is replaced by the renamed file, which is reported as modify event.
+        let mut file_types = self.file_types.lock().unwrap();
+        let replaced = file_types.get(to) == Some(false);
+        if let Some(is_dir) = file_types.remove(from) {
+          cache_file_type(&mut file_types, to, is_dir);
+        }
+
+        let kind = if replaced { "modify" } else { "rename" };
+        let mut ev = FsEvent::new(String::from(kind), std::mem::take(to), timestamp);
+        ev.old_path = Some(std::mem::take(from));
+        ev.atomic = replaced;
+        vec![ev]
+      }
+      (kind, _) => paths
+        .into_iter()
+        .map(|path| {
+          let mut file_types = self.file_types.lock().unwrap();
+          // Rename to event on the file already known replaces it
+          let replaced = matches!(kind, EventKind::Modify(ModifyKind::Name(RenameMode::To)))
+            && file_types.get(&path) == Some(false);
+          let kind = event_kind_name(
+            kind,
+            &path,
+            || resolve_is_dir(&mut file_types, kind, &path),
+            self.access,
+            self.metadata,
+            self.close_write,
+          );
+          // File modified is known to exist before its later replacement
+          if kind == "modify" && !file_types.contains_key(&path) {
+            cache_file_type(&mut file_types, &path, false);
+          }
+          drop(file_types);
+          let mut ev = FsEvent::new(String::from(kind), path, timestamp);
+          ev.atomic = replaced;
+          ev
+        })
+        .collect(),
+    }
+  }
+
+  // Reloads the patterns once the ignore file of a watched path changes, and the rules
+  // once a .gitignore file under the watched paths changes
+  fn reload_ignores(&self, path: &Path) {
+    let filter = &self.dispatcher.filter;
+    if self.watcherignore && path.file_name() == Some(IGNORE_FILE_NAME.as_ref()) {
+      if let Some(root) = path.parent().map(normalize_path) {
+        if filter.roots.read().unwrap().contains(&root) {
+          match read_ignore_file(&root) {
+            Ok(Some(ignore)) => {
+              filter.file_ignores.write().unwrap().insert(root, ignore);
+            }
+            Ok(None) => {
+              filter.file_ignores.write().unwrap().remove(&root);
+            }
+            Err(e) => self.dispatcher.emit_error(e),
+          }
+        }
+      }
+    }
+    if self.respect_gitignore && path.file_name() == Some(GITIGNORE_FILE_NAME.as_ref()) {
+      if let Some(dir) = path.parent().map(normalize_path) {
+        if closest_root(&filter.roots.read().unwrap(), &dir).is_some() {
+          filter.gitignores.write().unwrap().reload(&dir);
+        }
+      }
+    }
+  }
+
+  // Watched path removed from under the watcher is notified through the watchedPathGone
+  // event after its remove event, and it is watched again once recreated if the
+  // watch_pending option is set.
+  fn check_gone_root(&self, ev: &FsEvent) {
+    if ev.kind.starts_with("remove") {
+      let root = normalize_path(&ev.path);
+      if self.dispatcher.filter.roots.read().unwrap().contains(&root) {
+        let make_pending = self.watch_pending;
+        let task = StateTask::RemoveGoneRoot { root, make_pending };
+        self.dispatcher.tasks.push(task, Duration::ZERO);
+      }
+    }
+  }
+
+  // Events under the ancestors of the pending paths are delivered only for the pending
+  // paths themselves, and the pending paths are resolved once any of them or their
+  // parents appear. Returns false if the event is suppressed.
+  fn check_pending(&self, ev: &FsEvent) -> bool {
+    let path = normalize_path(&ev.path);
+    let pending_paths = self.pending.lock().unwrap();
+    let is_pending_area = pending_paths
+      .values()
+      .any(|watch| path.starts_with(&watch.ancestor))
+      && closest_root(&self.dispatcher.filter.roots.read().unwrap(), &path).is_none();
+    if !is_pending_area {
+      return true;
+    }
+
+    if pending_paths.keys().any(|target| target.starts_with(&path)) && path.exists() {
+      self
+        .dispatcher
+        .tasks
+        .push(StateTask::ResolvePending(path.clone()), Duration::ZERO);
+    }
+    if !pending_paths.contains_key(&path) {
+      self.dispatcher.suppress(ev, "pending");
+      return false;
+    }
+    true
+  }
+
+  // Emits symlink change event if the followed symlink points to another target, and
+  // watches the new target in place of the previous one.
+  fn check_link_target(&self, link: &Path, timestamp: u128) {
+    let target = match fs::read_link(link) {
+      Ok(target) => target,
+      Err(_) => return,
+    };
+    let old_target = self
+      .link_targets
+      .lock()
+      .unwrap()
+      .insert(link.to_path_buf(), target.clone());
+
+    if let Some(old_target) = old_target.filter(|old_target| *old_target != target) {
+      let old_dir = link.parent().map(|parent| parent.join(&old_target));
+      let mut change = FsEvent::new(String::from("symlinkChange"), link.to_path_buf(), timestamp);
+      change.target = Some(target);
+      change.old_target = Some(old_target);
+      if self
+        .dispatcher
+        .filter
+        .is_path_allowed(&change.path, kind_is_dir(&change.kind))
+      {
+        self.dispatcher.emit(change);
+      }
+
+      let link = link.to_path_buf();
+      self
+        .dispatcher
+        .tasks
+        .push(StateTask::FollowLink { link, old_dir }, Duration::ZERO);
+    }
+  }
+
+  // Watches the newly created directory if it is a symlink to be followed or within the
+  // max depth
+  fn watch_added_dir(&self, ev: &FsEvent) {
+    let watch_manually = self.limit_depth || !self.manual_paths.read().unwrap().is_empty();
+    if ev.kind != "addDir" || !(self.follow_symlinks || watch_manually) {
+      return;
+    }
+    let task = if !ev.path.is_symlink() {
+      StateTask::WatchSubdirs(ev.path.clone())
+    } else if self.follow_symlinks {
+      StateTask::FollowLink {
+        link: ev.path.clone(),
+        old_dir: None,
+      }
+    } else {
+      return;
+    };
+    self.dispatcher.tasks.push(task, Duration::ZERO);
+  }
+
+  // Returns the reason the event is suppressed for, if it is other, filtered out by path,
+  // or a duplicate of the previous event on the same path when checked for duplicates.
+  // The delivered event is recorded as the previous event of its path.
+  fn suppression(
+    &mut self,
+    ev: &FsEvent,
+    check_duplicate: bool,
+    timestamp: u128,
+  ) -> Option<&'static str> {
+    let filter = &self.dispatcher.filter;
+    if self.dedup && ev.kind == "other" {
+      return Some("other");
+    }
+    if !filter.is_path_allowed(&ev.path, kind_is_dir(&ev.kind)) {
+      return Some(filter.suppression_reason(&ev.path, kind_is_dir(&ev.kind)));
+    }
+    if check_duplicate
+      && self
+        .recent_events
+        .get(&ev.path)
+        .is_some_and(|prev| ev.is_duplicate(prev, self.debounce_ns))
+    {
+      return Some("duplicate");
+    }
+
+    // Drop the events past the debounce window to keep the map bounded
+    if self.recent_events.len() >= MAX_RECENT_EVENTS {
+      let debounce_ns = self.debounce_ns;
+      self
+        .recent_events
+        .retain(|_, prev| timestamp < prev.ts + debounce_ns);
+    }
+    self.recent_events.insert(ev.path.clone(), ev.clone());
+    None
+  }
+
+  // Holds the debounced event replacing the previous one of the burst, and delivers it if
+  // no other event of the path arrives within the window. The first event of the burst is
+  // delivered right away on the both edge. Returns true if the event is held.
+  fn debounce(&mut self, ev: &FsEvent, debounced: bool) -> bool {
+    if self.debounce_edge == DebounceEdge::Leading {
+      return false;
+    }
+    let mut pending = self.dispatcher.pending_debounce.lock().unwrap();
+
+    // Event of other kind ends the burst of the path, whose held event is delivered first
+    let burst = match pending.get(&ev.path) {
+      Some((_, _, prev)) if debounced && prev.kind == ev.kind => true,
+      Some(_) => {
+        if let Some((_, true, prev)) = pending.remove(&ev.path) {
+          self.dispatcher.emit(prev);
+        }
+        false
+      }
+      None => false,
+    };
+    if !debounced {
+      return false;
+    }
+
+    self.hold_id += 1;
+    let held = burst || self.debounce_edge == DebounceEdge::Trailing;
+    if let Some((_, true, prev)) = pending.insert(ev.path.clone(), (self.hold_id, held, ev.clone()))
+    {
+      self.dispatcher.suppress(&prev, "debounced");
+    }
+    self.schedule(Hold::Debounce, ev.path.clone());
+    held
+  }
+
+  // Drops the event if another event of the same path was delivered within the throttle
+  // interval. Returns true if the event is dropped.
+  fn throttle(&mut self, ev: &FsEvent, timestamp: u128) -> bool {
+    if self.throttle_ns == 0 {
+      return false;
+    }
+    if self
+      .last_emitted
+      .get(&ev.path)
+      .is_some_and(|last| timestamp < last + self.throttle_ns)
+    {
+      self.dispatcher.suppress(ev, "throttled");
+      return true;
+    }
+    if self.last_emitted.len() >= MAX_RECENT_EVENTS {
+      let throttle_ns = self.throttle_ns;
+      self
+        .last_emitted
+        .retain(|_, last| timestamp < *last + throttle_ns);
+    }
+    self.last_emitted.insert(ev.path.clone(), timestamp);
+    false
+  }
+
+  // Holds the settled event replacing the previous one of the path, and emits the event of
+  // their net effect if no other event of the path arrives within the window. The pending
+  // settled event of the path is emitted before any other event of it. Returns the event
+  // unless it is held.
+  fn hold_settled(&mut self, ev: FsEvent, settled: bool) -> Option<FsEvent> {
+    if !self.settle {
+      return Some(ev);
+    }
+    let mut pending = self.dispatcher.pending_settle.lock().unwrap();
+    if !settled {
+      if let Some((_, existed, prev)) = pending.remove(&ev.path) {
+        self.dispatcher.emit_settled(existed, prev);
+      }
+      return Some(ev);
+    }
+
+    self.hold_id += 1;
+    let existed = match pending.remove(&ev.path) {
+      Some((_, existed, prev)) => {
+        self.dispatcher.suppress(&prev, "settled");
+        existed
+      }
+      None => ev.kind != "add",
+    };
+    let path = ev.path.clone();
+    pending.insert(path.clone(), (self.hold_id, existed, ev));
+    self.schedule(Hold::Settle, path);
+    None
+  }
+
+  // Holds the modify event replacing the previous one of the path, and emits it if no
+  // other modify event of the path arrives within the window. The pending modify event of
+  // the path is emitted before any other event of it. Returns the event unless it is held.
+  fn hold_modify(&mut self, ev: FsEvent, coalesced: bool) -> Option<FsEvent> {
+    if !self.coalesce_modify {
+      return Some(ev);
+    }
+    let mut pending = self.dispatcher.pending_modify.lock().unwrap();
+    if !coalesced {
+      if let Some((_, prev)) = pending.remove(&ev.path) {
+        self.dispatcher.emit(prev);
+      }
+      return Some(ev);
+    }
+
+    self.hold_id += 1;
+    let path = ev.path.clone();
+    if let Some((_, prev)) = pending.insert(path.clone(), (self.hold_id, ev)) {
+      self.dispatcher.suppress(&prev, "coalesced");
+    }
+    self.schedule(Hold::Modify, path);
+    None
+  }
+
+  // Holds the rename from event and emits it as remove event if the rename to event
+  // doesn't arrive in time, or merges the held rename from event with the rename to event
+  // having the same tracker into single rename event. Returns the event to be delivered.
+  fn correlate_rename(
+    &mut self,
+    mut ev: FsEvent,
+    mode: RenameMode,
+    tracker: Option<usize>,
+  ) -> Option<FsEvent> {
+    let mut pending = self.dispatcher.pending_rename.lock().unwrap();
+    match mode {
+      RenameMode::From => {
+        // Emit the previous pending event as it is not going to be correlated.
+        if let Some(prev) = pending.take() {
+          self.dispatcher.emit(prev.event);
+        }
+        self.hold_id += 1;
+        *pending = Some(PendingRename {
+          id: self.hold_id,
+          tracker,
+          event: ev,
+        });
+        drop(pending);
+        self.schedule(Hold::Rename, PathBuf::new());
+        None
+      }
+      _ => {
+        if pending.as_ref().map(|p| p.tracker) == Some(tracker) {
+          let prev = pending.take().unwrap();
+          ev.kind = String::from("rename");
+          ev.old_path = Some(prev.event.path);
+          self.correlated_tracker = tracker;
+        }
+        // Renaming over the existing file is reported as modify event of that file
+        if ev.atomic {
+          ev.kind = String::from("modify");
+        }
+        Some(ev)
+      }
+    }
+  }
+
+  // Schedules the timer of the event held last for the path
+  fn schedule(&self, hold: Hold, path: PathBuf) {
+    self.dispatcher.timers.schedule(HoldTimer {
+      deadline: Instant::now() + self.hold_window,
+      hold,
+      id: self.hold_id,
+      path,
+    });
+  }
+}
+
+// Creates the watcher instance delivering the events and the errors to the given
+// callbacks, which are the javascript callbacks of watch. The in_flight counter is
+// decremented by the callback once it handles the events.
+fn create_fs_watcher(
+  options: WatchOptions,
+  in_flight: Arc<AtomicUsize>,
+  callback: EventCallback,
+  error_callback: Option<ErrorCallback>,
+) -> Result<FsWatcher> {
+  let paths = WatchedPaths::default();
+
+  // Events for paths and kinds not allowed by this filter will be dropped
+  let filter = EventFilter::new(&options, Arc::clone(&paths))?;
+  let batched = options.batch_ms > 0;
+  let stats = Arc::new(EventStats::default());
+
+  // Processes are traced without failing the watcher if the tracing is not permitted,
+  // which is noted once with the watchError event
+  let (pid_tracer, trace_error) = match options.trace_pid.then(PidTracer::new) {
+    Some(Ok(tracer)) => (Some(tracer), false),
+    Some(Err(_)) => (None, true),
+    None => (None, false),
+  };
+
+  let link_targets = LinkTargets::default();
+  let pending = PendingPaths::default();
+  let manual_paths = WatchedPaths::default();
+  let file_types: FileTypes = Arc::new(Mutex::new(FileTypeCache::new(Arc::clone(&paths))));
+
+  let polling = Arc::new(AtomicBool::new(false));
+
+  // Watcher is set into the state once it is created with this event handler
+  let state = Arc::new(Mutex::new(WatcherState {
+    watcher: None,
+    handler: None,
+    config: options.backend_config()?,
+    backend: WatcherKind::NullWatcher,
+    polling: Arc::clone(&polling),
+    shared: options.shared,
+    recursive_mode: if options.recursive {
+      RecursiveMode::Recursive
+    } else {
+      RecursiveMode::NonRecursive
+    },
+    max_depth: options
+      .max_depth
+      .filter(|_| options.recursive)
+      .map(|max_depth| max_depth as usize),
+    paths,
+    extra_paths: HashMap::new(),
+    modes: HashMap::new(),
+    link_targets: Arc::clone(&link_targets),
+    pending: Arc::clone(&pending),
+    manual_paths: Arc::clone(&manual_paths),
+    path_ignores: Arc::clone(&filter.path_ignores),
+    file_ignores: Arc::clone(&filter.file_ignores),
+    gitignores: Arc::clone(&filter.gitignores),
+    path_includes: Arc::clone(&filter.path_includes),
+    globs: HashMap::new(),
+    files: Arc::clone(&filter.files),
+    file_types: Arc::clone(&file_types),
+    plain_modes: HashMap::new(),
+    watch_counts: HashMap::new(),
+    max_watches: options.max_watches as usize,
+  }));
+  let dispatcher = Arc::new_cyclic(|this| Dispatcher {
+    callback,
+    error_callback,
+    batch: batched.then(|| Mutex::new(Vec::new())),
+    filter,
+    in_flight,
+    max_queue: options.max_queue as usize,
+    overflowed: AtomicBool::new(false),
+    blocking: options.blocking,
+    js_thread: thread::current().id(),
+    canonicalize: options.canonicalize,
+    include_size: options.include_size,
+    include_file_id: options.include_file_id,
+    once: options.once.then(Default::default),
+    state: Arc::downgrade(&state),
+    this: this.clone(),
+    last_event: Mutex::new(Instant::now()),
+    rate_limit: (options.max_events_per_second > 0)
+      .then(|| RateLimit::new(options.max_events_per_second)),
+    timers: Arc::default(),
+    tasks: Arc::default(),
+    pending_modify: CoalescingEvents::default(),
+    pending_settle: SettlingEvents::default(),
+    pending_debounce: DebouncingEvents::default(),
+    pending_rename: Arc::new(Mutex::new(None)),
+    pid_tracer,
+    debug_log: options.dry_run.then(Default::default),
+    stats: Arc::clone(&stats),
+    streams: Mutex::new(Vec::new()),
+  });
+  if trace_error {
+    dispatcher.emit(FsEvent::new(
+      String::from("watchError"),
+      PathBuf::new(),
+      system_time_nanos(SystemTime::now()),
+    ));
+  }
+
+  // Flush the buffered events periodically until the watcher is dropped
+  if batched {
+    let batch_interval = Duration::from_millis(options.batch_ms);
+    let dispatcher = Arc::downgrade(&dispatcher);
+
+    thread::spawn(move || loop {
+      thread::sleep(batch_interval);
+
+      match dispatcher.upgrade() {
+        Some(dispatcher) => dispatcher.flush(),
+        None => break,
+      }
+    });
+  }
+  // Deliver the summary of the events dropped by the rate limit every second until the
+  // watcher is dropped
+  if options.max_events_per_second > 0 {
+    let dispatcher = Arc::downgrade(&dispatcher);
+
+    thread::spawn(move || loop {
+      thread::sleep(Duration::from_secs(1));
+
+      match dispatcher.upgrade() {
+        Some(dispatcher) => dispatcher.flush_coalesced(),
+        None => break,
+      }
+    });
+  }
+  // Expire the held events on their timers until the watcher is dropped
+  {
+    let timers = Arc::clone(&dispatcher.timers);
+    let dispatcher = Arc::downgrade(&dispatcher);
+
+    thread::spawn(move || loop {
+      let expired = timers.expired(TIMER_CHECK_INTERVAL);
+
+      match dispatcher.upgrade() {
+        Some(dispatcher) => expired.iter().for_each(|timer| dispatcher.expire(timer)),
+        None => break,
+      }
+    });
+  }
+  // Do the work requested by the event handler on the watcher state until the watcher is
+  // dropped
+  {
+    let tasks = Arc::clone(&dispatcher.tasks);
+    let dispatcher = Arc::downgrade(&dispatcher);
+
+    thread::spawn(move || loop {
+      let due = tasks.due(TIMER_CHECK_INTERVAL);
+
+      match dispatcher.upgrade() {
+        Some(dispatcher) => due.into_iter().for_each(|task| dispatcher.run(task)),
+        None => break,
+      }
+    });
+  }
+  let weak_dispatcher = Arc::downgrade(&dispatcher);
+  let prefixes = Arc::clone(&dispatcher.filter.prefixes);
+  let patterns = Arc::clone(&dispatcher.filter.patterns);
+
+  // Close the watcher if no event is delivered within the idle timeout, which is notified
+  // through the idleTimeout event. The timer ends once the watcher is closed.
+  if options.idle_timeout_ms > 0 {
+    let idle_timeout = Duration::from_millis(options.idle_timeout_ms);
+    let state = Arc::downgrade(&state);
+    let dispatcher = Arc::downgrade(&dispatcher);
+
+    thread::spawn(move || loop {
+      let idle = match dispatcher.upgrade() {
+        Some(dispatcher) => dispatcher.last_event.lock().unwrap().elapsed(),
+        None => break,
+      };
+      if idle < idle_timeout {
+        thread::sleep(idle_timeout - idle);
+        continue;
+      }
+
+      if let (Some(state), Some(strong_dispatcher)) = (state.upgrade(), dispatcher.upgrade()) {
+        if state.lock().unwrap().watcher().is_ok() {
+          close_watcher(&state, &dispatcher, false);
+          strong_dispatcher.call(Ok(vec![FsEvent::new(
+            String::from("idleTimeout"),
+            PathBuf::new(),
+            system_time_nanos(SystemTime::now()),
+          )]));
+        }
+      }
+      break;
+    });
+  }
+
+  // Events are dropped while the watcher is paused
+  let paused = Arc::new(AtomicBool::new(false));
+  let snapshot = ScanSnapshot::default();
+  let follow_symlinks = options.follow_symlinks;
+  let watch_pending = options.watch_pending;
+
+  let mut event_handler = EventProcessor {
+    dispatcher,
+    state: Arc::downgrade(&state),
+    paused: Arc::clone(&paused),
+    polling,
+    backing_off: Arc::new(AtomicBool::new(false)),
+    max_backoff: Duration::from_millis(options.max_backoff_ms),
+    snapshot: Arc::clone(&snapshot),
+    file_types: Arc::clone(&file_types),
+    link_targets,
+    pending,
+    manual_paths,
+    recent_events: HashMap::new(),
+    debounce_ns: u128::from(options.debounce_ms) * 1_000_000,
+    dedup: options.dedup,
+    dedup_kinds: options
+      .dedup_kinds
+      .as_ref()
+      .map(|kinds| kinds.iter().cloned().collect()),
+    coalesce_modify: options.coalesce_modify,
+    settle: options.settle,
+    debounce_edge: options.debounce_edge,
+    hold_window: Duration::from_millis(options.debounce_ms),
+    hold_id: 0,
+    last_emitted: HashMap::new(),
+    throttle_ns: u128::from(options.throttle_ms) * 1_000_000,
+    correlated_tracker: None,
+    access: options.access,
+    close_write: options.close_write,
+    metadata: options.metadata,
+    follow_symlinks,
+    limit_depth: options.recursive && options.max_depth.is_some(),
+    auto_restart: options.auto_restart,
+    detail: options.detail,
+    dry_run: options.dry_run,
+    include_flags: options.include_flags,
+    watch_pending,
+    watcherignore: options.watcherignore,
+    respect_gitignore: options.respect_gitignore,
+  };
+
+  // Creates dynamic watcher with javascript callback as an event handler. If the use_polling
+  // option is true, creates poll watcher instance else recommended watcher.
+  {
+    let mut state = state.lock().unwrap();
+    state.handler = Some(Arc::new(Mutex::new(move |ev| event_handler.handle(ev))));
+    state.set_backend(options.use_polling)?;
+  }
+
+  Ok(FsWatcher {
+    state,
+    paused,
+    dispatcher: weak_dispatcher,
+    file_types,
+    stats,
+    auto_detect_network: options.auto_detect_network && !options.use_polling,
+    network_probe: is_network_fs,
+    emit_initial: options.emit_initial,
+    follow_symlinks,
+    watch_pending,
+    watcherignore: options.watcherignore,
+    respect_gitignore: options.respect_gitignore,
+    absolute_paths: options.absolute_paths,
+    snapshot,
+    prefixes,
+    patterns,
+  })
+}
+
+/// This function creates the watcher instance the same way as watchWithId, and registers
+/// it with a token which can be passed to abort for closing the watcher. The token is the
+/// id of the watcher, which keeps the watcher alive until it is aborted or closed. Returns
+/// the watcher instance along with the token.
+#[napi(
+  ts_args_type = "options: string, callback: (err: null | Error, event: WatchEvent | WatchEvent[] | Buffer) => void, onError?: (err: Error) => void",
+  ts_return_type = "{ watcher: JsExternal; token: number }"
+)]
+pub fn watch_with_token(
+  env: Env,
+  opts: JsString,
+  callback: JsFunction,
+  on_error: Option<JsFunction>,
+) -> Result<JsObject> {
+  let mut result = watch_with_id(env, opts, callback, on_error)?;
+  let id: JsNumber = result.get_named_property("id")?;
+  result.set_named_property("token", id)?;
+  result.delete_named_property("id")?;
+  Ok(result)
+}
+
+/// This function closes the watcher instance registered with the given token the same
+/// way as closeById. Fails if no watcher is registered with the token.
+#[napi]
+pub fn abort(env: Env, token: u32) -> Result<JsUndefined> {
+  close_by_id(env, token)
+}
+
+/// This function creates the watcher instance the same way as watch, and registers it
+/// with a numeric id which can be passed to the functions suffixed with ById instead of
+/// the watcher instance. The registry keeps the watcher alive until it is closed, which
+/// invalidates the id. Returns the watcher instance along with the id.
+#[napi(
+  ts_args_type = "options: string, callback: (err: null | Error, event: WatchEvent | WatchEvent[] | Buffer) => void, onError?: (err: Error) => void",
+  ts_return_type = "{watcher: JsExternal; id: number}"
+)]
+pub fn watch_with_id(
+  env: Env,
+  opts: JsString,
+  callback: JsFunction,
+  on_error: Option<JsFunction>,
+) -> Result<JsObject> {
+  let ext = watch(env, opts, callback, on_error)?;
+  let id = register_watcher(env.get_value_external::<FsWatcher>(&ext)?);
+
+  let mut result = env.create_object()?;
+  result.set_named_property("watcher", ext)?;
+  result.set_named_property("id", env.create_uint32(id)?)?;
+  Ok(result)
+}
+
+// Registers the watcher instance with a new id, which keeps it alive until closed
+fn register_watcher(fs_watcher: &FsWatcher) -> u32 {
+  let id = NEXT_WATCHER_ID.fetch_add(1, Ordering::Relaxed);
+  WATCHERS.lock().unwrap().insert(id, fs_watcher.clone());
+  id
+}
+
+// Returns the watcher instance registered with the id, sharing the instance with the
+// handle returned by watch_with_id. Fails if no watcher is registered with the id or the
+// watcher is closed, in which case the id is dropped from the registry.
+fn registered_watcher(id: u32) -> Result<FsWatcher> {
+  let mut watchers = WATCHERS.lock().unwrap();
+  match watchers.get(&id) {
+    Some(fs_watcher) if fs_watcher.state().watcher.is_some() => Ok(fs_watcher.clone()),
+    _ => {
+      watchers.remove(&id);
+      Err(Error::new(
+        Status::InvalidArg,
+        format!("No watcher is registered with id {}", id),
+      ))
+    }
+  }
+}
+
+// Creates a new handle of the watcher instance registered with the id
+fn watcher_by_id(env: Env, id: u32) -> Result<JsExternal> {
+  env.create_external(registered_watcher(id)?, None)
+}
+
+/// This function adds the path to the watcher registered with the given id the same way
+/// as add. Fails if no watcher is registered with the id.
+#[napi]
+pub fn add_by_id(env: Env, id: u32, dir: Either<JsString, Buffer>) -> Result<JsUndefined> {
+  add(env, watcher_by_id(env, id)?, dir)
+}
+
+/// This function unwatches the path of the watcher registered with the given id the same
+/// way as unwatch. Fails if no watcher is registered with the id.
+#[napi]
+pub fn unwatch_by_id(env: Env, id: u32, dir: Either<JsString, Buffer>) -> Result<JsUndefined> {
+  unwatch(env, watcher_by_id(env, id)?, dir)
+}
+
+/// This function unwatches all the paths of the watcher registered with the given id the
+/// same way as unwatchAll. Fails if no watcher is registered with the id.
+#[napi]
+pub fn unwatch_all_by_id(env: Env, id: u32) -> Result<JsUndefined> {
+  unwatch_all(env, watcher_by_id(env, id)?)
+}
+
+/// This function closes the watcher registered with the given id the same way as close,
+/// which invalidates the id. Fails if no watcher is registered with the id.
+#[napi]
+pub fn close_by_id(env: Env, id: u32) -> Result<JsUndefined> {
+  close(env, watcher_by_id(env, id)?, None)
+}
+
+/// This function returns the list of paths watched by the watcher registered with the
+/// given id the same way as list. Fails if no watcher is registered with the id.
+#[napi]
+pub fn list_by_id(env: Env, id: u32) -> Result<Vec<Either<String, Buffer>>> {
+  list(env, watcher_by_id(env, id)?)
+}
+
+/// This function checks whether the given path is being watched by the watcher registered
+/// with the given id the same way as isWatched. Fails if no watcher is registered with
+/// the id.
+#[napi]
+pub fn is_watched_by_id(env: Env, id: u32, dir: Either<JsString, Buffer>) -> Result<bool> {
+  is_watched(env, watcher_by_id(env, id)?, dir)
+}
+
+/// This function pauses the watcher registered with the given id the same way as pause.
+/// Fails if no watcher is registered with the id.
+#[napi]
+pub fn pause_by_id(env: Env, id: u32) -> Result<JsUndefined> {
+  pause(env, watcher_by_id(env, id)?)
+}
+
+/// This function resumes the watcher registered with the given id the same way as resume.
+/// Fails if no watcher is registered with the id.
+#[napi]
+pub fn resume_by_id(env: Env, id: u32) -> Result<JsUndefined> {
+  resume(env, watcher_by_id(env, id)?)
+}
+
+/// This function returns the statistics of the watcher registered with the given id the
+/// same way as stats. Fails if no watcher is registered with the id.
+#[napi]
+pub fn stats_by_id(env: Env, id: u32) -> Result<WatcherStats> {
+  stats(env, watcher_by_id(env, id)?)
+}
+
+/// This function takes in watcher instance and a path to be watched for events.
+/// The path is watched recursively if the watcher was created with recursive option.
+/// The ready event is emitted once the path is watched. The path can be given as a
+/// buffer of raw bytes if it is not valid UTF-8.
+#[napi]
+pub fn add(env: Env, ext: JsExternal, dir: Either<JsString, Buffer>) -> Result<JsUndefined> {
+  let dir = js_path(dir)?;
+  let fs_watcher = env.get_value_external::<FsWatcher>(&ext)?;
+
+  fs_watcher.add_path(&dir, None)?;
+  fs_watcher.emit_ready();
+  env.get_undefined()
+}
+
+/// This function takes in watcher instance and a path to be watched for events the
+/// same way as add, except that the path is watched recursively or not as given
+/// regardless of the recursive option of the watcher.
+#[napi]
+pub fn add_recursive(
+  env: Env,
+  ext: JsExternal,
+  dir: Either<JsString, Buffer>,
+  recursive: bool,
+) -> Result<JsUndefined> {
+  let dir = js_path(dir)?;
+  let fs_watcher = env.get_value_external::<FsWatcher>(&ext)?;
+  let recursive_mode = if recursive {
+    RecursiveMode::Recursive
+  } else {
+    RecursiveMode::NonRecursive
+  };
+
+  fs_watcher.add_path(&dir, Some(recursive_mode))?;
+  fs_watcher.emit_ready();
+  env.get_undefined()
+}
+
+/// This function takes in watcher instance and a list of paths to be watched for events.
+/// All the paths are attempted and the paths that failed to be watched are reported
+/// together in a single error, while the others remain watched. The ready event is
+/// emitted once if any of the paths is watched.
+#[napi]
+pub fn add_all(
+  env: Env,
+  ext: JsExternal,
+  dirs: Vec<Either<JsString, Buffer>>,
+) -> Result<JsUndefined> {
+  let fs_watcher = env.get_value_external::<FsWatcher>(&ext)?;
+  fs_watcher.state().watcher()?;
+
+  let dirs = dirs.into_iter().map(js_path).collect::<Result<Vec<_>>>()?;
+  fs_watcher.add_paths(&dirs)?;
+  env.get_undefined()
+}
+
+/// Path to be watched by addWithOptions along with its own settings
+#[napi(object)]
+pub struct PathEntry {
+  pub path: String,
+  /// Whether the path is watched recursively regardless of the recursive option
+  pub recursive: bool,
+  /// Glob patterns of the paths to be ignored under this path in addition to the ignore
+  /// option
+  pub ignore: Option<Vec<String>>,
+}
+
+/// This function takes in watcher instance and a list of paths to be watched for events,
+/// each with its own recursive flag and ignore patterns. All the paths are attempted the
+/// same way as addAll, and the paths that failed to be watched are reported together in
+/// a single error.
+#[napi]
+pub fn add_with_options(env: Env, ext: JsExternal, entries: Vec<PathEntry>) -> Result<JsUndefined> {
+  let fs_watcher = env.get_value_external::<FsWatcher>(&ext)?;
+  fs_watcher.state().watcher()?;
+
+  let failures: Vec<String> = entries
+    .iter()
+    .filter_map(|entry| {
+      let recursive_mode = if entry.recursive {
+        RecursiveMode::Recursive
+      } else {
+        RecursiveMode::NonRecursive
+      };
+      build_glob_set(entry.ignore.as_deref().unwrap_or_default())
+        .and_then(|ignore| fs_watcher.add_path_with_ignore(&entry.path, recursive_mode, ignore))
+        .err()
+        .map(|e| format!("{}: {}", entry.path, e.reason))
+    })
+    .collect();
+
+  if failures.len() < entries.len() {
+    fs_watcher.emit_ready();
+  }
+  if !failures.is_empty() {
+    return Err(Error::new(
+      Status::GenericFailure,
+      format!("Failed to watch paths: {}", failures.join(", ")),
+    ));
+  }
+  env.get_undefined()
+}
+
+// Options of add_glob in JSON format
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct GlobOptions {
+  // Whether the pattern is matched case insensitively
+  case_insensitive: bool,
+}
+
+// Splits the glob pattern into its leading components without glob characters and the
+// rest of the components
+fn split_glob(pattern: &Path) -> (PathBuf, PathBuf) {
+  let mut base = PathBuf::new();
+  let mut rest = PathBuf::new();
+  for component in pattern.components() {
+    let has_glob = component
+      .as_os_str()
+      .to_string_lossy()
+      .contains(['*', '?', '[', '{']);
+    if rest.as_os_str().is_empty() && !has_glob {
+      base.push(component);
+    } else {
+      rest.push(component);
+    }
+  }
+  (base, rest)
+}
+
+/// This function takes in watcher instance and a glob pattern such as src/*.rs, and
+/// watches the base directory of the pattern delivering only the events of the matching
+/// paths, including the ones created later. The options are given in JSON format, where
+/// the case_insensitive option matches the pattern case insensitively.
+#[napi]
+pub fn add_glob(
+  env: Env,
+  ext: JsExternal,
+  pattern: JsString,
+  opts: JsString,
+) -> Result<JsUndefined> {
+  let pattern = pattern.into_utf8()?;
+  let options: GlobOptions = parse_options(opts.into_utf8()?.as_str()?)?;
+  let fs_watcher = env.get_value_external::<FsWatcher>(&ext)?;
+
+  fs_watcher.add_glob(Path::new(pattern.as_str()?), options.case_insensitive)?;
+  fs_watcher.emit_ready();
+  env.get_undefined()
+}
+
+/// This function takes in watcher instance and a path of the file to be watched for events.
+/// The parent directory of the file is watched, delivering only the events of the file
+/// itself, so that the file remains watched when it is replaced by atomic saves. The file
+/// may not exist yet, in which case the add event is delivered once it is created. The
+/// other entries of the directory are still delivered if it is watched as well. The file
+/// is reported by list and isWatched, and is unwatched by unwatch. Fails if the path is
+/// a directory.
+#[napi]
+pub fn add_file(env: Env, ext: JsExternal, file: String) -> Result<JsUndefined> {
+  let fs_watcher = env.get_value_external::<FsWatcher>(&ext)?;
+
+  fs_watcher.add_file(Path::new(&file))?;
+  fs_watcher.emit_ready();
+  env.get_undefined()
+}
+
+/// This function invokes unwatch method on the specific path and removes that path
+/// from watching for fs events along with the additional paths watched under it, such
+/// as symlink targets and sub-directories.
+#[napi]
+pub fn unwatch(env: Env, ext: JsExternal, dir: Either<JsString, Buffer>) -> Result<JsUndefined> {
+  let dir = js_path(dir)?;
+  let fs_watcher = env.get_value_external::<FsWatcher>(&ext)?;
+  let mut state = fs_watcher.state();
+  state.watcher()?;
+
+  state.unwatch_root(&normalize_path(&dir))?;
+  env.get_undefined()
+}
+
+/// This function unwatches all the paths being watched by the watcher instance along
+/// with the additional paths watched under them. All the paths are attempted and the
+/// paths that failed to be unwatched are reported together in a single error. The
+/// watcher remains usable for adding paths.
+#[napi]
+pub fn unwatch_all(env: Env, ext: JsExternal) -> Result<JsUndefined> {
+  let fs_watcher = env.get_value_external::<FsWatcher>(&ext)?;
+  fs_watcher.state().unwatch_all()?;
+  env.get_undefined()
+}
+
+/// This function delivers the events held by the watcher instance right away, i.e. the
+/// batched events and the events waiting for the coalesceModify, settle and rename
+/// timers. The events are queued to the callback before this function returns, ahead of
+/// any later events.
+#[napi]
+pub fn flush(env: Env, ext: JsExternal) -> Result<JsUndefined> {
+  let fs_watcher = env.get_value_external::<FsWatcher>(&ext)?;
+
+  fs_watcher.state().watcher()?;
+  if let Some(dispatcher) = fs_watcher.dispatcher.upgrade() {
+    dispatcher.drain();
+  }
+  env.get_undefined()
+}
+
+/// This function closes the watcher instance by dropping it, which stops watching all
+/// the paths and releases the resources. Any held events are delivered before closing
+/// the same way as flush, unless dropPending is true in which case they are discarded.
+/// Subsequent calls on this instance will fail.
+#[napi]
+pub fn close(env: Env, ext: JsExternal, drop_pending: Option<bool>) -> Result<JsUndefined> {
+  let fs_watcher = env.get_value_external::<FsWatcher>(&ext)?;
+
+  close_watcher(
+    &fs_watcher.state,
+    &fs_watcher.dispatcher,
+    drop_pending.unwrap_or(false),
+  );
+  // Invalidate the id the watcher is registered with
+  WATCHERS
+    .lock()
+    .unwrap()
+    .retain(|_, registered| !Arc::ptr_eq(&registered.state, &fs_watcher.state));
+  env.get_undefined()
+}
+
+/// This function returns the list of paths currently being watched by the watcher
+/// instance in their absolute form. The paths which are not valid UTF-8 are returned as
+/// buffers of raw bytes.
+#[napi]
+pub fn list(env: Env, ext: JsExternal) -> Result<Vec<Either<String, Buffer>>> {
+  let fs_watcher = env.get_value_external::<FsWatcher>(&ext)?;
+
+  Ok(
+    fs_watcher
+      .state()
+      .watched_paths()
+      .iter()
+      .map(|path| match path_bytes(path) {
+        Some(bytes) => Either::B(bytes),
+        None => Either::A(path.to_string_lossy().into_owned()),
+      })
+      .collect(),
+  )
+}
+
+/// This function checks whether the given path is being watched by the watcher instance.
+/// The path is normalized into its absolute form the same way as it is added, and can be
+/// given as a buffer of raw bytes the same way as well.
+#[napi]
+pub fn is_watched(env: Env, ext: JsExternal, dir: Either<JsString, Buffer>) -> Result<bool> {
+  let dir = js_path(dir)?;
+  let fs_watcher = env.get_value_external::<FsWatcher>(&ext)?;
+  let path = normalize_path(&dir);
+
+  Ok(fs_watcher.state().paths.read().unwrap().contains(&path))
+}
+
+/// This function pauses the watcher instance, dropping all the fs events until it is
+/// resumed without removing the watched paths.
+#[napi]
+pub fn pause(env: Env, ext: JsExternal) -> Result<JsUndefined> {
+  let fs_watcher = env.get_value_external::<FsWatcher>(&ext)?;
+
+  fs_watcher.state().watcher()?;
+  fs_watcher.paused.store(true, Ordering::Relaxed);
+  env.get_undefined()
+}
+
+/// This function resumes the paused watcher instance to deliver fs events again.
+#[napi]
+pub fn resume(env: Env, ext: JsExternal) -> Result<JsUndefined> {
+  let fs_watcher = env.get_value_external::<FsWatcher>(&ext)?;
+
+  fs_watcher.state().watcher()?;
+  fs_watcher.paused.store(false, Ordering::Relaxed);
+  env.get_undefined()
+}
+
+/// This function delivers only the events of the paths under any of the given prefixes
+/// without rewatching the paths, which can be used to focus on a subtree of the watched
+/// paths temporarily. The prefixes are normalized into their absolute form the same way
+/// as the paths are added. Passing an empty list clears the filter.
+#[napi]
+pub fn set_prefix_filter(env: Env, ext: JsExternal, prefixes: Vec<String>) -> Result<JsUndefined> {
+  let fs_watcher = env.get_value_external::<FsWatcher>(&ext)?;
+
+  fs_watcher.state().watcher()?;
+  *fs_watcher.prefixes.write().unwrap() = prefixes
+    .iter()
+    .map(|prefix| normalize_path(Path::new(prefix)))
+    .collect();
+  env.get_undefined()
+}
+
+/// This function replaces the ignore, include, kinds and extensions filters with the
+/// ones in the given options JSON without rewatching the paths. The new filters apply to
+/// the events delivered after it returns, and the filters not given are cleared. Fails
+/// without changing the filters if any of the patterns is invalid. The lifecycle events
+/// such as ready and overflow are delivered regardless of the kinds filter.
+#[napi]
+pub fn set_filters(env: Env, ext: JsExternal, opts: JsString) -> Result<JsUndefined> {
+  let fs_watcher = env.get_value_external::<FsWatcher>(&ext)?;
+  let options: FilterOptions = parse_options(opts.into_utf8()?.as_str()?)?;
+
+  fs_watcher.state().watcher()?;
+  *fs_watcher.patterns.write().unwrap() = PatternFilter::new(&options)?;
+  env.get_undefined()
+}
+
+/// This function updates the interval in milliseconds at which the poll watcher scans
+/// for fs events, keeping the watched paths. Setting zero will fall back to the default.
+/// Fails if the interval is below 100 ms or the watcher is not using the poll backend.
+#[napi]
+pub fn set_poll_interval(env: Env, ext: JsExternal, ms: u32) -> Result<JsUndefined> {
+  let fs_watcher = env.get_value_external::<FsWatcher>(&ext)?;
+  let poll_interval = if ms > 0 {
+    u64::from(ms)
+  } else {
+    DEFAULT_POLL_INTERVAL as u64
+  };
+  if poll_interval < MIN_POLL_INTERVAL {
+    return Err(Error::new(
+      Status::InvalidArg,
+      format!(
+        "Poll interval of {} ms is below {} ms",
+        poll_interval, MIN_POLL_INTERVAL
+      ),
+    ));
+  }
+
+  fs_watcher
+    .state()
+    .set_poll_interval(Duration::from_millis(poll_interval))?;
+  env.get_undefined()
+}
+
+/// Statistics of the events handled by the watcher instance
+#[napi(object)]
+pub struct WatcherStats {
+  /// Number of the events received from the backend and the initial scan
+  pub events_seen: i64,
+  /// Number of the events delivered to the callback
+  pub events_delivered: i64,
+  /// Number of the events suppressed as duplicates or by the filters
+  pub events_suppressed: i64,
+  /// Number of the events dropped as the event queue is full
+  pub events_dropped: i64,
+  /// Number of the paths being watched
+  pub watched_paths: u32,
+}
+
+/// This function returns the statistics of the events handled by the watcher instance
+/// since it was created, along with the number of paths being watched.
+#[napi]
+pub fn stats(env: Env, ext: JsExternal) -> Result<WatcherStats> {
+  let fs_watcher = env.get_value_external::<FsWatcher>(&ext)?;
+  let stats = &fs_watcher.stats;
+  let count = |counter: &AtomicU64| counter.load(Ordering::Relaxed) as i64;
+
+  Ok(WatcherStats {
+    events_seen: count(&stats.seen),
+    events_delivered: count(&stats.delivered),
+    events_suppressed: count(&stats.suppressed),
+    events_dropped: count(&stats.dropped),
+    watched_paths: fs_watcher.state().paths.read().unwrap().len() as u32,
+  })
+}
+
+/// Decision made on the event by the watcher instance in the dry run mode
+#[napi(object)]
+pub struct DebugEntry {
+  /// Raw event kind reported by the backend such as Modify(Data(Content)), or null for
+  /// the events produced by the watcher itself such as ready and the initial events
+  pub raw: Option<String>,
+  pub event: WatchEvent,
+  /// Whether the event would be delivered to the callback
+  pub delivered: bool,
+  /// Reason the event is suppressed, which is one of other, ignored, notIncluded,
+  /// duplicate, throttled, debounced, coalesced, settled, pending, filtered, rateLimited
+  /// or once
+  pub reason: Option<String>,
+}
+
+/// This function returns the decisions made on the events by the watcher instance created
+/// with the dryRun option since the previous call, in the order they are made. The log
+/// keeps the latest decisions up to a limit. Returns an empty list if the watcher is not
+/// in the dry run mode.
+#[napi]
+pub fn drain_debug(env: Env, ext: JsExternal) -> Result<Vec<DebugEntry>> {
+  let fs_watcher = env.get_value_external::<FsWatcher>(&ext)?;
+  let dispatcher = match fs_watcher.dispatcher.upgrade() {
+    Some(dispatcher) => dispatcher,
+    None => return Ok(Vec::new()),
+  };
+  let records = match &dispatcher.debug_log {
+    Some(debug_log) => std::mem::take(&mut *debug_log.lock().unwrap()),
+    None => return Ok(Vec::new()),
+  };
+
+  Ok(
+    records
+      .into_iter()
+      .map(|record| DebugEntry {
+        raw: record.event.detail.clone(),
+        event: WatchEvent::from(record.event),
+        delivered: record.delivered,
+        reason: record.reason.map(String::from),
+      })
+      .collect(),
+  )
+}
+
+/// This function returns the name of the backend used by the watcher instance, which is
+/// one of inotify, fsevents, kqueue, readDirectoryChanges, poll or unknown. The backend
+/// is poll if the first watched path is detected to be on a network file system.
+#[napi]
+pub fn backend(env: Env, ext: JsExternal) -> Result<String> {
+  let fs_watcher = env.get_value_external::<FsWatcher>(&ext)?;
+
+  Ok(String::from(backend_name(fs_watcher.state().backend)))
+}
+
+// One-shot watch of the path for wait_for_change, which runs on the libuv thread pool
+// until the first event allowed by the filters arrives or the timeout expires.
+pub struct WaitForChange {
+  path: PathBuf,
+  // Options or the reason they failed to be parsed, which rejects the promise
+  options: std::result::Result<WatchOptions, String>,
+}
+
+impl Task for WaitForChange {
+  type Output = FsEvent;
+  type JsValue = WatchEvent;
+
+  fn compute(&mut self) -> Result<Self::Output> {
+    let options = self
+      .options
+      .as_ref()
+      .map_err(|reason| Error::new(Status::InvalidArg, reason.clone()))?;
+    let roots = WatchedPaths::default();
+    roots.write().unwrap().insert(normalize_path(&self.path));
+    let filter = EventFilter::new(options, roots)?;
+
+    let (sender, receiver) = mpsc::channel();
+    let handler: SharedHandler = Arc::new(Mutex::new(move |ev: notify::Result<Event>| {
+      let _ = sender.send(ev);
+    }));
+    // Watcher is dropped on return, which stops watching the path
+    let (mut watcher, _) = create_watcher(
+      &handler,
+      options.backend_config()?,
+      options.use_polling,
+      false,
+    )?;
+    let recursive_mode = if options.recursive {
+      RecursiveMode::Recursive
+    } else {
+      RecursiveMode::NonRecursive
+    };
+    watcher
+      .watch(&extended_path(&self.path), recursive_mode)
+      .map_err(|e| Error::new(Status::GenericFailure, format!("{}", e)))?;
+
+    // Wait without the timeout blocks a thread of the libuv thread pool until the first
+    // event arrives
+    let deadline =
+      (options.timeout_ms > 0).then(|| Instant::now() + Duration::from_millis(options.timeout_ms));
+    loop {
+      let ev = match deadline {
+        Some(deadline) => receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())),
+        None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+      };
+      let mut evt = match ev {
+        Ok(ev) => ev.map_err(|e| Error::new(Status::GenericFailure, format!("{}", e)))?,
+        Err(RecvTimeoutError::Timeout) => {
+          return Err(Error::new(
+            Status::GenericFailure,
+            format!("Timed out waiting for change in {}", self.path.display()),
+          ))
+        }
+        Err(RecvTimeoutError::Disconnected) => {
+          return Err(Error::new(
+            Status::GenericFailure,
+            format!(
+              "Watcher stopped before any change in {}",
+              self.path.display()
+            ),
+          ))
+        }
+      };
+
+      for path in evt.paths.iter_mut() {
+        strip_extended_prefix(path);
+      }
+      let timestamp = system_time_nanos(SystemTime::now());
+      for path in evt.paths {
+        let kind = event_kind_name(
+          &evt.kind,
+          &path,
+          || path.is_dir(),
+          options.access,
+          options.metadata,
+          options.close_write,
+        );
+        if kind == "other" || !filter.is_path_allowed(&path, kind_is_dir(kind)) {
+          continue;
+        }
+        let event = FsEvent::new(String::from(kind), path, timestamp);
+        if filter.is_event_allowed(&event) {
+          return Ok(event);
+        }
+      }
+    }
+  }
+
+  fn resolve(&mut self, _env: Env, output: Self::Output) -> Result<Self::JsValue> {
+    Ok(WatchEvent::from(output))
+  }
+}
+
+/// This function watches the given path once without creating the watcher instance, and
+/// returns a promise resolving with the first event allowed by the filters in the options.
+/// The promise is rejected if no event arrives within the timeout_ms option if given, if
+/// the watcher stops before any event, or if the options are malformed. The promise
+/// occupies a thread of the libuv thread pool until it settles.
+#[napi(ts_return_type = "Promise<WatchEvent>")]
+pub fn wait_for_change(path: String, opts: String) -> AsyncTask<WaitForChange> {
+  AsyncTask::new(WaitForChange {
+    path: PathBuf::from(path),
+    options: parse_options(&opts).map_err(|e| e.reason.clone()),
+  })
+}
+
+// Event iterator subscribed by events, which is read by nextEvent until closed
+pub struct EventReceiver {
+  receiver: Mutex<mpsc::Receiver<FsEvent>>,
+  closed: AtomicBool,
+}
+
+pub struct NextEvent {
+  stream: Arc<EventReceiver>,
+}
+
+impl Task for NextEvent {
+  type Output = Option<FsEvent>;
+  type JsValue = Option<WatchEvent>;
+
+  // Waits in steps so that closeEvents ends the wait of a quiet watcher
+  fn compute(&mut self) -> Result<Self::Output> {
+    let receiver = self.stream.receiver.lock().unwrap();
+    while !self.stream.closed.load(Ordering::SeqCst) {
+      match receiver.recv_timeout(TIMER_CHECK_INTERVAL) {
+        Ok(event) => return Ok(Some(event)),
+        Err(RecvTimeoutError::Timeout) => continue,
+        Err(RecvTimeoutError::Disconnected) => break,
+      }
+    }
+    Ok(None)
+  }
+
+  fn resolve(&mut self, _env: Env, output: Self::Output) -> Result<Self::JsValue> {
+    Ok(output.map(WatchEvent::from))
+  }
+}
+
+/// This function subscribes to the events of the watcher instance, returning the event
+/// iterator read by nextEvent. The events are the ones delivered to the callback, with
+/// the absolute paths and the native kinds regardless of the relativeTo, kindMap, compat,
+/// normalizeSeparators and format options. Up to the given capacity of events, 1000 by
+/// default, are kept until read, beyond which the events are dropped and an overflow
+/// event is yielded in their place. Fails if the watcher is closed.
+#[napi]
+pub fn events(env: Env, ext: JsExternal, capacity: Option<u32>) -> Result<JsExternal> {
+  let fs_watcher = env.get_value_external::<FsWatcher>(&ext)?;
+  let capacity = capacity.unwrap_or(DEFAULT_STREAM_CAPACITY) as usize;
+  let stream = EventReceiver {
+    receiver: Mutex::new(fs_watcher.subscribe(capacity)?),
+    closed: AtomicBool::new(false),
+  };
+  env.create_external(Arc::new(stream), None)
+}
+
+/// This function returns a promise resolving with the next event of the event iterator
+/// returned by events, or with null once the watcher or the iterator is closed and the
+/// events kept so far are read. The promise occupies a thread of the libuv thread pool
+/// until it settles.
+#[napi(ts_return_type = "Promise<WatchEvent | null>")]
+pub fn next_event(env: Env, stream: JsExternal) -> Result<AsyncTask<NextEvent>> {
+  let stream = env.get_value_external::<Arc<EventReceiver>>(&stream)?;
+  Ok(AsyncTask::new(NextEvent {
+    stream: Arc::clone(stream),
+  }))
+}
+
+/// This function closes the event iterator returned by events, which resolves its
+/// pending and later nextEvent promises with null. The watcher is not affected.
+#[napi]
+pub fn close_events(env: Env, stream: JsExternal) -> Result<JsUndefined> {
+  let stream = env.get_value_external::<Arc<EventReceiver>>(&stream)?;
+  stream.closed.store(true, Ordering::SeqCst);
+  env.get_undefined()
+}
+
+/// Features supported by the backend of the watcher instance
+#[napi(object)]
+pub struct Capabilities {
+  /// Whether the paths can be watched recursively by the backend, which watches the
+  /// sub-directories created later as well
+  pub recursive: bool,
+  /// Whether the rename events are reported in pairs of source and destination, which
+  /// are correlated into single rename event
+  pub rename_correlation: bool,
+  /// Whether the access events are reported, including the close write events
+  pub access_events: bool,
+  /// Whether the events are detected by polling, which supports comparing the contents
+  pub poll_only: bool,
+}
+
+/// This function returns the features supported by the backend of the watcher instance,
+/// which can be used to degrade gracefully on the backends lacking them.
+#[napi]
+pub fn capabilities(env: Env, ext: JsExternal) -> Result<Capabilities> {
+  let fs_watcher = env.get_value_external::<FsWatcher>(&ext)?;
+  let backend = fs_watcher.state().backend;
+  Ok(Capabilities::of(backend))
+}
+
+impl Capabilities {
+  // Features of the backend of the given kind. All the backends of notify support the
+  // recursive mode, and the rename events are reported in pairs only by the backends
+  // tracking them with a cookie.
+  fn of(kind: WatcherKind) -> Self {
+    Capabilities {
+      recursive: kind != WatcherKind::NullWatcher,
+      rename_correlation: matches!(
+        kind,
+        WatcherKind::Inotify | WatcherKind::ReadDirectoryChangesWatcher
+      ),
+      access_events: kind == WatcherKind::Inotify,
+      poll_only: kind == WatcherKind::PollWatcher,
+    }
+  }
+}
+
+/// Versions and features the native addon is built with
+#[napi(object)]
+pub struct BuildInfo {
+  /// Version of the native addon
+  pub version: String,
+  /// Version of the notify crate providing the backends
+  pub notify_version: String,
+  /// Target triple such as x86_64-unknown-linux-gnu
+  pub target: String,
+  /// Build profile, either debug or release
+  pub profile: String,
+  /// Platform dependent features supported by the build, among fanotify for the tracePid
+  /// option, networkDetection for the autoDetectNetwork option, closeWrite for the
+  /// closeWrite option and fileId for the includeFileId option
+  pub features: Vec<String>,
+}
+
+/// This function returns the versions and features the native addon is built with, which
+/// helps telling apart the behaviors of the builds for different platforms.
+#[napi]
+pub fn info() -> BuildInfo {
+  let features = [
+    ("fanotify", cfg!(target_os = "linux")),
+    (
+      "networkDetection",
+      cfg!(any(target_os = "linux", target_os = "macos")),
+    ),
+    ("closeWrite", cfg!(target_os = "linux")),
+    ("fileId", cfg!(unix)),
+  ];
+
+  BuildInfo {
+    version: env!("CARGO_PKG_VERSION").to_string(),
+    notify_version: env!("NFS_WATCHER_NOTIFY_VERSION").to_string(),
+    target: env!("NFS_WATCHER_TARGET").to_string(),
+    profile: env!("NFS_WATCHER_PROFILE").to_string(),
+    features: features
+      .iter()
+      .filter(|(_, enabled)| *enabled)
+      .map(|(feature, _)| feature.to_string())
+      .collect(),
+  }
+}
+
+/// This function scans the paths watched by the poll watcher right away instead of
+/// waiting for the poll interval, and emits the changes not reported yet. The poll
+/// watcher is restarted so that the changes are not reported again. Returns false
+/// without scanning if the watcher is not using the poll backend.
+#[napi]
+pub fn rescan(env: Env, ext: JsExternal) -> Result<bool> {
+  let fs_watcher = env.get_value_external::<FsWatcher>(&ext)?;
+  let mut state = fs_watcher.state();
+  if state.backend != WatcherKind::PollWatcher {
+    return Ok(false);
+  }
+  state.watcher()?;
+
+  let roots: Vec<PathBuf> = state.paths.read().unwrap().iter().cloned().collect();
+  let mut current = HashMap::new();
+  for root in &roots {
+    let max_depth = match state.mode_of(root) {
+      RecursiveMode::Recursive => state.max_depth.unwrap_or(usize::MAX),
+      RecursiveMode::NonRecursive => 1,
+    };
+    current.extend(scan_entries(root, max_depth));
+  }
+
+  // Entries of the paths unwatched since the previous scan are not compared
+  let previous = fs_watcher
+    .snapshot
+    .lock()
+    .unwrap()
+    .replace(current.clone())
+    .unwrap_or_default();
+  let previous: HashMap<PathBuf, EntryState> = previous
+    .into_iter()
+    .filter(|(path, _)| roots.iter().any(|root| path.starts_with(root)))
+    .collect();
+  state.restart()?;
+  drop(state);
+
+  let dispatcher = match fs_watcher.dispatcher.upgrade() {
+    Some(dispatcher) => dispatcher,
+    None => return Ok(true),
+  };
+  let timestamp = system_time_nanos(SystemTime::now());
+  let dir_suffix = |entry: &EntryState| if entry.is_dir { "Dir" } else { "" };
+
+  let mut events: Vec<FsEvent> = previous
+    .iter()
+    .filter(|(path, _)| !current.contains_key(*path))
+    .map(|(path, entry)| {
+      FsEvent::new(
+        format!("remove{}", dir_suffix(entry)),
+        path.clone(),
+        timestamp,
+      )
+    })
+    .collect();
+  for (path, entry) in &current {
+    let kind = match previous.get(path) {
+      None => format!("add{}", dir_suffix(entry)),
+      Some(prev) if prev != entry && !entry.is_dir => String::from("modify"),
+      _ => continue,
+    };
+    events.push(FsEvent::new(kind, path.clone(), timestamp));
+  }
+  events.sort_by(|a, b| a.path.cmp(&b.path));
+
+  for event in events {
+    fs_watcher.stats.add(&fs_watcher.stats.seen, 1);
+    let is_dir = event.kind.ends_with("Dir");
+    let mut file_types = fs_watcher.file_types.lock().unwrap();
+    if event.kind.starts_with("remove") {
+      file_types.remove(&event.path);
+    } else {
+      cache_file_type(&mut file_types, &event.path, is_dir);
+    }
+    drop(file_types);
+
+    if dispatcher.filter.is_path_allowed(&event.path, Some(is_dir)) {
+      dispatcher.emit(event);
+    } else {
+      dispatcher.suppress(
+        &event,
+        dispatcher
+          .filter
+          .suppression_reason(&event.path, Some(is_dir)),
+      );
+    }
+  }
+  Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // Node-API functions referenced by the code under test, which are provided by node once
+  // the addon is loaded. The unit tests run outside of node and never call into it.
+  macro_rules! node_api_stubs {
+    ($($name:ident),*) => {$(
+      #[no_mangle]
+      extern "C" fn $name() {
+        unreachable!(concat!(stringify!($name), " is only available in node"));
+      }
+    )*};
+  }
+
+  node_api_stubs!(
+    napi_call_threadsafe_function,
+    napi_create_error,
+    napi_create_string_utf8,
+    napi_delete_reference,
+    napi_get_reference_value,
+    napi_reference_unref,
+    napi_throw
+  );
+
+  // Time waited for the expected events, which is long enough for the backends to report
+  // them on a busy machine
+  const EVENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+  // Watcher created with the given options delivering its events and errors to the
+  // returned channels in place of the javascript callbacks
+  fn test_watcher(options: &str) -> (FsWatcher, mpsc::Receiver<FsEvent>, mpsc::Receiver<Error>) {
+    let (event_sender, events) = mpsc::channel();
+    let (fs_watcher, errors) = watcher_with_callback(options, move |batch| {
+      for event in batch {
+        let _ = event_sender.send(event);
+      }
+    });
+    (fs_watcher, events, errors)
+  }
+
+  // Watcher created with the given options passing each call of its callback to the given
+  // function, and delivering its errors to the returned channel
+  fn watcher_with_callback(
+    options: &str,
+    deliver: impl Fn(Vec<FsEvent>) + Send + Sync + 'static,
+  ) -> (FsWatcher, mpsc::Receiver<Error>) {
+    let options: WatchOptions = parse_options(options).unwrap();
+    let in_flight = Arc::new(AtomicUsize::new(0));
+    let completed = Arc::clone(&in_flight);
+    let (error_sender, errors) = mpsc::channel();
+    let callback_errors = error_sender.clone();
+
+    let fs_watcher = create_fs_watcher(
+      options,
+      in_flight,
+      Box::new(move |value, _| {
+        match value {
+          Ok(batch) => {
+            completed.fetch_sub(1, Ordering::SeqCst);
+            deliver(batch);
+          }
+          Err(error) => {
+            let _ = callback_errors.send(error);
+          }
+        }
+        Status::Ok
+      }),
+      Some(Box::new(move |error, _| {
+        let _ = error_sender.send(error);
+        Status::Ok
+      })),
+    )
+    .unwrap();
+    (fs_watcher, errors)
+  }
+
+  // Feeds the event into the event handler of the watcher the same way as the backend does
+  fn inject(fs_watcher: &FsWatcher, event: notify::Result<Event>) {
+    let handler = fs_watcher.state().handler.clone().unwrap();
+    (handler.lock().unwrap())(event);
+  }
+
+  // Next delivered event, skipping the events of the kinds given
+  fn next_event(events: &mpsc::Receiver<FsEvent>, skipped: &[&str]) -> FsEvent {
+    loop {
+      let event = events
+        .recv_timeout(EVENT_TIMEOUT)
+        .expect("no event delivered");
+      if !skipped.contains(&event.kind.as_str()) {
+        return event;
+      }
+    }
+  }
+
+  // Events delivered within the given time
+  fn events_within(events: &mpsc::Receiver<FsEvent>, timeout: Duration) -> Vec<FsEvent> {
+    let deadline = Instant::now() + timeout;
+    let mut delivered = Vec::new();
+    while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+      match events.recv_timeout(left) {
+        Ok(event) => delivered.push(event),
+        Err(_) => break,
+      }
+    }
+    delivered
+  }
+
+  // Directory created under the temporary directory of the system for a test, which is
+  // removed along with its contents once dropped
+  struct TempDir(PathBuf);
+
+  impl TempDir {
+    fn new() -> Self {
+      static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+      let path = std::env::temp_dir().join(format!(
+        "nfs-watcher-test-{}-{}",
+        std::process::id(),
+        NEXT_DIR.fetch_add(1, Ordering::SeqCst)
+      ));
+      fs::create_dir_all(&path).unwrap();
+      // Event paths are reported under the canonical path of the directory
+      TempDir(fs::canonicalize(path).unwrap())
+    }
+
+    fn path(&self) -> &Path {
+      &self.0
+    }
+  }
+
+  impl Drop for TempDir {
+    fn drop(&mut self) {
+      let _ = fs::remove_dir_all(&self.0);
+    }
+  }
+
+  // Bytes taken by the paths stored in the file type cache, i.e. the interned prefixes
+  // along with the relative paths grouped by them
+  fn cached_path_bytes(cache: &FileTypeCache) -> usize {
+    cache
+      .prefixes
+      .iter()
+      .map(|(prefix, entries)| {
+        prefix.as_os_str().len()
+          + entries
+            .keys()
+            .map(|relative| relative.as_os_str().len())
+            .sum::<usize>()
+      })
+      .sum()
+  }
+
+  #[test]
+  fn file_type_cache_groups_paths_by_prefix() {
+    let roots = WatchedPaths::default();
+    roots.write().unwrap().insert(PathBuf::from("/repo"));
+    let mut cache = FileTypeCache::new(roots);
+
+    cache.insert(Path::new("/repo/src"), true);
+    cache.insert(Path::new("/repo/src/lib.rs"), false);
+    cache.insert(Path::new("/outside/file"), false);
+    assert_eq!(cache.len(), 3);
+    assert_eq!(cache.get(Path::new("/repo/src")), Some(true));
+    assert_eq!(cache.get(Path::new("/repo/src/lib.rs")), Some(false));
+    assert_eq!(cache.get(Path::new("/repo/other")), None);
+
+    cache.remove_under(Path::new("/repo/src"));
+    assert_eq!(cache.len(), 1);
+    assert_eq!(cache.get(Path::new("/repo/src/lib.rs")), None);
+
+    assert_eq!(cache.remove(Path::new("/outside/file")), Some(false));
+    assert_eq!(cache.len(), 0);
+    assert!(cache.prefixes.is_empty());
+  }
+
+  // Serializes the event without the optional fields into json by hand, which is the fast
+  // path compared with serde_json by the benchmark below
+  fn event_json(event: &WatchEvent) -> String {
+    let mut json = String::with_capacity(64 + event.path.len());
+    json.push_str("{\"kind\":\"");
+    json.push_str(&event.kind);
+    json.push_str("\",\"path\":\"");
+    for c in event.path.chars() {
+      match c {
+        '"' => json.push_str("\\\""),
+        '\\' => json.push_str("\\\\"),
+        c if c.is_control() => json.push_str(&format!("\\u{:04x}", c as u32)),
+        c => json.push(c),
+      }
+    }
+    // Numbers are written in the shortest form the same way as serde_json, such as 1.0
+    json.push_str(&format!(
+      "\",\"ts\":{:?},\"seq\":{:?}}}",
+      event.ts, event.seq
+    ));
+    json
+  }
+
+  #[test]
+  #[ignore = "benchmark"]
+  fn event_serialization_speed() {
+    // Timestamps in whole milliseconds have the single shortest form in json, so that the
+    // outputs are compared as they are
+    let now = system_time_nanos(SystemTime::now()) / 1_000_000 * 1_000_000;
+    let events: Vec<FsEvent> = (0..100_000)
+      .map(|i| {
+        FsEvent::new(
+          String::from("modify"),
+          PathBuf::from(format!(
+            "/home/user/project/src/module{}/file{}.rs",
+            i % 100,
+            i
+          )),
+          now + i * 1_000_000,
+        )
+      })
+      .collect();
+
+    let start = Instant::now();
+    let converted: Vec<WatchEvent> = events.iter().cloned().map(WatchEvent::from).collect();
+    let conversion = start.elapsed();
+
+    let start = Instant::now();
+    let serde: Vec<String> = converted
+      .iter()
+      .map(|event| serde_json::to_string(event).unwrap())
+      .collect();
+    let serde_time = start.elapsed();
+
+    let start = Instant::now();
+    let manual: Vec<String> = converted.iter().map(event_json).collect();
+    let manual_time = start.elapsed();
+
+    for (serde, manual) in serde.iter().zip(&manual) {
+      assert_eq!(serde, manual);
+    }
+    println!(
+      "{} events: {:?} converting into WatchEvent, {:?} with serde_json, {:?} by hand",
+      events.len(),
+      conversion,
+      serde_time,
+      manual_time
+    );
+  }
+
+  #[test]
+  #[ignore = "benchmark"]
+  fn file_type_cache_memory() {
+    let root = PathBuf::from("/home/user/projects/organization/monorepo/packages/application");
+    let roots = WatchedPaths::default();
+    roots.write().unwrap().insert(root.clone());
+    let mut cache = FileTypeCache::new(roots);
+    let mut plain: HashMap<PathBuf, bool> = HashMap::new();
+
+    for i in 0..100_000 {
+      let path = root.join(format!("src/module{}/file{}.ts", i % 100, i));
+      cache.insert(&path, false);
+      plain.insert(path, false);
+    }
+
+    let interned = cached_path_bytes(&cache);
+    let full: usize = plain.keys().map(|path| path.as_os_str().len()).sum();
+    println!(
+      "{} paths: {} bytes interned, {} bytes as full paths",
+      cache.len(),
+      interned,
+      full
+    );
+    assert!(interned < full / 2);
+  }
+
+  #[test]
+  fn event_without_paths_is_delivered_as_other() {
+    let (fs_watcher, events, _) = test_watcher(r#"{"dedup": false}"#);
+
+    inject(
+      &fs_watcher,
+      Ok(Event::new(EventKind::Remove(RemoveKind::Any))),
+    );
+    let event = next_event(&events, &[]);
+    assert_eq!(event.kind, "other");
+    assert_eq!(event.path, PathBuf::new());
+  }
+
+  #[test]
+  fn event_without_paths_is_dropped_as_other_with_dedup() {
+    let (fs_watcher, events, _) = test_watcher("{}");
+
+    inject(&fs_watcher, Ok(Event::new(EventKind::Any)));
+    assert!(events_within(&events, Duration::from_millis(200)).is_empty());
+  }
+
+  #[test]
+  fn event_is_delivered_with_its_kind_and_path() {
+    let (fs_watcher, events, _) = test_watcher("{}");
+    let path = PathBuf::from("/watched/new.txt");
+
+    inject(
+      &fs_watcher,
+      Ok(Event::new(EventKind::Create(CreateKind::File)).add_path(path.clone())),
+    );
+    let event = next_event(&events, &[]);
+    assert_eq!(event.kind, "add");
+    assert_eq!(event.path, path);
+  }
+
+  #[test]
+  fn ignore_matcher_matches_any_pattern() {
+    let matcher =
+      IgnoreMatcher::new(&["**/node_modules/**".to_string(), "**/*.tmp".to_string()]).unwrap();
+
+    assert!(!matcher.has_negation());
+    assert!(matcher.is_match(Path::new("/repo/node_modules/pkg/index.js")));
+    assert!(matcher.is_match(Path::new("/repo/src/file.tmp")));
+    assert!(!matcher.is_match(Path::new("/repo/src/file.rs")));
+  }
+
+  #[test]
+  fn ignore_matcher_rejects_invalid_pattern() {
+    let error = IgnoreMatcher::new(&["src/[".to_string()]).err().unwrap();
+    assert_eq!(error.status, Status::InvalidArg);
+  }
+
+  #[test]
+  fn event_filter_applies_ignore_before_include() {
+    let options: WatchOptions =
+      parse_options(r#"{"ignore": ["**/*.log"], "include": ["**/logs/**"]}"#).unwrap();
+    let filter = EventFilter::new(&options, WatchedPaths::default()).unwrap();
+
+    assert!(filter.is_path_allowed(Path::new("/repo/logs/today.txt"), Some(false)));
+    assert!(!filter.is_path_allowed(Path::new("/repo/logs/today.log"), Some(false)));
+    assert!(!filter.is_path_allowed(Path::new("/repo/src/lib.rs"), Some(false)));
+    assert_eq!(
+      filter.suppression_reason(Path::new("/repo/logs/today.log"), Some(false)),
+      "ignored"
+    );
+    assert_eq!(
+      filter.suppression_reason(Path::new("/repo/src/lib.rs"), Some(false)),
+      "notIncluded"
+    );
+  }
+
+  #[test]
+  fn event_filter_checks_kinds_and_extensions() {
+    let options: WatchOptions =
+      parse_options(r#"{"kinds": ["add"], "extensions": [".RS"]}"#).unwrap();
+    let filter = EventFilter::new(&options, WatchedPaths::default()).unwrap();
+    let event = |kind: &str, path: &str| FsEvent::new(kind.to_string(), PathBuf::from(path), 0);
+
+    assert!(filter.is_event_allowed(&event("add", "/repo/lib.rs")));
+    assert!(!filter.is_event_allowed(&event("modify", "/repo/lib.rs")));
+    assert!(!filter.is_event_allowed(&event("add", "/repo/lib.ts")));
+    assert!(filter.is_event_allowed(&event("restart", "")));
+  }
+
+  #[test]
+  fn dispatcher_delivers_allowed_events() {
+    let (fs_watcher, events, _) = test_watcher(r#"{"kinds": ["add"]}"#);
+    let dispatcher = fs_watcher.dispatcher.upgrade().unwrap();
+
+    dispatcher.emit(FsEvent::new(
+      String::from("modify"),
+      PathBuf::from("/repo/a"),
+      0,
+    ));
+    dispatcher.emit(FsEvent::new(
+      String::from("add"),
+      PathBuf::from("/repo/b"),
+      0,
+    ));
+    let event = next_event(&events, &[]);
+    assert_eq!(event.kind, "add");
+    assert_eq!(event.path, PathBuf::from("/repo/b"));
+    assert!(events.try_recv().is_err());
+    assert_eq!(dispatcher.in_flight.load(Ordering::SeqCst), 0);
+  }
+
+  #[test]
+  fn file_created_in_watched_dir_is_delivered() {
+    let dir = TempDir::new();
+    let (mut fs_watcher, events, _) = test_watcher("{}");
+    fs_watcher.add_path(dir.path(), None).unwrap();
+
+    let path = dir.path().join("new.txt");
+    fs::write(&path, "x").unwrap();
+    let event = next_event(&events, &[]);
+    assert_eq!(event.kind, "add");
+    assert_eq!(event.path, path);
+  }
+
+  // Kinds of the events delivered for two identical modify events of a file fed 100ms
+  // apart with the given debounce time
+  fn debounced_kinds(debounce_ms: u64) -> Vec<String> {
+    let dir = TempDir::new();
+    let path = dir.path().join("file.txt");
+    fs::write(&path, "x").unwrap();
+    let (fs_watcher, events, _) = test_watcher(&format!(r#"{{"debounce_ms": {}}}"#, debounce_ms));
+    let modify = || {
+      Ok(
+        Event::new(EventKind::Modify(ModifyKind::Data(
+          notify::event::DataChange::Content,
+        )))
+        .add_path(path.clone()),
+      )
+    };
+
+    inject(&fs_watcher, modify());
+    thread::sleep(Duration::from_millis(100));
+    inject(&fs_watcher, modify());
+    events_within(&events, Duration::from_millis(300))
+      .into_iter()
+      .map(|event| event.kind)
+      .collect()
+  }
+
+  #[test]
+  fn duplicate_event_within_debounce_is_suppressed() {
+    assert_eq!(debounced_kinds(200), ["modify"]);
+  }
+
+  #[test]
+  fn duplicate_event_past_debounce_is_delivered() {
+    assert_eq!(debounced_kinds(50), ["modify", "modify"]);
+  }
+
+  #[test]
+  fn negated_ignore_pattern_includes_back_subtree() {
+    let matcher = IgnoreMatcher::new(&[
+      "**/node_modules/**".to_string(),
+      "!**/node_modules/local/**".to_string(),
+    ])
+    .unwrap();
+
+    assert!(matcher.has_negation());
+    assert!(matcher.is_match(Path::new("/repo/node_modules/pkg/index.js")));
+    assert!(!matcher.is_match(Path::new("/repo/node_modules/local/index.js")));
+    assert!(!matcher.is_match(Path::new("/repo/src/index.js")));
+  }
+
+  #[test]
+  fn last_matching_ignore_pattern_decides() {
+    let rust_only = IgnoreMatcher::new(&["**/*".to_string(), "!**/*.rs".to_string()]).unwrap();
+    assert!(!rust_only.is_match(Path::new("/repo/src/lib.rs")));
+    assert!(rust_only.is_match(Path::new("/repo/src/index.ts")));
+
+    let reordered = IgnoreMatcher::new(&["!**/*.rs".to_string(), "**/*".to_string()]).unwrap();
+    assert!(reordered.is_match(Path::new("/repo/src/lib.rs")));
+  }
+
+  #[test]
+  fn event_filter_doesnt_skip_subtree_with_negation() {
+    let options: WatchOptions =
+      parse_options(r#"{"ignore": ["**/build", "**/build/**", "!**/build/keep/**"]}"#).unwrap();
+    let filter = EventFilter::new(&options, WatchedPaths::default()).unwrap();
+
+    assert!(!filter.is_path_allowed(Path::new("/repo/build/out.js"), Some(false)));
+    assert!(filter.is_path_allowed(Path::new("/repo/build/keep/out.js"), Some(false)));
+    assert!(!filter.is_subtree_ignored(Path::new("/repo/build"), true));
+  }
+
+  #[test]
+  fn gitignored_paths_are_dropped() {
+    let dir = TempDir::new();
+    let root = dir.path();
+    fs::write(root.join(".gitignore"), "dist/\n*.log\n!keep.log\n").unwrap();
+    fs::create_dir_all(root.join("dist")).unwrap();
+    fs::create_dir_all(root.join("src")).unwrap();
+    fs::write(root.join("src").join(".gitignore"), "generated.rs\n").unwrap();
+    let (mut fs_watcher, events, _) =
+      test_watcher(r#"{"recursive": true, "respect_gitignore": true}"#);
+    fs_watcher.add_path(root, None).unwrap();
+
+    for path in [
+      "dist/bundle.js",
+      "src/generated.rs",
+      "debug.log",
+      "src/lib.rs",
+      "keep.log",
+      "src/dist",
+    ] {
+      fs::write(root.join(path), "x").unwrap();
+    }
+    let mut paths: Vec<PathBuf> = events_within(&events, Duration::from_millis(500))
+      .into_iter()
+      .map(|event| event.path)
+      .collect();
+    paths.sort();
+    paths.dedup();
+    assert_eq!(
+      paths,
+      [
+        root.join("keep.log"),
+        root.join("src").join("dist"),
+        root.join("src").join("lib.rs"),
+      ]
+    );
+  }
+
+  #[test]
+  fn stale_handle_error_watches_path_again() {
+    let dir = TempDir::new();
+    let (mut fs_watcher, events, errors) = test_watcher("{}");
+    fs_watcher.add_path(dir.path(), None).unwrap();
+
+    // Backend stops reporting the events of the path once its handle goes stale
+    fs_watcher
+      .state()
+      .watcher()
+      .unwrap()
+      .unwatch(dir.path())
+      .unwrap();
+    fs::write(dir.path().join("lost.txt"), "x").unwrap();
+    assert!(events_within(&events, Duration::from_millis(200)).is_empty());
+
+    let stale = io::Error::from(io::ErrorKind::StaleNetworkFileHandle);
+    inject(
+      &fs_watcher,
+      Err(notify::Error::io(stale).add_path(dir.path().to_path_buf())),
+    );
+    thread::sleep(Duration::from_millis(STALE_REWATCH_DELAY_MS + 200));
+
+    let path = dir.path().join("new.txt");
+    fs::write(&path, "x").unwrap();
+    let event = next_event(&events, &[]);
+    assert_eq!(event.kind, "add");
+    assert_eq!(event.path, path);
+    assert!(errors.try_recv().is_err());
+  }
+
+  #[test]
+  fn fatal_error_restarts_watcher() {
+    let dir = TempDir::new();
+    let (mut fs_watcher, events, errors) = test_watcher("{}");
+    fs_watcher.add_path(dir.path(), None).unwrap();
+
+    // Backend doesn't report any events after the fatal error
+    fs_watcher
+      .state()
+      .watcher()
+      .unwrap()
+      .unwatch(dir.path())
+      .unwrap();
+    inject(&fs_watcher, Err(notify::Error::generic("inotify failed")));
+    let event = next_event(&events, &[]);
+    assert_eq!(event.kind, "restart");
+    assert_eq!(event.path, PathBuf::new());
+
+    let path = dir.path().join("new.txt");
+    fs::write(&path, "x").unwrap();
+    let event = next_event(&events, &[]);
+    assert_eq!(event.kind, "add");
+    assert_eq!(event.path, path);
+    assert!(errors.try_recv().is_err());
+  }
+
+  #[test]
+  fn fatal_error_is_delivered_without_auto_restart() {
+    let (fs_watcher, events, errors) = test_watcher(r#"{"auto_restart": false}"#);
+
+    inject(&fs_watcher, Err(notify::Error::generic("inotify failed")));
+    let error = errors.recv_timeout(EVENT_TIMEOUT).unwrap();
+    assert!(error.reason.contains("inotify failed"));
+    assert!(events_within(&events, Duration::from_millis(200)).is_empty());
+  }
+
+  #[test]
+  fn network_path_is_watched_by_polling() {
+    let dir = TempDir::new();
+    let (mut fs_watcher, _, _) = test_watcher("{}");
+    fs_watcher.network_probe = |_| true;
+    fs_watcher.add_path(dir.path(), None).unwrap();
+
+    assert_eq!(fs_watcher.state().backend, PollWatcher::kind());
+  }
+
+  #[test]
+  fn local_path_is_watched_by_recommended_watcher() {
+    let dir = TempDir::new();
+    let (mut fs_watcher, _, _) = test_watcher("{}");
+    fs_watcher.network_probe = |_| false;
+    fs_watcher.add_path(dir.path(), None).unwrap();
+
+    assert_eq!(fs_watcher.state().backend, RecommendedWatcher::kind());
+  }
+
+  #[test]
+  fn network_detection_can_be_disabled() {
+    let dir = TempDir::new();
+    let (mut fs_watcher, _, _) = test_watcher(r#"{"auto_detect_network": false}"#);
+    fs_watcher.network_probe = |_| true;
+    fs_watcher.add_path(dir.path(), None).unwrap();
+
+    assert_eq!(fs_watcher.state().backend, RecommendedWatcher::kind());
+  }
+
+  #[test]
+  fn short_poll_interval_delivers_events_sooner() {
+    let dir = TempDir::new();
+    let (mut fs_watcher, events, _) =
+      test_watcher(r#"{"use_polling": true, "poll_interval": 200}"#);
+    fs_watcher.add_path(dir.path(), None).unwrap();
+
+    let path = dir.path().join("new.txt");
+    let written = Instant::now();
+    fs::write(&path, "x").unwrap();
+    let event = next_event(&events, &[]);
+    assert_eq!(event.path, path);
+    assert!(written.elapsed() < Duration::from_millis(DEFAULT_POLL_INTERVAL as u64));
+  }
+
+  #[test]
+  fn ready_is_delivered_before_changes() {
+    let dir = TempDir::new();
+    fs::write(dir.path().join("existing.txt"), "x").unwrap();
+    let (mut fs_watcher, events, _) = test_watcher(r#"{"emit_initial": true, "kinds": ["add"]}"#);
+    // Path is added the same way as add
+    fs_watcher.add_path(dir.path(), None).unwrap();
+    fs_watcher.emit_ready();
+
+    let path = dir.path().join("new.txt");
+    fs::write(&path, "x").unwrap();
+    let delivered: Vec<(String, PathBuf)> = (0..3)
+      .map(|_| next_event(&events, &[]))
+      .map(|event| (event.kind, event.path))
+      .collect();
+    assert_eq!(
+      delivered,
+      [
+        (String::from("add"), dir.path().join("existing.txt")),
+        (String::from("ready"), PathBuf::new()),
+        (String::from("add"), path),
+      ]
+    );
+  }
+
+  #[test]
+  fn rescan_flag_is_delivered_as_overflow() {
+    let (fs_watcher, events, _) = test_watcher(r#"{"kinds": ["add"]}"#);
+
+    inject(
+      &fs_watcher,
+      Ok(Event::new(EventKind::Other).set_flag(Flag::Rescan)),
+    );
+    let event = next_event(&events, &[]);
+    assert_eq!(event.kind, "overflow");
+    assert_eq!(event.path, PathBuf::new());
+
+    let dir = PathBuf::from("/watched/dir");
+    inject(
+      &fs_watcher,
+      Ok(
+        Event::new(EventKind::Other)
+          .set_flag(Flag::Rescan)
+          .add_path(dir.clone()),
+      ),
+    );
+    let event = next_event(&events, &[]);
+    assert_eq!(event.kind, "overflow");
+    assert_eq!(event.path, dir);
+  }
+
+  #[test]
+  fn backoff_doubles_up_to_cap() {
+    let max_backoff = Duration::from_millis(500);
+    let mut delay = Duration::from_millis(100);
+    let delays: Vec<u128> = (0..4)
+      .map(|_| {
+        delay = next_backoff(delay, max_backoff);
+        delay.as_millis()
+      })
+      .collect();
+    assert_eq!(delays, [200, 400, 500, 500]);
+
+    for _ in 0..100 {
+      let jittered = jitter(max_backoff);
+      assert!(jittered >= max_backoff / 2 && jittered <= max_backoff);
+    }
+  }
+
+  // Error of the poll watcher for the given path, such as the entry removed during the scan
+  fn poll_error(path: PathBuf) -> notify::Result<Event> {
+    Err(notify::Error::io(io::Error::from(io::ErrorKind::NotFound)).add_path(path))
+  }
+
+  #[test]
+  fn poll_error_streak_delivers_single_watch_error_and_recovered() {
+    let dir = TempDir::new();
+    let root = dir.path().join("watched");
+    let away = dir.path().join("away");
+    fs::create_dir(&root).unwrap();
+    let (mut fs_watcher, events, errors) =
+      test_watcher(r#"{"use_polling": true, "poll_interval": 200, "max_backoff_ms": 400}"#);
+    fs_watcher.add_path(&root, None).unwrap();
+
+    // Watched path is unreachable for several probes, during which the errors are dropped
+    let started = Instant::now();
+    inject(&fs_watcher, poll_error(root.join("gone.txt")));
+    thread::sleep(Duration::from_millis(100));
+    fs::rename(&root, &away).unwrap();
+    inject(&fs_watcher, poll_error(root.join("gone.txt")));
+    inject(&fs_watcher, poll_error(root.clone()));
+    thread::sleep(Duration::from_millis(1000));
+    fs::rename(&away, &root).unwrap();
+
+    let mut kinds = Vec::new();
+    while kinds.last().map(String::as_str) != Some("recovered") {
+      kinds.push(next_event(&events, &[]).kind);
+    }
+    assert!(started.elapsed() >= Duration::from_millis(1100));
+    assert_eq!(kinds, ["watchError", "recovered"]);
+    assert!(errors.try_recv().is_ok());
+    assert!(errors.try_recv().is_err());
+
+    // Polling resumes once recovered
+    let path = root.join("new.txt");
+    fs::write(&path, "x").unwrap();
+    let event = next_event(&events, &[]);
+    assert_eq!(event.path, path);
+  }
+
+  #[test]
+  fn poll_error_of_removed_entry_resumes_polling() {
+    let dir = TempDir::new();
+    let (mut fs_watcher, events, _) =
+      test_watcher(r#"{"use_polling": true, "poll_interval": 100, "max_backoff_ms": 200}"#);
+    fs_watcher.add_path(dir.path(), None).unwrap();
+
+    // Entry removed during the scan stays missing, while its watched path is reachable
+    inject(&fs_watcher, poll_error(dir.path().join("gone.txt")));
+    assert_eq!(next_event(&events, &[]).kind, "watchError");
+    assert_eq!(next_event(&events, &[]).kind, "recovered");
+
+    let path = dir.path().join("new.txt");
+    fs::write(&path, "x").unwrap();
+    assert_eq!(next_event(&events, &[]).path, path);
+  }
+
+  #[test]
+  fn lowered_poll_interval_delivers_events_sooner() {
+    let dir = TempDir::new();
+    let (mut fs_watcher, events, _) = test_watcher(r#"{"use_polling": true}"#);
+    fs_watcher.add_path(dir.path(), None).unwrap();
+    fs_watcher
+      .state()
+      .set_poll_interval(Duration::from_millis(100))
+      .unwrap();
+
+    let path = dir.path().join("new.txt");
+    let written = Instant::now();
+    fs::write(&path, "x").unwrap();
+    assert_eq!(next_event(&events, &[]).path, path);
+    assert!(written.elapsed() < Duration::from_millis(1000));
+  }
+
+  #[test]
+  fn poll_interval_cant_be_set_for_other_backends() {
+    let dir = TempDir::new();
+    let (mut fs_watcher, _, _) = test_watcher(r#"{"auto_detect_network": false}"#);
+    fs_watcher.add_path(dir.path(), None).unwrap();
+
+    let error = fs_watcher
+      .state()
+      .set_poll_interval(Duration::from_millis(100))
+      .unwrap_err();
+    assert!(error.reason.contains("can't be set"));
+  }
+
+  #[test]
+  fn poll_interval_below_floor_is_rejected() {
+    for options in [
+      r#"{"poll_interval": 1}"#,
+      r#"{"config": {"poll_interval": 99}}"#,
+    ] {
+      let options: WatchOptions = parse_options(options).unwrap();
+      let error = options.backend_config().unwrap_err();
+      assert_eq!(error.status, Status::InvalidArg);
+    }
+
+    let options: WatchOptions = parse_options(r#"{"poll_interval": 0}"#).unwrap();
+    let config = options.backend_config().unwrap();
+    assert_eq!(
+      config.poll_interval(),
+      Duration::from_millis(DEFAULT_POLL_INTERVAL as u64)
+    );
+  }
+
+  #[test]
+  #[cfg(target_os = "linux")]
+  fn traced_pids_evict_least_recently_modified() {
+    let mut pids = TracedPids::default();
+    for i in 0..MAX_TRACED_PIDS {
+      pids.insert(PathBuf::from(format!("/repo/file{}", i)), 1);
+    }
+    // Modified again, which keeps it over the paths modified once since
+    pids.insert(PathBuf::from("/repo/file0"), 2);
+    pids.insert(PathBuf::from("/repo/new"), 3);
+
+    assert_eq!(pids.pids.len(), MAX_TRACED_PIDS);
+    assert_eq!(pids.get(Path::new("/repo/file0")), Some(2));
+    assert_eq!(pids.get(Path::new("/repo/file1")), None);
+    assert_eq!(pids.get(Path::new("/repo/file2")), Some(1));
+    assert_eq!(pids.get(Path::new("/repo/new")), Some(3));
+  }
+
+  #[test]
+  #[cfg(target_os = "linux")]
+  fn traced_pid_is_writing_process() {
+    // Tracing requires the CAP_SYS_ADMIN capability
+    if PidTracer::new().is_err() {
+      eprintln!("skipped as fanotify is not permitted");
+      return;
+    }
+    let dir = TempDir::new();
+    let path = dir.path().join("file.txt");
+    fs::write(&path, "x").unwrap();
+    let (mut fs_watcher, events, _) = test_watcher(r#"{"trace_pid": true}"#);
+    fs_watcher.add_path(dir.path(), None).unwrap();
+
+    fs::write(&path, "y").unwrap();
+    let event = next_event(&events, &["watchError"]);
+    assert_eq!(event.kind, "modify");
+    assert_eq!(event.pid, Some(std::process::id()));
+  }
+
+  #[test]
+  fn unwatched_path_delivers_no_events() {
+    let (first, second) = (TempDir::new(), TempDir::new());
+    let (mut fs_watcher, events, _) = test_watcher("{}");
+    fs_watcher.add_path(first.path(), None).unwrap();
+    fs_watcher.add_path(second.path(), None).unwrap();
+    fs_watcher.state().unwatch_root(first.path()).unwrap();
+
+    assert!(!fs_watcher
+      .state()
+      .paths
+      .read()
+      .unwrap()
+      .contains(first.path()));
+    fs::write(first.path().join("a.txt"), "x").unwrap();
+    let path = second.path().join("b.txt");
+    fs::write(&path, "x").unwrap();
+    assert_eq!(next_event(&events, &[]).path, path);
+    assert!(events_within(&events, Duration::from_millis(200))
+      .iter()
+      .all(|event| event.path.starts_with(second.path())));
+  }
+
+  #[test]
+  fn unwatch_all_keeps_watcher_usable() {
+    let dirs = [TempDir::new(), TempDir::new(), TempDir::new()];
+    let (mut fs_watcher, events, _) = test_watcher("{}");
+    for dir in &dirs {
+      fs_watcher.add_path(dir.path(), None).unwrap();
+    }
+    fs_watcher.state().unwatch_all().unwrap();
+
+    assert!(fs_watcher.state().paths.read().unwrap().is_empty());
+    for dir in &dirs {
+      fs::write(dir.path().join("a.txt"), "x").unwrap();
+    }
+    assert!(events_within(&events, Duration::from_millis(200)).is_empty());
+
+    fs_watcher.add_path(dirs[0].path(), None).unwrap();
+    let path = dirs[0].path().join("b.txt");
+    fs::write(&path, "x").unwrap();
+    assert_eq!(next_event(&events, &[]).path, path);
+  }
+
+  // Watched directory containing the symlink to a directory outside of it
+  fn symlinked_tree() -> (TempDir, PathBuf, PathBuf) {
+    let dir = TempDir::new();
+    let root = dir.path().join("root");
+    let target = dir.path().join("target");
+    fs::create_dir(&root).unwrap();
+    fs::create_dir(&target).unwrap();
+    std::os::unix::fs::symlink(&target, root.join("link")).unwrap();
+    (dir, root, target)
+  }
+
+  #[test]
+  #[cfg(unix)]
+  fn followed_symlink_target_delivers_events() {
+    let (_dir, root, target) = symlinked_tree();
+    let (mut fs_watcher, events, _) = test_watcher(r#"{"follow_symlinks": true}"#);
+    fs_watcher.add_path(&root, None).unwrap();
+
+    fs::write(target.join("file.txt"), "x").unwrap();
+    let event = next_event(&events, &[]);
+    assert_eq!(event.kind, "add");
+    assert_eq!(event.path.file_name(), Some("file.txt".as_ref()));
+  }
+
+  #[test]
+  #[cfg(unix)]
+  fn symlinks_beyond_limit_are_reported() {
+    let (_dir, root, target) = symlinked_tree();
+    let (mut fs_watcher, events, errors) = test_watcher(r#"{"follow_symlinks": true}"#);
+    fs_watcher.state().link_targets.lock().unwrap().extend(
+      (0..MAX_LINK_TARGETS).map(|i| (PathBuf::from(format!("/links/{}", i)), PathBuf::new())),
+    );
+    fs_watcher.add_path(&root, None).unwrap();
+
+    let event = next_event(&events, &[]);
+    assert_eq!(event.kind, "watchError");
+    assert_eq!(event.path, root.join("link"));
+    assert!(errors.try_recv().unwrap().reason.contains("not followed"));
+    fs::write(target.join("file.txt"), "x").unwrap();
+    assert!(events_within(&events, Duration::from_millis(200)).is_empty());
+  }
+
+  // Waits for the change of the given path in another thread the same way as
+  // wait_for_change
+  fn wait_for(path: &Path, options: &str) -> mpsc::Receiver<Result<FsEvent>> {
+    let mut task = WaitForChange {
+      path: path.to_path_buf(),
+      options: parse_options(options).map_err(|e| e.reason.clone()),
+    };
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+      let _ = sender.send(task.compute());
+    });
+    receiver
+  }
+
+  #[test]
+  fn wait_for_change_resolves_on_touch() {
+    let dir = TempDir::new();
+    let result = wait_for(dir.path(), "{}");
+    thread::sleep(Duration::from_millis(200));
+
+    let path = dir.path().join("touched.txt");
+    fs::write(&path, "x").unwrap();
+    let event = result.recv_timeout(EVENT_TIMEOUT).unwrap().unwrap();
+    assert_eq!(event.kind, "add");
+    assert_eq!(event.path, path);
+  }
+
+  #[test]
+  fn wait_for_change_rejects_on_timeout() {
+    let dir = TempDir::new();
+    let result = wait_for(dir.path(), r#"{"timeout_ms": 200}"#);
+
+    let error = result.recv_timeout(EVENT_TIMEOUT).unwrap().err().unwrap();
+    assert!(error.reason.starts_with("Timed out"));
+  }
+
+  fn next_streamed(stream: &Arc<EventReceiver>) -> Option<FsEvent> {
+    NextEvent {
+      stream: Arc::clone(stream),
+    }
+    .compute()
+    .unwrap()
+  }
+
+  #[test]
+  fn event_iterator_yields_events_and_completes_on_close() {
+    let dir = TempDir::new();
+    let (mut fs_watcher, _events, _) = test_watcher("{}");
+    fs_watcher.add_path(dir.path(), None).unwrap();
+    let stream = Arc::new(EventReceiver {
+      receiver: Mutex::new(fs_watcher.subscribe(10).unwrap()),
+      closed: AtomicBool::new(false),
+    });
+
+    let path = dir.path().join("a.txt");
+    fs::write(&path, "x").unwrap();
+    let event = next_streamed(&stream).unwrap();
+    assert_eq!((event.kind.as_str(), &event.path), ("add", &path));
+
+    close_watcher(&fs_watcher.state, &fs_watcher.dispatcher, false);
+    while next_streamed(&stream).is_some() {}
+    assert!(fs_watcher.subscribe(10).is_err());
+  }
+
+  #[test]
+  fn event_iterator_reports_dropped_events_as_overflow() {
+    let (fs_watcher, _events, _) = test_watcher("{}");
+    let receiver = fs_watcher.subscribe(2).unwrap();
+    let dispatcher = fs_watcher.dispatcher.upgrade().unwrap();
+    let event = |name: &str| FsEvent::new(String::from("add"), PathBuf::from(name), 0);
+    for name in ["a", "b", "c", "d"] {
+      dispatcher.deliver(event(name));
+    }
+
+    let kinds = |receiver: &mpsc::Receiver<FsEvent>| -> Vec<(String, PathBuf)> {
+      receiver
+        .try_iter()
+        .map(|event| (event.kind, event.path))
+        .collect()
+    };
+    assert_eq!(
+      kinds(&receiver),
+      [("add".into(), "a".into()), ("add".into(), "b".into())]
+    );
+    dispatcher.deliver(event("e"));
+    assert_eq!(
+      kinds(&receiver),
+      [("overflow".into(), "".into()), ("add".into(), "e".into())]
+    );
+  }
+
+  #[test]
+  fn closed_event_iterator_ends_without_events() {
+    let (fs_watcher, _events, _) = test_watcher("{}");
+    let stream = Arc::new(EventReceiver {
+      receiver: Mutex::new(fs_watcher.subscribe(10).unwrap()),
+      closed: AtomicBool::new(false),
+    });
+    let pending = {
+      let stream = Arc::clone(&stream);
+      thread::spawn(move || next_streamed(&stream))
+    };
+    stream.closed.store(true, Ordering::SeqCst);
+    assert!(pending.join().unwrap().is_none());
+  }
+
+  fn capability_set(capabilities: Capabilities) -> [bool; 4] {
+    [
+      capabilities.recursive,
+      capabilities.rename_correlation,
+      capabilities.access_events,
+      capabilities.poll_only,
+    ]
+  }
+
+  #[test]
+  fn capabilities_of_recommended_watcher() {
+    let dir = TempDir::new();
+    let (mut fs_watcher, _, _) = test_watcher("{}");
+    fs_watcher.network_probe = |_| false;
+    fs_watcher.add_path(dir.path(), None).unwrap();
+    let capabilities = Capabilities::of(fs_watcher.state().backend);
+
+    #[cfg(target_os = "linux")]
+    assert_eq!(capability_set(capabilities), [true, true, true, false]);
+    #[cfg(target_os = "macos")]
+    assert_eq!(capability_set(capabilities), [true, false, false, false]);
+    #[cfg(target_os = "windows")]
+    assert_eq!(capability_set(capabilities), [true, true, false, false]);
+    #[cfg(any(target_os = "freebsd", target_os = "openbsd", target_os = "netbsd"))]
+    assert_eq!(capability_set(capabilities), [true, false, false, false]);
+  }
+
+  #[test]
+  fn capabilities_of_poll_watcher() {
+    let dir = TempDir::new();
+    let (mut fs_watcher, _, _) = test_watcher(r#"{"use_polling": true}"#);
+    fs_watcher.add_path(dir.path(), None).unwrap();
+    let capabilities = Capabilities::of(fs_watcher.state().backend);
+
+    assert_eq!(capability_set(capabilities), [true, false, false, true]);
+  }
+
+  #[test]
+  fn recursive_watch_delivers_events_of_nested_dir_created_later() {
+    let dir = TempDir::new();
+    let (mut fs_watcher, events, _) = test_watcher(r#"{"recursive": true}"#);
+    fs_watcher.add_path(dir.path(), None).unwrap();
+
+    let nested = dir.path().join("a").join("b");
+    fs::create_dir_all(&nested).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    let path = nested.join("c.txt");
+    fs::write(&path, "x").unwrap();
+
+    let delivered = events_within(&events, Duration::from_millis(500));
+    assert!(delivered
+      .iter()
+      .any(|event| event.kind == "add" && event.path == path));
+  }
+
+  #[test]
+  fn state_tasks_are_queued_once_and_run_when_due() {
+    let tasks = StateTasks::default();
+    let delay = Duration::from_millis(200);
+    tasks.push(StateTask::Rewatch(Vec::new()), delay);
+    tasks.push(StateTask::Rewatch(Vec::new()), delay);
+    tasks.push(StateTask::WatchSubdirs(PathBuf::from("a")), Duration::ZERO);
+    tasks.push(StateTask::WatchSubdirs(PathBuf::from("b")), Duration::ZERO);
+
+    assert!(
+      tasks.due(Duration::ZERO)
+        == [
+          StateTask::WatchSubdirs(PathBuf::from("a")),
+          StateTask::WatchSubdirs(PathBuf::from("b")),
+        ]
+    );
+    assert!(tasks.due(EVENT_TIMEOUT) == [StateTask::Rewatch(Vec::new())]);
+    assert!(tasks.due(Duration::ZERO).is_empty());
+  }
+
+  #[test]
+  fn file_rename_is_delivered_as_single_rename() {
+    let dir = TempDir::new();
+    let from = dir.path().join("old.txt");
+    let to = dir.path().join("new.txt");
+    fs::write(&from, "x").unwrap();
+    let (mut fs_watcher, events, _) = test_watcher("{}");
+    fs_watcher.add_path(dir.path(), None).unwrap();
+
+    fs::rename(&from, &to).unwrap();
+    let delivered = events_within(&events, Duration::from_millis(500));
+    let renames: Vec<&FsEvent> = delivered
+      .iter()
+      .filter(|event| event.kind == "rename")
+      .collect();
+    assert_eq!(renames.len(), 1);
+    assert_eq!(renames[0].path, to);
+    assert_eq!(renames[0].old_path.as_ref(), Some(&from));
+    assert!(delivered
+      .iter()
+      .all(|event| !matches!(event.kind.as_str(), "add" | "remove")));
+  }
+
+  #[test]
+  fn uncorrelated_rename_from_is_delivered_as_remove() {
+    let (fs_watcher, events, _) = test_watcher("{}");
+    let path = PathBuf::from("/watched/moved-away.txt");
+
+    inject(
+      &fs_watcher,
+      Ok(
+        Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::From)))
+          .add_path(path.clone())
+          .set_tracker(1),
+      ),
+    );
+    let event = next_event(&events, &[]);
+    assert_eq!((event.kind.as_str(), &event.path), ("remove", &path));
+    assert!(event.old_path.is_none());
+  }
+
+  #[test]
+  fn closed_watcher_delivers_no_events_and_rejects_calls() {
+    let dir = TempDir::new();
+    let (mut fs_watcher, events, _) = test_watcher("{}");
+    fs_watcher.add_path(dir.path(), None).unwrap();
+
+    close_watcher(&fs_watcher.state, &fs_watcher.dispatcher, false);
+    fs::write(dir.path().join("a.txt"), "x").unwrap();
+    assert!(events_within(&events, Duration::from_millis(300)).is_empty());
+    assert!(fs_watcher.add_path(dir.path(), None).is_err());
+    assert!(fs_watcher.state().unwatch_all().is_err());
+  }
+
+  #[test]
+  fn unwatched_path_is_not_listed() {
+    let dirs = [TempDir::new(), TempDir::new(), TempDir::new()];
+    let (mut fs_watcher, _, _) = test_watcher("{}");
+    for dir in &dirs {
+      fs_watcher.add_path(dir.path(), None).unwrap();
+    }
+    fs_watcher.state().unwatch_root(dirs[1].path()).unwrap();
+
+    let mut expected = vec![dirs[0].path().to_path_buf(), dirs[2].path().to_path_buf()];
+    expected.sort();
+    assert_eq!(fs_watcher.state().watched_paths(), expected);
+  }
+
+  #[test]
+  fn changes_while_paused_are_dropped() {
+    let dir = TempDir::new();
+    let (mut fs_watcher, events, _) = test_watcher("{}");
+    fs_watcher.add_path(dir.path(), None).unwrap();
+
+    fs_watcher.paused.store(true, Ordering::Relaxed);
+    fs::write(dir.path().join("paused.txt"), "x").unwrap();
+    thread::sleep(Duration::from_millis(200));
+    fs_watcher.paused.store(false, Ordering::Relaxed);
+    assert!(events_within(&events, Duration::from_millis(200)).is_empty());
+
+    let path = dir.path().join("resumed.txt");
+    fs::write(&path, "x").unwrap();
+    assert_eq!(next_event(&events, &[]).path, path);
+  }
+
+  #[test]
+  fn add_all_watches_valid_paths_and_names_failed_ones() {
+    let dirs = [TempDir::new(), TempDir::new()];
+    let missing = dirs[0].path().join("missing");
+    let (mut fs_watcher, events, _) = test_watcher("{}");
+
+    let error = fs_watcher
+      .add_paths(&[
+        dirs[0].path().to_path_buf(),
+        missing.clone(),
+        dirs[1].path().to_path_buf(),
+      ])
+      .err()
+      .unwrap();
+    assert!(error.reason.starts_with("Failed to watch paths: "));
+    assert!(error.reason.contains(&missing.display().to_string()));
+    assert_eq!(fs_watcher.state().paths.read().unwrap().len(), dirs.len());
+
+    assert_eq!(next_event(&events, &[]).kind, "ready");
+    let path = dirs[1].path().join("a.txt");
+    fs::write(&path, "x").unwrap();
+    assert_eq!(next_event(&events, &[]).path, path);
+  }
+
+  #[test]
+  fn quick_writes_are_delivered_in_one_batch() {
+    let dir = TempDir::new();
+    let (sender, batches) = mpsc::channel();
+    let (mut fs_watcher, _) = watcher_with_callback(r#"{"batch_ms": 300}"#, move |batch| {
+      let _ = sender.send(batch);
+    });
+    fs_watcher.add_path(dir.path(), None).unwrap();
+
+    let paths: Vec<PathBuf> = (0..10)
+      .map(|i| dir.path().join(format!("{}.txt", i)))
+      .collect();
+    for path in &paths {
+      fs::write(path, "x").unwrap();
+    }
+    let batch = batches.recv_timeout(EVENT_TIMEOUT).unwrap();
+    let added: Vec<&PathBuf> = batch
+      .iter()
+      .filter(|event| event.kind == "add")
+      .map(|event| &event.path)
+      .collect();
+    assert_eq!(added, paths.iter().collect::<Vec<_>>());
+  }
+
+  #[test]
+  fn batched_events_are_flushed_on_close() {
+    let (sender, batches) = mpsc::channel();
+    let (fs_watcher, _) = watcher_with_callback(r#"{"batch_ms": 60000}"#, move |batch| {
+      let _ = sender.send(batch);
+    });
+    let path = PathBuf::from("/watched/new.txt");
+    inject(
+      &fs_watcher,
+      Ok(Event::new(EventKind::Create(CreateKind::File)).add_path(path.clone())),
+    );
+
+    close_watcher(&fs_watcher.state, &fs_watcher.dispatcher, false);
+    let batch = batches.recv_timeout(EVENT_TIMEOUT).unwrap();
+    assert_eq!(batch.len(), 1);
+    assert_eq!(batch[0].path, path);
+  }
+
+  #[test]
+  fn existing_files_are_delivered_as_initial_adds() {
+    let dir = TempDir::new();
+    let paths = [dir.path().join("a.txt"), dir.path().join("b.txt")];
+    for path in &paths {
+      fs::write(path, "x").unwrap();
+    }
+    let modified = system_time_nanos(fs::metadata(&paths[0]).unwrap().modified().unwrap());
+    let (mut fs_watcher, events, _) = test_watcher(r#"{"emit_initial": true}"#);
+    fs_watcher.add_path(dir.path(), None).unwrap();
+
+    let mut delivered: Vec<FsEvent> = events.try_iter().collect();
+    delivered.sort_by(|a, b| a.path.cmp(&b.path));
+    let added: Vec<(&str, &PathBuf)> = delivered
+      .iter()
+      .map(|event| (event.kind.as_str(), &event.path))
+      .collect();
+    assert_eq!(added, [("add", &paths[0]), ("add", &paths[1])]);
+    assert_eq!(delivered[0].ts, modified);
+  }
+
+  #[test]
+  fn changes_beyond_max_depth_are_not_delivered() {
+    let dir = TempDir::new();
+    let deep = dir.path().join("a").join("b").join("c");
+    fs::create_dir_all(&deep).unwrap();
+    let (mut fs_watcher, events, _) = test_watcher(r#"{"recursive": true, "max_depth": 3}"#);
+    fs_watcher.add_path(dir.path(), None).unwrap();
+
+    fs::write(deep.join("depth4.txt"), "x").unwrap();
+    let shallow = dir.path().join("a").join("depth2.txt");
+    fs::write(&shallow, "x").unwrap();
+    let delivered = events_within(&events, Duration::from_millis(300));
+    assert!(delivered.iter().any(|event| event.path == shallow));
+    assert!(delivered.iter().all(|event| !event.path.starts_with(&deep)));
+
+    // Directory created within the depth is watched as well
+    let created = dir.path().join("a").join("new");
+    fs::create_dir(&created).unwrap();
+    thread::sleep(Duration::from_millis(200));
+    let path = created.join("depth3.txt");
+    fs::write(&path, "x").unwrap();
+    let delivered = events_within(&events, Duration::from_millis(300));
+    assert!(delivered.iter().any(|event| event.path == path));
+
+    // Subdirectories are unwatched along with the watched path
+    fs_watcher.state().unwatch_root(dir.path()).unwrap();
+    fs::write(&shallow, "y").unwrap();
+    assert!(events_within(&events, Duration::from_millis(300)).is_empty());
+  }
+
+  #[test]
+  fn removed_subdirectory_is_delivered_as_remove_dir() {
+    let dir = TempDir::new();
+    let sub = dir.path().join("sub");
+    fs::create_dir(&sub).unwrap();
+    let (mut fs_watcher, events, _) = test_watcher("{}");
+    fs_watcher.add_path(dir.path(), None).unwrap();
+
+    fs::remove_dir(&sub).unwrap();
+    let event = next_event(&events, &[]);
+    assert_eq!((event.kind.as_str(), &event.path), ("removeDir", &sub));
+  }
+
+  #[test]
+  fn directory_type_is_cached_from_create_until_removal() {
+    let (fs_watcher, events, _) = test_watcher(r#"{"dedup": false}"#);
+    let dir = PathBuf::from("/watched/gone-dir");
+    let file = PathBuf::from("/watched/gone-file");
+    let event = |kind: EventKind, path: &PathBuf| Ok(Event::new(kind).add_path(path.clone()));
+
+    // Paths are removed before their events are handled, as in rapid create and remove
+    inject(
+      &fs_watcher,
+      event(EventKind::Create(CreateKind::Folder), &dir),
+    );
+    inject(
+      &fs_watcher,
+      event(EventKind::Create(CreateKind::File), &file),
+    );
+    inject(&fs_watcher, event(EventKind::Remove(RemoveKind::Any), &dir));
+    inject(
+      &fs_watcher,
+      event(EventKind::Remove(RemoveKind::Any), &file),
+    );
+    let kinds: Vec<String> = (0..4).map(|_| next_event(&events, &[]).kind).collect();
+    assert_eq!(kinds, ["addDir", "add", "removeDir", "remove"]);
+
+    let file_types = fs_watcher.file_types.lock().unwrap();
+    assert_eq!(file_types.get(&dir), None);
+    assert_eq!(file_types.get(&file), None);
+  }
+
+  #[test]
+  fn created_directory_is_delivered_as_add_dir() {
+    let dir = TempDir::new();
+    let (mut fs_watcher, events, _) = test_watcher("{}");
+    fs_watcher.add_path(dir.path(), None).unwrap();
+
+    let sub = dir.path().join("sub");
+    fs::create_dir(&sub).unwrap();
+    let event = next_event(&events, &[]);
+    assert_eq!((event.kind.as_str(), &event.path), ("addDir", &sub));
+    assert_eq!(fs_watcher.file_types.lock().unwrap().get(&sub), Some(true));
+  }
+
+  #[test]
+  fn atomic_save_is_delivered_as_single_modify() {
+    let dir = TempDir::new();
+    let path = dir.path().join("doc.txt");
+    let temp = dir.path().join("doc.txt.tmp");
+    fs::write(&path, "old").unwrap();
+    let (mut fs_watcher, events, _) = test_watcher("{}");
+    fs_watcher.add_path(dir.path(), None).unwrap();
+
+    fs::write(&temp, "new").unwrap();
+    fs::rename(&temp, &path).unwrap();
+    let delivered: Vec<FsEvent> = events_within(&events, Duration::from_millis(500))
+      .into_iter()
+      .filter(|event| event.path == path)
+      .collect();
+    assert_eq!(delivered.len(), 1);
+    assert_eq!(delivered[0].kind, "modify");
+    assert!(delivered[0].atomic);
+    assert_eq!(delivered[0].old_path.as_ref(), Some(&temp));
+  }
+
+  #[test]
+  fn modify_burst_is_coalesced_into_one_trailing_event() {
+    let dir = TempDir::new();
+    let path = dir.path().join("file.txt");
+    fs::write(&path, "x").unwrap();
+    let (mut fs_watcher, events, _) =
+      test_watcher(r#"{"coalesce_modify": true, "debounce_ms": 200}"#);
+    fs_watcher.add_path(dir.path(), None).unwrap();
+
+    for content in ["a", "b", "c"] {
+      fs::write(&path, content).unwrap();
+    }
+    let written = Instant::now();
+    let event = next_event(&events, &[]);
+    assert_eq!((event.kind.as_str(), &event.path), ("modify", &path));
+    assert!(written.elapsed() >= Duration::from_millis(150));
+    assert!(events_within(&events, Duration::from_millis(400)).is_empty());
+  }
+
+  #[test]
+  fn non_modify_events_pass_coalescing_right_away() {
+    let (fs_watcher, events, _) =
+      test_watcher(r#"{"coalesce_modify": true, "debounce_ms": 60000}"#);
+    let path = PathBuf::from("/watched/new.txt");
+
+    inject(
+      &fs_watcher,
+      Ok(Event::new(EventKind::Create(CreateKind::File)).add_path(path.clone())),
+    );
+    let event = events.recv_timeout(Duration::from_millis(500)).unwrap();
+    assert_eq!((event.kind.as_str(), &event.path), ("add", &path));
+  }
+
+  #[test]
+  fn once_delivers_first_allowed_event_and_closes() {
+    let dir = TempDir::new();
+    let (mut fs_watcher, events, _) = test_watcher(r#"{"once": true, "ignore": ["**/*.log"]}"#);
+    fs_watcher.add_path(dir.path(), None).unwrap();
+
+    fs::write(dir.path().join("ignored.log"), "x").unwrap();
+    thread::sleep(Duration::from_millis(100));
+    let path = dir.path().join("first.txt");
+    fs::write(&path, "x").unwrap();
+    let event = next_event(&events, &[]);
+    assert_eq!((event.kind.as_str(), &event.path), ("add", &path));
+
+    thread::sleep(Duration::from_millis(100));
+    fs::write(dir.path().join("second.txt"), "x").unwrap();
+    assert!(events_within(&events, Duration::from_millis(300)).is_empty());
+    assert!(fs_watcher.state().watcher().is_err());
+  }
+
+  #[test]
+  fn idle_watcher_stops_with_idle_timeout_event() {
+    let dir = TempDir::new();
+    let (mut fs_watcher, events, _) = test_watcher(r#"{"idle_timeout_ms": 300}"#);
+    fs_watcher.add_path(dir.path(), None).unwrap();
+
+    // Delivered event resets the timer
+    thread::sleep(Duration::from_millis(200));
+    let path = dir.path().join("a.txt");
+    fs::write(&path, "x").unwrap();
+    let written = Instant::now();
+    assert_eq!(next_event(&events, &["modify"]).path, path);
+
+    let event = next_event(&events, &["modify"]);
+    assert_eq!(
+      (event.kind.as_str(), &event.path),
+      ("idleTimeout", &PathBuf::new())
+    );
+    assert!(written.elapsed() >= Duration::from_millis(250));
+    assert!(fs_watcher.state().watcher().is_err());
+  }
+
+  #[test]
+  fn pending_file_is_delivered_once_created() {
+    let dir = TempDir::new();
+    let config_dir = dir.path().join("myapp");
+    let path = config_dir.join("config.toml");
+    let (mut fs_watcher, events, _) = test_watcher(r#"{"watch_pending": true}"#);
+    fs_watcher.add_path(&path, None).unwrap();
+    assert!(fs_watcher
+      .state()
+      .pending
+      .lock()
+      .unwrap()
+      .contains_key(&path));
+
+    fs::create_dir(&config_dir).unwrap();
+    thread::sleep(Duration::from_millis(200));
+    fs::write(&path, "x").unwrap();
+    let event = next_event(&events, &["addDir"]);
+    assert_eq!((event.kind.as_str(), &event.path), ("add", &path));
+
+    thread::sleep(Duration::from_millis(200));
+    assert!(fs_watcher.state().pending.lock().unwrap().is_empty());
+    assert!(fs_watcher.state().paths.read().unwrap().contains(&path));
+  }
+
+  #[test]
+  fn removed_watched_dir_is_delivered_as_gone_and_rewatched() {
+    let parent = TempDir::new();
+    let dir = parent.path().join("watched");
+    fs::create_dir(&dir).unwrap();
+    let (mut fs_watcher, events, _) = test_watcher(r#"{"watch_pending": true}"#);
+    fs_watcher.add_path(&dir, None).unwrap();
+
+    fs::remove_dir(&dir).unwrap();
+    let event = next_event(&events, &["removeDir", "remove"]);
+    assert_eq!(
+      (event.kind.as_str(), &event.path),
+      ("watchedPathGone", &dir)
+    );
+
+    // Recreated directory is watched again
+    fs::create_dir(&dir).unwrap();
+    thread::sleep(Duration::from_millis(200));
+    let path = dir.join("a.txt");
+    fs::write(&path, "x").unwrap();
+    let delivered = events_within(&events, Duration::from_millis(500));
+    assert!(delivered.iter().any(|event| event.path == path));
+  }
+
+  #[test]
+  fn unreadable_subdirectory_is_skipped_with_watch_error() {
+    use std::os::unix::fs::PermissionsExt;
+
+    // Permissions are not enforced for root
+    if unsafe { libc::geteuid() } == 0 {
+      eprintln!("skipped as permissions are not enforced for root");
+      return;
+    }
+    let dir = TempDir::new();
+    let readable = dir.path().join("readable");
+    let unreadable = dir.path().join("unreadable");
+    fs::create_dir(&readable).unwrap();
+    fs::create_dir(&unreadable).unwrap();
+    fs::set_permissions(&unreadable, fs::Permissions::from_mode(0o000)).unwrap();
+    let (mut fs_watcher, events, _) = test_watcher(r#"{"recursive": true}"#);
+    let added = fs_watcher.add_path(dir.path(), None);
+    fs::set_permissions(&unreadable, fs::Permissions::from_mode(0o755)).unwrap();
+
+    added.unwrap();
+    let event = next_event(&events, &[]);
+    assert_eq!(
+      (event.kind.as_str(), &event.path),
+      ("watchError", &unreadable)
+    );
+    let path = readable.join("a.txt");
+    fs::write(&path, "x").unwrap();
+    assert_eq!(next_event(&events, &[]).path, path);
+  }
+
+  #[test]
+  fn shared_watchers_route_events_and_share_watches() {
+    let (root, own) = (TempDir::new(), [TempDir::new(), TempDir::new()]);
+    let mut watchers: Vec<(FsWatcher, mpsc::Receiver<FsEvent>)> = own
+      .iter()
+      .map(|dir| {
+        let (mut fs_watcher, events, _) = test_watcher(r#"{"shared": true}"#);
+        fs_watcher.network_probe = |_| false;
+        fs_watcher.add_path(root.path(), None).unwrap();
+        fs_watcher.add_path(dir.path(), None).unwrap();
+        (fs_watcher, events)
+      })
+      .collect();
+    let routes = |path: &Path| SHARED_ROUTES.read().unwrap().get(path).map_or(0, Vec::len);
+    assert_eq!(routes(root.path()), 2);
+
+    // Events of the own paths are routed only to their watcher
+    for (i, dir) in own.iter().enumerate() {
+      let path = dir.path().join("own.txt");
+      fs::write(&path, "x").unwrap();
+      let delivered = events_within(&watchers[i].1, Duration::from_millis(300));
+      assert!(delivered.iter().all(|event| event.path == path));
+      assert!(!delivered.is_empty());
+      assert!(watchers[1 - i].1.try_recv().is_err());
+    }
+    let path = root.path().join("shared.txt");
+    fs::write(&path, "x").unwrap();
+    for (_, events) in &watchers {
+      assert_eq!(next_event(events, &["modify"]).path, path);
+    }
+
+    // Closing one keeps the other watching the shared path
+    let (closed, _) = watchers.remove(0);
+    close_watcher(&closed.state, &closed.dispatcher, false);
+    assert_eq!(routes(root.path()), 1);
+    assert_eq!(routes(own[0].path()), 0);
+    let path = root.path().join("after-close.txt");
+    fs::write(&path, "x").unwrap();
+    assert_eq!(next_event(&watchers[0].1, &["modify"]).path, path);
+  }
+
+  #[test]
+  fn watcher_is_driven_by_id_until_closed() {
+    let dir = TempDir::new();
+    let (fs_watcher, events, _) = test_watcher("{}");
+    let id = register_watcher(&fs_watcher);
+
+    let mut by_id = registered_watcher(id).unwrap();
+    by_id.add_path(dir.path(), None).unwrap();
+    assert!(fs_watcher
+      .state()
+      .paths
+      .read()
+      .unwrap()
+      .contains(dir.path()));
+    let path = dir.path().join("a.txt");
+    fs::write(&path, "x").unwrap();
+    assert_eq!(next_event(&events, &[]).path, path);
+
+    close_watcher(&by_id.state, &by_id.dispatcher, false);
+    let error = registered_watcher(id).err().unwrap();
+    assert_eq!(error.status, Status::InvalidArg);
+    assert_eq!(
+      error.reason,
+      format!("No watcher is registered with id {}", id)
+    );
+    assert!(!WATCHERS.lock().unwrap().contains_key(&id));
+  }
+
+  #[test]
+  fn created_and_rewritten_file_settles_into_one_add() {
+    let dir = TempDir::new();
+    let (mut fs_watcher, events, _) = test_watcher(r#"{"settle": true, "debounce_ms": 200}"#);
+    fs_watcher.add_path(dir.path(), None).unwrap();
+
+    let path = dir.path().join("thumb.png");
+    for content in ["a", "b", "c"] {
+      fs::write(&path, content).unwrap();
+    }
+    let written = Instant::now();
+    let event = next_event(&events, &[]);
+    assert_eq!((event.kind.as_str(), &event.path), ("add", &path));
+    assert!(written.elapsed() >= Duration::from_millis(150));
+    assert!(events_within(&events, Duration::from_millis(400)).is_empty());
+  }
+
+  #[test]
+  fn settled_kind_reflects_net_effect() {
+    let dir = TempDir::new();
+    let existing = dir.path().join("existing.txt");
+    let created = dir.path().join("created.txt");
+    fs::write(&existing, "x").unwrap();
+    let (mut fs_watcher, events, _) = test_watcher(r#"{"settle": true, "debounce_ms": 200}"#);
+    fs_watcher.add_path(dir.path(), None).unwrap();
+
+    fs::write(&existing, "y").unwrap();
+    fs::write(&created, "x").unwrap();
+    fs::remove_file(&created).unwrap();
+    let delivered: Vec<(String, PathBuf)> = events_within(&events, Duration::from_millis(600))
+      .into_iter()
+      .map(|event| (event.kind, event.path))
+      .collect();
+    assert_eq!(delivered, [(String::from("modify"), existing.clone())]);
+
+    fs::remove_file(&existing).unwrap();
+    let event = next_event(&events, &[]);
+    assert_eq!((event.kind.as_str(), &event.path), ("remove", &existing));
+  }
+
+  #[test]
+  fn flush_delivers_held_events_before_returning() {
+    let (fs_watcher, events, _) = test_watcher(
+      r#"{"batch_ms": 60000, "coalesce_modify": true, "settle": false, "debounce_ms": 60000}"#,
+    );
+    let added = PathBuf::from("/watched/added.txt");
+    let dir = TempDir::new();
+    let modified = dir.path().join("modified.txt");
+    fs::write(&modified, "x").unwrap();
+    inject(
+      &fs_watcher,
+      Ok(Event::new(EventKind::Create(CreateKind::File)).add_path(added.clone())),
+    );
+    inject(
+      &fs_watcher,
+      Ok(
+        Event::new(EventKind::Modify(ModifyKind::Data(
+          notify::event::DataChange::Content,
+        )))
+        .add_path(modified.clone()),
+      ),
+    );
+    assert!(events.try_recv().is_err());
+
+    fs_watcher.dispatcher.upgrade().unwrap().drain();
+    let delivered: Vec<(String, PathBuf)> = events
+      .try_iter()
+      .map(|event| (event.kind, event.path))
+      .collect();
+    assert_eq!(
+      delivered,
+      [
+        (String::from("add"), added),
+        (String::from("modify"), modified),
+      ]
+    );
+  }
+
+  #[test]
+  fn close_dropping_pending_discards_held_events() {
+    let (fs_watcher, events, _) = test_watcher(r#"{"batch_ms": 60000}"#);
+    inject(
+      &fs_watcher,
+      Ok(Event::new(EventKind::Create(CreateKind::File)).add_path(PathBuf::from("/watched/a"))),
+    );
+
+    close_watcher(&fs_watcher.state, &fs_watcher.dispatcher, true);
+    assert!(events_within(&events, Duration::from_millis(200)).is_empty());
+  }
+
+  #[test]
+  fn malformed_or_unknown_options_are_rejected() {
+    for (options, reason) in [
+      (r#"{"use_poling": true}"#, "unknown field `use_poling`"),
+      (r#"{"use_polling": "yes"}"#, "invalid type"),
+      (r#"{"use_polling": true"#, "EOF"),
+    ] {
+      let error = parse_options::<WatchOptions>(options).unwrap_err();
+      assert_eq!(error.status, Status::InvalidArg);
+      assert!(
+        error.reason.starts_with("Invalid options: "),
+        "{}",
+        error.reason
+      );
+      assert!(error.reason.contains(reason), "{}", error.reason);
+    }
+  }
+
+  #[test]
+  fn empty_or_null_options_fall_back_to_defaults() {
+    for options in ["", "  ", "null"] {
+      let options: WatchOptions = parse_options(options).unwrap();
+      assert!(!options.use_polling);
+      assert_eq!(options.debounce_ms, WatchOptions::default().debounce_ms);
+    }
+  }
+
+  // Injects the burst of modify events of the file, and returns the events delivered
+  // right away and the ones delivered once the path is quiet
+  fn debounce_burst(edge: &str) -> (Vec<FsEvent>, Vec<FsEvent>) {
+    let dir = TempDir::new();
+    let path = dir.path().join("file.txt");
+    fs::write(&path, "x").unwrap();
+    let (fs_watcher, events, _) = test_watcher(&format!(
+      r#"{{"debounce_ms": 300, "debounce_edge": "{}", "settle": false}}"#,
+      edge
+    ));
+    for _ in 0..3 {
+      inject(
+        &fs_watcher,
+        Ok(
+          Event::new(EventKind::Modify(ModifyKind::Data(
+            notify::event::DataChange::Content,
+          )))
+          .add_path(path.clone()),
+        ),
+      );
+    }
+    let first = events_within(&events, Duration::from_millis(100));
+    let last = events_within(&events, Duration::from_millis(700));
+    for event in first.iter().chain(&last) {
+      assert_eq!((event.kind.as_str(), &event.path), ("modify", &path));
+    }
+    (first, last)
+  }
+
+  #[test]
+  fn leading_debounce_delivers_first_event_of_burst() {
+    let (first, last) = debounce_burst("leading");
+    assert_eq!((first.len(), last.len()), (1, 0));
+  }
+
+  #[test]
+  fn trailing_debounce_delivers_last_event_of_burst_once_quiet() {
+    let (first, last) = debounce_burst("trailing");
+    assert_eq!((first.len(), last.len()), (0, 1));
+  }
+
+  #[test]
+  fn both_edges_debounce_delivers_first_and_last_events_of_burst() {
+    let (first, last) = debounce_burst("both");
+    assert_eq!((first.len(), last.len()), (1, 1));
+    assert!(first[0].seq < last[0].seq);
+  }
+
+  #[test]
+  fn watched_file_delivers_only_its_own_events() {
+    let dir = TempDir::new();
+    let file = dir.path().join("config.json");
+    let sibling = dir.path().join("other.json");
+    fs::write(&file, "{}").unwrap();
+    fs::write(&sibling, "{}").unwrap();
+    let (mut fs_watcher, events, _) = test_watcher("");
+    fs_watcher.add_file(&file).unwrap();
+    assert_eq!(
+      fs_watcher.state().watched_paths(),
+      std::slice::from_ref(&file)
+    );
+
+    fs::write(&sibling, "{\"a\": 1}").unwrap();
+    fs::write(dir.path().join("new.json"), "{}").unwrap();
+    fs::write(&file, "{\"a\": 1}").unwrap();
+    let delivered = events_within(&events, Duration::from_millis(500));
+    assert!(!delivered.is_empty());
+    for event in &delivered {
+      assert_eq!((event.kind.as_str(), &event.path), ("modify", &file));
+    }
+  }
+
+  #[test]
+  fn dedup_kinds_limits_suppression_to_listed_kinds() {
+    let dir = TempDir::new();
+    let modified = dir.path().join("modified.txt");
+    fs::write(&modified, "x").unwrap();
+    let removed = PathBuf::from("/watched/removed.txt");
+    let (fs_watcher, events, _) =
+      test_watcher(r#"{"debounce_ms": 60000, "dedup_kinds": ["modify"], "settle": false}"#);
+    for _ in 0..2 {
+      inject(
+        &fs_watcher,
+        Ok(
+          Event::new(EventKind::Modify(ModifyKind::Data(
+            notify::event::DataChange::Content,
+          )))
+          .add_path(modified.clone()),
+        ),
+      );
+      inject(
+        &fs_watcher,
+        Ok(Event::new(EventKind::Remove(RemoveKind::File)).add_path(removed.clone())),
+      );
+    }
+
+    let delivered: Vec<(String, PathBuf)> = events_within(&events, Duration::from_millis(300))
+      .into_iter()
+      .map(|event| (event.kind, event.path))
+      .collect();
+    assert_eq!(
+      delivered,
+      [
+        (String::from("modify"), modified),
+        (String::from("remove"), removed.clone()),
+        (String::from("remove"), removed),
+      ]
+    );
+  }
+
+  #[test]
+  fn prefix_filter_suppresses_events_outside_prefixes_until_cleared() {
+    let dir = TempDir::new();
+    let focused = dir.path().join("focused");
+    let other = dir.path().join("other");
+    fs::create_dir(&focused).unwrap();
+    fs::create_dir(&other).unwrap();
+    let (mut fs_watcher, events, _) = test_watcher(r#"{"recursive": true}"#);
+    fs_watcher.add_path(dir.path(), None).unwrap();
+
+    *fs_watcher.prefixes.write().unwrap() = vec![focused.clone()];
+    fs::write(other.join("outside.txt"), "x").unwrap();
+    let inside = focused.join("inside.txt");
+    fs::write(&inside, "x").unwrap();
+    let delivered = events_within(&events, Duration::from_millis(300));
+    assert!(!delivered.is_empty());
+    assert!(delivered.iter().all(|event| event.path == inside));
+
+    fs_watcher.prefixes.write().unwrap().clear();
+    let outside = other.join("cleared.txt");
+    fs::write(&outside, "x").unwrap();
+    let event = next_event(&events, &[]);
+    assert_eq!((event.kind.as_str(), &event.path), ("add", &outside));
+  }
+
+  #[test]
+  fn dry_run_records_decisions_instead_of_delivering() {
+    let (fs_watcher, events, _) = test_watcher(r#"{"dry_run": true, "ignore": ["**/*.log"]}"#);
+    let ignored = PathBuf::from("/watched/debug.log");
+    let allowed = PathBuf::from("/watched/main.rs");
+    for path in [&ignored, &allowed] {
+      inject(
+        &fs_watcher,
+        Ok(Event::new(EventKind::Create(CreateKind::File)).add_path(path.clone())),
+      );
+    }
+    assert!(events_within(&events, Duration::from_millis(200)).is_empty());
+
+    let dispatcher = fs_watcher.dispatcher.upgrade().unwrap();
+    let records = std::mem::take(&mut *dispatcher.debug_log.as_ref().unwrap().lock().unwrap());
+    let decisions: Vec<(&Path, bool, Option<&str>)> = records
+      .iter()
+      .map(|record| (record.event.path.as_path(), record.delivered, record.reason))
+      .collect();
+    assert_eq!(
+      decisions,
+      [
+        (ignored.as_path(), false, Some("ignored")),
+        (allowed.as_path(), true, None),
+      ]
+    );
+    assert!(records
+      .iter()
+      .all(|record| record.event.detail.as_deref() == Some("Create(File)")));
+  }
+
+  #[test]
+  fn exceeding_max_watches_fails_with_friendly_error() {
+    let dir = TempDir::new();
+    for name in ["a", "b", "c"] {
+      fs::create_dir(dir.path().join(name)).unwrap();
+    }
+    let (mut fs_watcher, events, _) = test_watcher(r#"{"recursive": true, "max_watches": 3}"#);
+
+    let error = fs_watcher.add_path(dir.path(), None).unwrap_err();
+    assert!(
+      error.reason.contains("exceed the maxWatches limit of 3"),
+      "{}",
+      error.reason
+    );
+    assert!(error.reason.contains("fs.inotify.max_user_watches"));
+    assert!(fs_watcher.state().watched_paths().is_empty());
+
+    // Narrower watch within the limit is still allowed
+    fs_watcher
+      .add_path(dir.path(), Some(RecursiveMode::NonRecursive))
+      .unwrap();
+    let path = dir.path().join("top.txt");
+    fs::write(&path, "x").unwrap();
+    let event = next_event(&events, &[]);
+    assert_eq!((event.kind.as_str(), &event.path), ("add", &path));
+  }
+
+  #[test]
+  fn os_watch_limit_error_is_explained() {
+    let path = PathBuf::from("/watched/deep");
+    for error in [
+      notify::Error::io(io::Error::from_raw_os_error(libc::ENOSPC)),
+      notify::Error::new(notify::ErrorKind::MaxFilesWatch),
+    ] {
+      let error = watch_error(error.add_path(path.clone()));
+      assert!(
+        error
+          .reason
+          .starts_with("Reached the OS limit of file watches while watching /watched/deep."),
+        "{}",
+        error.reason
+      );
+      assert!(error.reason.contains("fs.inotify.max_user_watches"));
+    }
+
+    let error = watch_error(notify::Error::generic("failed"));
+    assert_eq!(error.reason, "failed");
+  }
+
+  #[test]
+  fn relative_path_is_delivered_absolute_with_absolute_paths() {
+    let dir = TempDir::new();
+    // Relative path of the directory from the working directory, which is shared by the
+    // tests and thus not changed
+    let cwd = std::env::current_dir().unwrap();
+    let relative: PathBuf = cwd
+      .components()
+      .skip(1)
+      .map(|_| Path::new(".."))
+      .chain(dir.path().strip_prefix("/"))
+      .collect();
+    assert!(relative.is_relative());
+    let (mut fs_watcher, events, _) = test_watcher(r#"{"absolute_paths": true}"#);
+    fs_watcher.add_path(&relative, None).unwrap();
+
+    fs::write(dir.path().join("a.txt"), "x").unwrap();
+    let event = next_event(&events, &[]);
+    assert_eq!(
+      (event.kind.as_str(), &event.path),
+      ("add", &cwd.join(&relative).join("a.txt"))
+    );
+  }
+
+  #[test]
+  fn replaced_filters_apply_to_later_events() {
+    let dir = TempDir::new();
+    let (mut fs_watcher, events, _) = test_watcher("");
+    fs_watcher.add_path(dir.path(), None).unwrap();
+
+    let path = dir.path().join("before.log");
+    fs::write(&path, "x").unwrap();
+    let event = next_event(&events, &[]);
+    assert_eq!((event.kind.as_str(), &event.path), ("add", &path));
+    thread::sleep(Duration::from_millis(100));
+    events.try_iter().for_each(drop);
+
+    let options: FilterOptions = parse_options(r#"{"ignore": ["**/*.log"]}"#).unwrap();
+    *fs_watcher.patterns.write().unwrap() = PatternFilter::new(&options).unwrap();
+    fs::write(dir.path().join("after.log"), "x").unwrap();
+    let path = dir.path().join("after.txt");
+    fs::write(&path, "x").unwrap();
+    let delivered = events_within(&events, Duration::from_millis(300));
+    assert!(!delivered.is_empty());
+    assert!(delivered.iter().all(|event| event.path == path));
+  }
 }